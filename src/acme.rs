@@ -0,0 +1,320 @@
+use crate::dns_client::{self, RecordType};
+use crate::{dns_record, propagation};
+use config::Config;
+use log::{info, warn};
+use reqwest::Client;
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+
+pub const ACME_CHALLENGE_LABEL: &str = "_acme-challenge";
+
+const DEFAULT_PROPAGATION_TIMEOUT_IN_SECONDS: u64 = 300;
+const PROPAGATION_INTERVAL: Duration = Duration::from_secs(5);
+const NAMESERVERS_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const USAGE: &str = "Usage: infomaniak-dyndns-wildcard acme <present|cleanup> [<domain> <value>]";
+
+/// Returns the fully qualified name of the challenge record for `domain`.
+///
+/// Accepts a domain (`home.example.com`), a wildcard (`*.home.example.com`) or
+/// an already prefixed name as given by lego (`_acme-challenge.home.example.com.`).
+pub fn challenge_fqdn(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let domain = domain.strip_prefix("*.").unwrap_or(&domain);
+    if domain.starts_with(&format!("{}.", ACME_CHALLENGE_LABEL)) {
+        domain.to_string()
+    } else {
+        format!("{}.{}", ACME_CHALLENGE_LABEL, domain)
+    }
+}
+
+/// Returns `fqdn` relative to `zone`, as expected by the Infomaniak API.
+pub fn relative_record_name(fqdn: &str, zone: &str) -> Result<String, Box<dyn Error>> {
+    let zone = zone.trim_end_matches('.').to_lowercase();
+    match fqdn
+        .strip_suffix(zone.as_str())
+        .and_then(|prefix| prefix.strip_suffix('.'))
+    {
+        Some(relative_name) if !relative_name.is_empty() => Ok(relative_name.to_string()),
+        _ => Err(format!("{} is not part of the DNS zone {}", fqdn, zone).into()),
+    }
+}
+
+/// Creates the TXT record holding the challenge `value` for `domain`.
+pub async fn present(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    dns_zone_id: &str,
+    domain: &str,
+    value: &str,
+) -> Result<(), Box<dyn Error>> {
+    let record_name = relative_record_name(&challenge_fqdn(domain), dns_zone_id)?;
+    let record = dns_record::update_dns_record(
+        client,
+        infomaniak_zones_api_url,
+        value,
+        None,
+        dns_zone_id,
+        &record_name,
+        "TXT",
+    )
+    .await?;
+    info!("Challenge record created: {:?}", record);
+    Ok(())
+}
+
+/// Deletes the TXT records holding the challenge `value` for `domain`.
+pub async fn cleanup(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    dns_zone_id: &str,
+    domain: &str,
+    value: &str,
+) -> Result<(), Box<dyn Error>> {
+    let record_name = relative_record_name(&challenge_fqdn(domain), dns_zone_id)?;
+    let records =
+        dns_record::get_dns_records(client, infomaniak_zones_api_url, dns_zone_id, &["TXT"])
+            .await?;
+
+    let mut found = false;
+    for record in records
+        .iter()
+        .filter(|record| record.source == record_name && record.target.trim_matches('"') == value)
+    {
+        dns_record::delete_dns_record(
+            client,
+            infomaniak_zones_api_url,
+            dns_zone_id,
+            &record.id.to_string(),
+        )
+        .await?;
+        info!("Challenge record deleted: {:?}", record);
+        found = true;
+    }
+    if !found {
+        warn!("No challenge record found for {} to clean up", record_name);
+    }
+    Ok(())
+}
+
+/// Reads the domain and the challenge value from the arguments, or from the
+/// environment variables set by certbot when used as a manual hook.
+fn challenge_from_args(args: &[String]) -> Result<(String, String), Box<dyn Error>> {
+    match args {
+        [domain, value] => Ok((domain.clone(), value.clone())),
+        [] => match (env::var("CERTBOT_DOMAIN"), env::var("CERTBOT_VALIDATION")) {
+            (Ok(domain), Ok(value)) => Ok((domain, value)),
+            _ => Err(format!(
+                "CERTBOT_DOMAIN and CERTBOT_VALIDATION must be set when no domain is given\n{}",
+                USAGE
+            )
+            .into()),
+        },
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Runs the `acme` subcommand.
+pub async fn run(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    config: &Config,
+    dns_zone_id: &str,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let (action, args) = args.split_first().ok_or(USAGE)?;
+    let (domain, value) = challenge_from_args(args)?;
+
+    match action.as_str() {
+        "present" => {
+            present(
+                client,
+                infomaniak_zones_api_url,
+                dns_zone_id,
+                &domain,
+                &value,
+            )
+            .await?;
+
+            let resolver =
+                dns_client::resolver_or_system(config.get_string("dns_resolver").ok().as_deref())?;
+            let timeout = Duration::from_secs(
+                config
+                    .get::<u64>("acme_propagation_timeout_in_seconds")
+                    .unwrap_or(DEFAULT_PROPAGATION_TIMEOUT_IN_SECONDS),
+            );
+            let nameservers =
+                dns_client::lookup_nameservers(resolver, dns_zone_id, NAMESERVERS_LOOKUP_TIMEOUT)
+                    .await?;
+            // Other challenges of the same name may be pending along with it
+            let elapsed = propagation::wait_for_values(
+                &nameservers,
+                &challenge_fqdn(&domain),
+                RecordType::Txt,
+                std::slice::from_ref(&value),
+                &[],
+                timeout,
+                PROPAGATION_INTERVAL,
+            )
+            .await?;
+            info!(
+                "Challenge record served by all nameservers after {:?}",
+                elapsed
+            );
+            Ok(())
+        }
+        "cleanup" => {
+            cleanup(
+                client,
+                infomaniak_zones_api_url,
+                dns_zone_id,
+                &domain,
+                &value,
+            )
+            .await
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+    use serde_json::json;
+
+    #[test]
+    fn test_challenge_fqdn() {
+        assert_eq!(
+            challenge_fqdn("home.example.com"),
+            "_acme-challenge.home.example.com"
+        );
+        assert_eq!(
+            challenge_fqdn("*.home.example.com"),
+            "_acme-challenge.home.example.com"
+        );
+        assert_eq!(
+            challenge_fqdn("_acme-challenge.Home.Example.com."),
+            "_acme-challenge.home.example.com"
+        );
+    }
+
+    #[test]
+    fn test_relative_record_name() {
+        assert_eq!(
+            relative_record_name("_acme-challenge.home.example.com", "example.com").unwrap(),
+            "_acme-challenge.home"
+        );
+        assert_eq!(
+            relative_record_name("_acme-challenge.example.com", "example.com.").unwrap(),
+            "_acme-challenge"
+        );
+        assert!(relative_record_name("_acme-challenge.example.org", "example.com").is_err());
+        assert!(relative_record_name("_acme-challenge.notexample.com", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_challenge_from_args() {
+        let args = vec!["home.example.com".to_string(), "token".to_string()];
+        assert_eq!(
+            challenge_from_args(&args).unwrap(),
+            ("home.example.com".to_string(), "token".to_string())
+        );
+        assert!(challenge_from_args(&args[..1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_present() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/example.com/records")
+            .match_body(mockito::Matcher::PartialJson(json!({
+                "source": "_acme-challenge.home",
+                "target": "token",
+                "type": "TXT"
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": {
+                        "id": 125,
+                        "source": "_acme-challenge.home",
+                        "target": "token",
+                        "ttl": 300,
+                        "type": "TXT",
+                        "updated_at": 1234567890
+                    }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = Client::new();
+        let result = present(
+            &client,
+            &server.url(),
+            "example.com",
+            "*.home.example.com",
+            "token",
+        )
+        .await;
+
+        mock.assert_async().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_deletes_matching_record_only() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock("GET", "/example.com/records?filter[types][]=TXT")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        {
+                            "id": 1,
+                            "source": "_acme-challenge.home",
+                            "target": "\"token\"",
+                            "ttl": 300,
+                            "type": "TXT",
+                            "updated_at": 1234567890
+                        },
+                        {
+                            "id": 2,
+                            "source": "_acme-challenge.home",
+                            "target": "other-token",
+                            "ttl": 300,
+                            "type": "TXT",
+                            "updated_at": 1234567890
+                        }
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let delete_mock = server
+            .mock("DELETE", "/example.com/records/1")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let client = Client::new();
+        let result = cleanup(
+            &client,
+            &server.url(),
+            "example.com",
+            "home.example.com",
+            "token",
+        )
+        .await;
+
+        list_mock.assert_async().await;
+        delete_mock.assert_async().await;
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// IP address family of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(address: &IpAddr) -> AddressFamily {
+        match address {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+
+    /// Type of the DNS records holding addresses of this family.
+    pub fn record_type(self) -> &'static str {
+        match self {
+            AddressFamily::Ipv4 => "A",
+            AddressFamily::Ipv6 => "AAAA",
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::Ipv4 => write!(f, "IPv4"),
+            AddressFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Public addresses to publish, for each family.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublicIps {
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+impl PublicIps {
+    pub fn new(ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> PublicIps {
+        PublicIps {
+            ipv4: ipv4.into_iter().collect(),
+            ipv6: ipv6.into_iter().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Returns the addresses of `family`, formatted like record targets.
+    pub fn targets(&self, family: AddressFamily) -> Vec<String> {
+        match family {
+            AddressFamily::Ipv4 => self.ipv4.iter().map(|ip| ip.to_string()).collect(),
+            AddressFamily::Ipv6 => self.ipv6.iter().map(|ip| ip.to_string()).collect(),
+        }
+    }
+
+    /// Adds the addresses of `other` that are not already in the set.
+    pub fn extend(&mut self, other: &PublicIps) {
+        for ip in &other.ipv4 {
+            if !self.ipv4.contains(ip) {
+                self.ipv4.push(*ip);
+            }
+        }
+        for ip in &other.ipv6 {
+            if !self.ipv6.contains(ip) {
+                self.ipv6.push(*ip);
+            }
+        }
+    }
+}
+
+/// Error state of an address family, used to back off after failures
+/// without delaying the other family.
+#[derive(Debug)]
+pub struct FamilyState {
+    pub family: AddressFamily,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl FamilyState {
+    pub fn new(family: AddressFamily) -> FamilyState {
+        FamilyState {
+            family,
+            consecutive_failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Tells whether the family should be reconciled at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Records a failure and returns how long to wait before the next attempt.
+    ///
+    /// The first failure is retried at the next cycle, then the delay doubles
+    /// from `interval` up to `max_backoff`.
+    pub fn record_failure(
+        &mut self,
+        now: Instant,
+        interval: Duration,
+        max_backoff: Duration,
+    ) -> Duration {
+        self.consecutive_failures += 1;
+        let exponent = (self.consecutive_failures - 1).min(16);
+        let backoff = interval
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(max_backoff.max(interval));
+        self.retry_at = Some(now + backoff);
+        backoff
+    }
+
+    /// Postpones the next attempt by `retry_in` without counting a failure,
+    /// for updates held back by the rate limit.
+    pub fn defer(&mut self, now: Instant, retry_in: Duration) {
+        self.retry_at = Some(now + retry_in);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_family_state_backoff() {
+        let interval = Duration::from_secs(60);
+        let max_backoff = Duration::from_secs(300);
+        let now = Instant::now();
+        let mut state = FamilyState::new(AddressFamily::Ipv6);
+        assert!(state.is_due(now));
+
+        assert_eq!(
+            state.record_failure(now, interval, max_backoff),
+            Duration::from_secs(60)
+        );
+        assert!(!state.is_due(now));
+        assert!(state.is_due(now + Duration::from_secs(60)));
+        assert_eq!(
+            state.record_failure(now, interval, max_backoff),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            state.record_failure(now, interval, max_backoff),
+            Duration::from_secs(240)
+        );
+        assert_eq!(
+            state.record_failure(now, interval, max_backoff),
+            Duration::from_secs(300)
+        );
+
+        state.record_success();
+        assert!(state.is_due(now));
+        assert_eq!(
+            state.record_failure(now, interval, max_backoff),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn test_family_state_defer() {
+        let interval = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut state = FamilyState::new(AddressFamily::Ipv4);
+
+        state.defer(now, Duration::from_secs(600));
+        assert!(!state.is_due(now + interval));
+        assert!(state.is_due(now + Duration::from_secs(600)));
+        // Deferrals don't grow the backoff
+        assert_eq!(
+            state.record_failure(now, interval, Duration::from_secs(300)),
+            interval
+        );
+    }
+
+    #[test]
+    fn test_address_family_record_type() {
+        assert_eq!(AddressFamily::Ipv4.record_type(), "A");
+        assert_eq!(AddressFamily::Ipv6.record_type(), "AAAA");
+        assert_eq!(AddressFamily::Ipv6.to_string(), "IPv6");
+    }
+}
@@ -0,0 +1,306 @@
+use config::Config;
+use log::{debug, warn};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+
+/// Block of addresses, like `100.64.0.0/10`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    const fn v4(a: u8, b: u8, c: u8, d: u8, prefix_len: u8) -> Network {
+        Network {
+            address: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+            prefix_len,
+        }
+    }
+
+    const fn v6(segments: [u16; 8], prefix_len: u8) -> Network {
+        let [a, b, c, d, e, f, g, h] = segments;
+        Network {
+            address: IpAddr::V6(Ipv6Addr::new(a, b, c, d, e, f, g, h)),
+            prefix_len,
+        }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid network {:?}: {}", s, e))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length in network {:?}", s))?,
+            None => max_prefix_len,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+const CGNAT: Network = Network::v4(100, 64, 0, 0, 10);
+
+/// Ranges that can't be reached from the Internet, with their description.
+const BOGONS: &[(Network, &str)] = &[
+    (Network::v4(0, 0, 0, 0, 8), "a \"this network\" address"),
+    (Network::v4(10, 0, 0, 0, 8), "a private address"),
+    (CGNAT, "a carrier-grade NAT address"),
+    (Network::v4(127, 0, 0, 0, 8), "a loopback address"),
+    (Network::v4(169, 254, 0, 0, 16), "a link-local address"),
+    (Network::v4(172, 16, 0, 0, 12), "a private address"),
+    (Network::v4(192, 0, 0, 0, 24), "an IETF protocol address"),
+    (Network::v4(192, 0, 2, 0, 24), "a documentation address"),
+    (Network::v4(192, 168, 0, 0, 16), "a private address"),
+    (Network::v4(198, 18, 0, 0, 15), "a benchmarking address"),
+    (Network::v4(198, 51, 100, 0, 24), "a documentation address"),
+    (Network::v4(203, 0, 113, 0, 24), "a documentation address"),
+    (Network::v4(224, 0, 0, 0, 4), "a multicast address"),
+    (Network::v4(240, 0, 0, 0, 4), "a reserved address"),
+    (
+        Network::v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+        "the unspecified address",
+    ),
+    (
+        Network::v6([0, 0, 0, 0, 0, 0, 0, 1], 128),
+        "a loopback address",
+    ),
+    (
+        Network::v6([0, 0, 0, 0, 0, 0xffff, 0, 0], 96),
+        "an IPv4-mapped address",
+    ),
+    (
+        Network::v6([0x64, 0xff9b, 0, 0, 0, 0, 0, 0], 96),
+        "a NAT64 address",
+    ),
+    (
+        Network::v6([0x100, 0, 0, 0, 0, 0, 0, 0], 64),
+        "a discard address",
+    ),
+    (
+        Network::v6([0x2001, 0x2, 0, 0, 0, 0, 0, 0], 48),
+        "a benchmarking address",
+    ),
+    (
+        Network::v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32),
+        "a documentation address",
+    ),
+    (
+        Network::v6([0x3fff, 0, 0, 0, 0, 0, 0, 0], 20),
+        "a documentation address",
+    ),
+    (
+        Network::v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+        "a unique local address",
+    ),
+    (
+        Network::v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+        "a link-local address",
+    ),
+    (
+        Network::v6([0xfec0, 0, 0, 0, 0, 0, 0, 0], 10),
+        "a site-local address",
+    ),
+    (
+        Network::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+        "a multicast address",
+    ),
+];
+
+/// Returns the description of the reserved range `address` belongs to.
+pub fn bogon_range(address: IpAddr) -> Option<&'static str> {
+    if let IpAddr::V6(address) = address {
+        // Only 2000::/3 is allocated for global unicast
+        if address.segments()[0] & 0xe000 != 0x2000
+            && !BOGONS
+                .iter()
+                .any(|(network, _)| network.contains(IpAddr::V6(address)))
+        {
+            return Some("an unallocated address");
+        }
+    }
+    BOGONS
+        .iter()
+        .find(|(network, _)| network.contains(address))
+        .map(|(_, description)| *description)
+}
+
+/// Refuses to publish addresses that can't be reached from the Internet,
+/// except the ones of the allow-list.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddressFilter {
+    allowed: Vec<Network>,
+}
+
+impl AddressFilter {
+    pub fn new(allowed: Vec<Network>) -> AddressFilter {
+        AddressFilter { allowed }
+    }
+
+    /// Reads the allow-list from `allowed_addresses`, either a list or a
+    /// string of networks separated by `,`.
+    pub fn from_config(config: &Config) -> Result<AddressFilter, Box<dyn Error>> {
+        let allowed = match config.get::<Vec<String>>("allowed_addresses") {
+            Ok(allowed) => allowed,
+            Err(_) => match config.get_string("allowed_addresses") {
+                Ok(allowed) => allowed.split(',').map(String::from).collect(),
+                Err(_) => Vec::new(),
+            },
+        };
+        Ok(AddressFilter::new(
+            allowed
+                .iter()
+                .map(|network| network.parse::<Network>())
+                .collect::<Result<Vec<Network>, String>>()?,
+        ))
+    }
+
+    /// Checks that `address` can be published.
+    pub fn check(&self, address: IpAddr) -> Result<(), Box<dyn Error>> {
+        let Some(range) = bogon_range(address) else {
+            return Ok(());
+        };
+        if self.allowed.iter().any(|network| network.contains(address)) {
+            debug!("Publishing {}, {} from the allow-list", address, range);
+            return Ok(());
+        }
+        let mut message = format!("Refusing to publish {}, it is {}", address, range);
+        if CGNAT.contains(address) {
+            message.push_str(
+                ", the host seems to be behind a carrier-grade NAT and can't be reached from the Internet",
+            );
+        }
+        Err(message.into())
+    }
+}
+
+/// Warns when the address used to reach the Internet over IPv4 belongs to
+/// the carrier-grade NAT range, in which case the public IPv4 is shared with
+/// other customers of the ISP.
+pub fn warn_if_behind_cgnat() {
+    // Connecting a UDP socket doesn't send anything, it only picks the route.
+    let local_address = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(1, 1, 1, 1), 53))?;
+            socket.local_addr()
+        })
+        .map(|address| address.ip());
+    match local_address {
+        Ok(address) if CGNAT.contains(address) => warn!(
+            "The local address {} is a carrier-grade NAT address, the public IPv4 is shared \
+             and records pointing to it likely can't be reached from the Internet",
+            address
+        ),
+        Ok(address) => debug!("Local IPv4 address: {}", address),
+        Err(e) => debug!("Can't find the local IPv4 address: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(filter: &AddressFilter, address: &str) -> Result<(), Box<dyn Error>> {
+        filter.check(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_bogons_are_refused() {
+        let filter = AddressFilter::default();
+
+        for address in [
+            "10.0.0.1",
+            "100.64.12.34",
+            "127.0.0.1",
+            "169.254.1.1",
+            "172.31.255.255",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.51.100.1",
+            "203.0.113.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::ffff:1.1.1.1",
+            "2001:db8::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "4000::1",
+        ] {
+            assert!(check(&filter, address).is_err(), "{} was accepted", address);
+        }
+        for address in ["1.1.1.1", "100.128.0.1", "172.32.0.1", "2a00:1450::1"] {
+            assert!(check(&filter, address).is_ok(), "{} was refused", address);
+        }
+    }
+
+    #[test]
+    fn test_cgnat_error_is_explicit() {
+        let error = check(&AddressFilter::default(), "100.64.0.1")
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("carrier-grade NAT"));
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let config = Config::builder()
+            .set_override("allowed_addresses", "10.0.0.0/8, fd00::1")
+            .unwrap()
+            .build()
+            .unwrap();
+        let filter = AddressFilter::from_config(&config).unwrap();
+
+        assert!(check(&filter, "10.1.2.3").is_ok());
+        assert!(check(&filter, "fd00::1").is_ok());
+        assert!(check(&filter, "fd00::2").is_err());
+        assert!(check(&filter, "192.168.1.1").is_err());
+    }
+
+    #[test]
+    fn test_network_from_str() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("eth0/24".parse::<Network>().is_err());
+        assert!(
+            "0.0.0.0/0"
+                .parse::<Network>()
+                .unwrap()
+                .contains("8.8.8.8".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,791 @@
+use crate::address_family::AddressFamily;
+use crate::public_ip::DetectionBinding;
+use crate::random::random_u64;
+use log::debug;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net;
+use tokio::time;
+
+pub const DNS_PORT: u16 = 53;
+
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+const MAX_UDP_PAYLOAD_SIZE: usize = 4096;
+const MAX_COMPRESSION_POINTERS: usize = 64;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const CLASS_IN: u16 = 1;
+
+/// Response code of a successful DNS query.
+pub const RCODE_NO_ERROR: u8 = 0;
+
+/// DNS record types supported by the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordType {
+    A,
+    Ns,
+    Cname,
+    Txt,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+impl std::str::FromStr for RecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "A" => Ok(RecordType::A),
+            "NS" => Ok(RecordType::Ns),
+            "CNAME" => Ok(RecordType::Cname),
+            "TXT" => Ok(RecordType::Txt),
+            "AAAA" => Ok(RecordType::Aaaa),
+            _ => Err(format!("Unsupported DNS record type {:?}", s)),
+        }
+    }
+}
+
+/// Data of a record found in the answer section of a response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(String),
+    Cname(String),
+    Txt(String),
+}
+
+impl RecordData {
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            RecordData::A(_) => RecordType::A,
+            RecordData::Aaaa(_) => RecordType::Aaaa,
+            RecordData::Ns(_) => RecordType::Ns,
+            RecordData::Cname(_) => RecordType::Cname,
+            RecordData::Txt(_) => RecordType::Txt,
+        }
+    }
+}
+
+impl fmt::Display for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordData::A(ip) => write!(f, "{}", ip),
+            RecordData::Aaaa(ip) => write!(f, "{}", ip),
+            RecordData::Ns(name) | RecordData::Cname(name) | RecordData::Txt(name) => {
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsAnswer {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug)]
+pub struct DnsResponse {
+    pub response_code: u8,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub answers: Vec<DnsAnswer>,
+}
+
+impl DnsResponse {
+    /// Returns the answers of the given type, ignoring CNAMEs and other records.
+    pub fn values(&self, record_type: RecordType) -> Vec<&RecordData> {
+        self.answers
+            .iter()
+            .map(|answer| &answer.data)
+            .filter(|data| data.record_type() == record_type)
+            .collect()
+    }
+}
+
+/// A nameserver and the addresses it can be reached on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nameserver {
+    pub name: String,
+    pub addresses: Vec<SocketAddr>,
+}
+
+fn encode_name(packet: &mut Vec<u8>, name: &str) -> Result<(), Box<dyn Error>> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() {
+            if name.trim_end_matches('.').is_empty() {
+                break;
+            }
+            return Err(format!("Invalid DNS name {:?}: empty label", name).into());
+        }
+        if label.len() > 63 {
+            return Err(format!("Invalid DNS name {:?}: label too long", name).into());
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    Ok(())
+}
+
+/// Encodes a query for `name` and `record_type` with the given id.
+pub fn encode_query(
+    id: u16,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question, no answer, authority or additional records
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut packet, name)?;
+    packet.extend_from_slice(&record_type.code().to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
+    match packet.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err("Truncated DNS packet".into()),
+    }
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    match packet.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => Err("Truncated DNS packet".into()),
+    }
+}
+
+/// Reads a possibly compressed name, returns it with the offset following it.
+fn read_name(packet: &[u8], offset: usize) -> Result<(String, usize), Box<dyn Error>> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = offset;
+    let mut end_offset = None;
+    let mut pointers_followed = 0;
+
+    loop {
+        let length = *packet.get(position).ok_or("Truncated DNS packet")? as usize;
+        if length & 0xC0 == 0xC0 {
+            pointers_followed += 1;
+            if pointers_followed > MAX_COMPRESSION_POINTERS {
+                return Err("Too many compression pointers in DNS packet".into());
+            }
+            let pointer = (read_u16(packet, position)? & 0x3FFF) as usize;
+            end_offset.get_or_insert(position + 2);
+            position = pointer;
+        } else if length == 0 {
+            end_offset.get_or_insert(position + 1);
+            break;
+        } else {
+            let label = packet
+                .get(position + 1..position + 1 + length)
+                .ok_or("Truncated DNS packet")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            position += 1 + length;
+        }
+    }
+
+    Ok((labels.join("."), end_offset.unwrap_or(position)))
+}
+
+fn decode_record_data(
+    packet: &[u8],
+    record_type: u16,
+    offset: usize,
+    length: usize,
+) -> Result<Option<RecordData>, Box<dyn Error>> {
+    let data = packet
+        .get(offset..offset + length)
+        .ok_or("Truncated DNS packet")?;
+    let record_data = match record_type {
+        1 => {
+            let octets: [u8; 4] = data.try_into().map_err(|_| "Invalid A record length")?;
+            Some(RecordData::A(Ipv4Addr::from(octets)))
+        }
+        28 => {
+            let octets: [u8; 16] = data.try_into().map_err(|_| "Invalid AAAA record length")?;
+            Some(RecordData::Aaaa(Ipv6Addr::from(octets)))
+        }
+        2 => Some(RecordData::Ns(read_name(packet, offset)?.0)),
+        5 => Some(RecordData::Cname(read_name(packet, offset)?.0)),
+        16 => {
+            let mut text = String::new();
+            let mut position = 0;
+            while position < data.len() {
+                let string_length = data[position] as usize;
+                let string = data
+                    .get(position + 1..position + 1 + string_length)
+                    .ok_or("Truncated TXT record")?;
+                text.push_str(&String::from_utf8_lossy(string));
+                position += 1 + string_length;
+            }
+            Some(RecordData::Txt(text))
+        }
+        _ => None,
+    };
+    Ok(record_data)
+}
+
+/// Decodes a response to the query with the given id.
+pub fn decode_response(id: u16, packet: &[u8]) -> Result<DnsResponse, Box<dyn Error>> {
+    if read_u16(packet, 0)? != id {
+        return Err("DNS response id does not match the query".into());
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err("DNS packet is not a response".into());
+    }
+    let question_count = read_u16(packet, 4)?;
+    let answer_count = read_u16(packet, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..question_count {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+        let (name, name_end) = read_name(packet, offset)?;
+        let record_type = read_u16(packet, name_end)?;
+        let ttl = read_u32(packet, name_end + 4)?;
+        let data_length = read_u16(packet, name_end + 8)? as usize;
+        let data_offset = name_end + 10;
+        if let Some(data) = decode_record_data(packet, record_type, data_offset, data_length)? {
+            answers.push(DnsAnswer { name, ttl, data });
+        }
+        offset = data_offset + data_length;
+    }
+
+    Ok(DnsResponse {
+        response_code: (flags & 0x000F) as u8,
+        authoritative: flags & FLAG_AUTHORITATIVE != 0,
+        truncated: flags & FLAG_TRUNCATED != 0,
+        answers,
+    })
+}
+
+/// Sends `query` to `server` over UDP and returns the first response to it.
+///
+/// Datagrams from another source, with another id, or which aren't
+/// responses, like late answers to previous queries, are dropped until the
+/// timeout.
+async fn query_udp(
+    server: SocketAddr,
+    query: &[u8],
+    timeout: Duration,
+    binding: &DetectionBinding,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let deadline = time::Instant::now() + timeout;
+    let id = read_u16(query, 0)?;
+    let socket = binding.bind_udp(AddressFamily::of(&server.ip())).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    loop {
+        let (length, source) = time::timeout_at(deadline, socket.recv_from(&mut buffer)).await??;
+        let packet = &buffer[..length];
+        let is_response = read_u16(packet, 2).is_ok_and(|flags| flags & FLAG_RESPONSE != 0);
+        if source == server
+            && read_u16(packet, 0).is_ok_and(|packet_id| packet_id == id)
+            && is_response
+        {
+            buffer.truncate(length);
+            return Ok(buffer);
+        }
+        debug!(
+            "Dropping a DNS datagram from {} not answering the query",
+            source
+        );
+    }
+}
+
+async fn query_tcp(
+    server: SocketAddr,
+    query: &[u8],
+    timeout: Duration,
+    binding: &DetectionBinding,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let deadline = time::Instant::now() + timeout;
+    let mut stream = time::timeout_at(deadline, binding.connect_tcp(server)).await??;
+    let exchange = async {
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message).await?;
+
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).await?;
+        let mut buffer = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer).await?;
+        Ok::<Vec<u8>, std::io::Error>(buffer)
+    };
+    Ok(time::timeout_at(deadline, exchange).await??)
+}
+
+/// Sends a query to `server` over UDP, retrying over TCP if the answer is truncated.
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+    timeout: Duration,
+) -> Result<DnsResponse, Box<dyn Error>> {
+    query_from(
+        server,
+        name,
+        record_type,
+        timeout,
+        &DetectionBinding::default(),
+    )
+    .await
+}
+
+/// Sends a query to `server` like [`query`], from sockets bound with `binding`.
+pub async fn query_from(
+    server: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+    timeout: Duration,
+    binding: &DetectionBinding,
+) -> Result<DnsResponse, Box<dyn Error>> {
+    let id = random_u64() as u16;
+    let query = encode_query(id, name, record_type)?;
+
+    let response = decode_response(id, &query_udp(server, &query, timeout, binding).await?)?;
+    if !response.truncated {
+        return Ok(response);
+    }
+    decode_response(id, &query_tcp(server, &query, timeout, binding).await?)
+}
+
+/// Sends a query to a nameserver, trying each of its addresses until one answers.
+pub async fn query_nameserver(
+    nameserver: &Nameserver,
+    name: &str,
+    record_type: RecordType,
+    timeout: Duration,
+) -> Result<DnsResponse, Box<dyn Error>> {
+    let mut last_error = format!("Nameserver {} has no address", nameserver.name);
+    for address in &nameserver.addresses {
+        match query(*address, name, record_type, timeout).await {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                last_error = format!(
+                    "Error querying nameserver {} ({}): {}",
+                    nameserver.name, address, e
+                )
+            }
+        }
+    }
+    Err(last_error.into())
+}
+
+/// Parses a resolver address, with or without port.
+pub fn parse_resolver(resolver: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    if let Ok(address) = resolver.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    match resolver.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, DNS_PORT)),
+        Err(_) => Err(format!("Invalid DNS resolver address {:?}", resolver).into()),
+    }
+}
+
+/// Returns the first nameserver configured in `/etc/resolv.conf`.
+pub fn system_resolver() -> Result<SocketAddr, Box<dyn Error>> {
+    let resolv_conf = fs::read_to_string(RESOLV_CONF_PATH)?;
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|address| parse_resolver(address.trim()).ok())
+        .ok_or_else(|| format!("No nameserver found in {}", RESOLV_CONF_PATH).into())
+}
+
+/// Parses `resolver` if given, or returns the system resolver.
+pub fn resolver_or_system(resolver: Option<&str>) -> Result<SocketAddr, Box<dyn Error>> {
+    match resolver {
+        Some(resolver) => parse_resolver(resolver),
+        None => system_resolver(),
+    }
+}
+
+/// Looks up the authoritative nameservers of `zone` and their addresses using `resolver`.
+pub async fn lookup_nameservers(
+    resolver: SocketAddr,
+    zone: &str,
+    timeout: Duration,
+) -> Result<Vec<Nameserver>, Box<dyn Error>> {
+    let response = query(resolver, zone, RecordType::Ns, timeout).await?;
+    if response.response_code != RCODE_NO_ERROR {
+        return Err(format!(
+            "Error looking up nameservers of {}: response code {}",
+            zone, response.response_code
+        )
+        .into());
+    }
+
+    let mut nameservers = Vec::new();
+    for data in response.values(RecordType::Ns) {
+        let name = data.to_string();
+        let mut addresses = Vec::new();
+        for record_type in [RecordType::A, RecordType::Aaaa] {
+            let error = match query(resolver, &name, record_type, timeout).await {
+                Ok(response) => {
+                    addresses.extend(response.values(record_type).iter().filter_map(|data| {
+                        match data {
+                            RecordData::A(ip) => Some(SocketAddr::new(IpAddr::V4(*ip), DNS_PORT)),
+                            RecordData::Aaaa(ip) => {
+                                Some(SocketAddr::new(IpAddr::V6(*ip), DNS_PORT))
+                            }
+                            _ => None,
+                        }
+                    }));
+                    continue;
+                }
+                Err(e) => e.to_string(),
+            };
+            log::warn!("Error resolving nameserver {}: {}", name, error);
+        }
+        if addresses.is_empty() {
+            // Fall back on the system resolver
+            addresses.extend(net::lookup_host((name.as_str(), DNS_PORT)).await?);
+        }
+        nameservers.push(Nameserver { name, addresses });
+    }
+
+    if nameservers.is_empty() {
+        return Err(format!("No nameserver found for {}", zone).into());
+    }
+    Ok(nameservers)
+}
+
+#[cfg(test)]
+pub mod stub {
+    //! Minimal DNS server answering queries from a fixed list of records.
+
+    use super::*;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    /// Builds a response to `query` containing the given answers.
+    pub fn build_response(
+        query: &[u8],
+        answers: &[(&str, RecordData)],
+        truncated: bool,
+    ) -> Vec<u8> {
+        let mut packet = query[0..2].to_vec();
+        let mut flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE;
+        if truncated {
+            flags |= FLAG_TRUNCATED;
+        }
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&query[12..]);
+        for (name, data) in answers {
+            encode_name(&mut packet, name).unwrap();
+            packet.extend_from_slice(&data.record_type().code().to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+            packet.extend_from_slice(&60u32.to_be_bytes());
+            let mut rdata = Vec::new();
+            match data {
+                RecordData::A(ip) => rdata.extend_from_slice(&ip.octets()),
+                RecordData::Aaaa(ip) => rdata.extend_from_slice(&ip.octets()),
+                RecordData::Ns(target) | RecordData::Cname(target) => {
+                    encode_name(&mut rdata, target).unwrap()
+                }
+                RecordData::Txt(text) => {
+                    rdata.push(text.len() as u8);
+                    rdata.extend_from_slice(text.as_bytes());
+                }
+            }
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&rdata);
+        }
+        packet
+    }
+
+    /// Returns the name and type code of the question of `query`.
+    pub fn question(query: &[u8]) -> (String, u16) {
+        let (name, offset) = read_name(query, 12).unwrap();
+        (name, read_u16(query, offset).unwrap())
+    }
+
+    /// Tells whether `name` matches `record_name`, which can be a wildcard.
+    fn matches_name(record_name: &str, name: &str) -> bool {
+        match record_name.strip_prefix('*') {
+            Some(suffix) => name.to_lowercase().ends_with(&suffix.to_lowercase()),
+            None => record_name.eq_ignore_ascii_case(name),
+        }
+    }
+
+    /// Starts a UDP server answering with the records matching the question.
+    pub fn start(records: Vec<(String, RecordData)>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            while let Ok((length, peer)) = socket.recv_from(&mut buffer) {
+                let query = &buffer[..length];
+                let (name, record_type) = question(query);
+                let answers: Vec<(&str, RecordData)> = records
+                    .iter()
+                    .filter(|(record_name, data)| {
+                        matches_name(record_name, &name) && data.record_type().code() == record_type
+                    })
+                    .map(|(record_name, data)| (record_name.as_str(), data.clone()))
+                    .collect();
+                let _ = socket.send_to(&build_response(query, &answers, false), peer);
+            }
+        });
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "example.com.", RecordType::A).unwrap();
+
+        assert_eq!(
+            query,
+            vec![
+                0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p',
+                b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_query_invalid_name() {
+        assert!(encode_query(1, "example..com", RecordType::A).is_err());
+        assert!(encode_query(1, &format!("{}.com", "a".repeat(64)), RecordType::A).is_err());
+    }
+
+    #[test]
+    fn test_decode_response_with_compressed_names() {
+        let query = encode_query(42, "example.com", RecordType::Ns).unwrap();
+        let mut response = stub::build_response(&query, &[], false);
+        // Answer count
+        response[7] = 1;
+        // Name pointing to the question, type NS, class IN, TTL, data pointing to the question
+        response.extend_from_slice(&[0xC0, 12, 0, 2, 0, 1, 0, 0, 0, 60, 0, 6]);
+        response.extend_from_slice(&[3, b'n', b's', b'1', 0xC0, 12]);
+
+        let response = decode_response(42, &response).unwrap();
+
+        assert_eq!(response.response_code, RCODE_NO_ERROR);
+        assert!(response.authoritative);
+        assert_eq!(
+            response.answers,
+            vec![DnsAnswer {
+                name: "example.com".to_string(),
+                ttl: 60,
+                data: RecordData::Ns("ns1.example.com".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decode_response_id_mismatch() {
+        let query = encode_query(42, "example.com", RecordType::A).unwrap();
+        let response = stub::build_response(&query, &[], false);
+
+        assert!(decode_response(43, &response).is_err());
+    }
+
+    #[test]
+    fn test_decode_response_compression_loop() {
+        let query = encode_query(42, "example.com", RecordType::A).unwrap();
+        let mut response = stub::build_response(&query, &[], false);
+        response[7] = 1;
+        let loop_offset = response.len() as u8;
+        response.extend_from_slice(&[0xC0, loop_offset]);
+
+        assert!(decode_response(42, &response).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_udp() {
+        let server = stub::start(vec![
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            ),
+            (
+                "www.example.com".to_string(),
+                RecordData::Txt("hello".to_string()),
+            ),
+        ]);
+
+        let response = query(
+            server,
+            "www.example.com",
+            RecordType::A,
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.values(RecordType::A),
+            vec![&RecordData::A("192.168.1.1".parse().unwrap())]
+        );
+        assert!(response.values(RecordType::Txt).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_udp_drops_stray_datagrams() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (length, peer) = socket.recv_from(&mut buffer).unwrap();
+            let query = &buffer[..length];
+            let answers = [(
+                "www.example.com",
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            )];
+            // A late answer to a previous query, garbage, then the answer
+            let mut stray = stub::build_response(query, &[], false);
+            stray[0] ^= 0xFF;
+            socket.send_to(&stray, peer).unwrap();
+            socket.send_to(&[0], peer).unwrap();
+            socket
+                .send_to(&stub::build_response(query, &answers, false), peer)
+                .unwrap();
+        });
+
+        let response = query(
+            address,
+            "www.example.com",
+            RecordType::A,
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.values(RecordType::A),
+            vec![&RecordData::A("192.168.1.1".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_falls_back_to_tcp_when_truncated() {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = udp_socket.local_addr().unwrap();
+        let tcp_listener = TcpListener::bind(address).unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (length, peer) = udp_socket.recv_from(&mut buffer).unwrap();
+            let response = stub::build_response(&buffer[..length], &[], true);
+            udp_socket.send_to(&response, peer).unwrap();
+        });
+        thread::spawn(move || {
+            let (mut stream, _) = tcp_listener.accept().unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut query = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let response = stub::build_response(
+                &query,
+                &[("www.example.com", RecordData::Txt("over tcp".to_string()))],
+                false,
+            );
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        });
+
+        let response = query(
+            address,
+            "www.example.com",
+            RecordType::Txt,
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.values(RecordType::Txt),
+            vec![&RecordData::Txt("over tcp".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_nameservers() {
+        let resolver = stub::start(vec![
+            (
+                "example.com".to_string(),
+                RecordData::Ns("ns1.example.net".to_string()),
+            ),
+            (
+                "ns1.example.net".to_string(),
+                RecordData::A("192.0.2.53".parse().unwrap()),
+            ),
+            (
+                "ns1.example.net".to_string(),
+                RecordData::Aaaa("2001:db8::53".parse().unwrap()),
+            ),
+        ]);
+
+        let nameservers = lookup_nameservers(resolver, "example.com", Duration::from_secs(2))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            nameservers,
+            vec![Nameserver {
+                name: "ns1.example.net".to_string(),
+                addresses: vec![
+                    "192.0.2.53:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap()
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_resolver() {
+        assert_eq!(
+            parse_resolver("1.1.1.1").unwrap(),
+            "1.1.1.1:53".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_resolver("[::1]:5353").unwrap(),
+            "[::1]:5353".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_resolver("resolver.example.com").is_err());
+    }
+}
@@ -1,7 +1,8 @@
-use reqwest::blocking::{Client, Response};
+use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::error::Error;
+use std::fmt;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DnsRecord {
@@ -14,34 +15,72 @@ pub struct DnsRecord {
     pub updated_at: u64,
 }
 
+/// Error of a request the API answered with an unsuccessful status.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+/// Tells whether `error` is the API refusing the token, which may have been
+/// revoked or lost its scopes since it was checked.
+pub fn rejects_token(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<ApiError>().is_some_and(|error| {
+        matches!(
+            error.status,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )
+    })
+}
+
 #[derive(Debug, Deserialize)]
 struct GetRecordsResponse {
     data: Vec<DnsRecord>,
 }
 
-pub fn get_dns_records(
+/// Retrieves the records of the zone matching one of the given types.
+pub async fn get_dns_records(
     client: &Client,
     infomaniak_zones_api_url: &str,
     dns_zone_id: &str,
+    record_types: &[&str],
 ) -> Result<Vec<DnsRecord>, Box<dyn Error>> {
+    let types_filter = record_types
+        .iter()
+        .map(|record_type| format!("filter[types][]={}", record_type))
+        .collect::<Vec<String>>()
+        .join("&");
+
     // Retrieve existing records
     let response: Response = client
         .get(format!(
-            "{}/{}/records?filter[types][]=A&filter[types][]=AAAA",
-            infomaniak_zones_api_url, dns_zone_id
+            "{}/{}/records?{}",
+            infomaniak_zones_api_url, dns_zone_id, types_filter
         ))
-        .send()?;
+        .send()
+        .await?;
 
     // Return an error if the request was not successful
     if !response.status().is_success() {
-        return Err(format!(
-            "Error retrieving DNS records: {:?}",
-            response.json::<serde_json::Value>()?
-        )
+        return Err(ApiError {
+            status: response.status(),
+            message: format!(
+                "Error retrieving DNS records: {:?}",
+                response.json::<serde_json::Value>().await?
+            ),
+        }
         .into());
     }
 
-    let api_resp = response.json::<GetRecordsResponse>()?.data;
+    let api_resp = response.json::<GetRecordsResponse>().await?.data;
 
     Ok(api_resp)
 }
@@ -52,7 +91,7 @@ struct UpdateRecordResponse {
 }
 
 /// Updates or creates a DNS record via the Infomaniak API.
-pub fn update_dns_record(
+pub async fn update_dns_record(
     client: &Client,
     infomaniak_zones_api_url: &str,
     ip: &str,
@@ -75,19 +114,10 @@ pub fn update_dns_record(
 
     if let Some(id) = record_id_to_delete {
         // Update existing record
-        let delete_record_result = client
-            .delete(format!(
-                "{}/{}/records/{}",
-                infomaniak_zones_api_url, dns_zone_id, id
-            ))
-            .send()?;
-
-        if !delete_record_result.status().is_success() {
+        if let Err(e) = delete_dns_record(client, infomaniak_zones_api_url, dns_zone_id, id).await {
             return Err(format!(
                 "Error updating DNS record {} of type {}: {}",
-                records_name,
-                record_type,
-                delete_record_result.status()
+                records_name, record_type, e
             )
             .into());
         }
@@ -100,33 +130,66 @@ pub fn update_dns_record(
             infomaniak_zones_api_url, dns_zone_id
         ))
         .json(&record_data)
-        .send()?;
+        .send()
+        .await?;
 
     // Check if the request was successful
     if !create_record_result.status().is_success() {
-        return Err(format!(
-            "Error updating DNS records: {}, body: {:?}",
-            create_record_result.status(),
-            create_record_result.text()
-        )
+        return Err(ApiError {
+            status: create_record_result.status(),
+            message: format!(
+                "Error updating DNS records: {}, body: {:?}",
+                create_record_result.status(),
+                create_record_result.text().await
+            ),
+        }
         .into());
     }
 
-    let create_record_result: UpdateRecordResponse = create_record_result.json()?;
+    let create_record_result: UpdateRecordResponse = create_record_result.json().await?;
 
     Ok(create_record_result.data)
 }
 
+/// Deletes a DNS record via the Infomaniak API.
+pub async fn delete_dns_record(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    dns_zone_id: &str,
+    record_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let delete_record_result = client
+        .delete(format!(
+            "{}/{}/records/{}",
+            infomaniak_zones_api_url, dns_zone_id, record_id
+        ))
+        .send()
+        .await?;
+
+    if !delete_record_result.status().is_success() {
+        return Err(ApiError {
+            status: delete_record_result.status(),
+            message: format!(
+                "Error deleting DNS record {}: {}",
+                record_id,
+                delete_record_result.status()
+            ),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use mockito::Server;
-    use reqwest::blocking::Client;
     use serde_json::json;
 
-    #[test]
-    fn test_get_dns_records_success_with_matching_record() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_get_dns_records_success_with_matching_record() {
+        let mut server = Server::new_async().await;
         let mock = server
             .mock(
                 "GET",
@@ -157,12 +220,13 @@ mod tests {
                 })
                 .to_string(),
             )
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_dns_records(&client, &server.url(), "test-zone");
+        let result = get_dns_records(&client, &server.url(), "test-zone", &["A", "AAAA"]).await;
 
-        mock.assert();
+        mock.assert_async().await;
         assert!(result.is_ok());
         let records = result.unwrap();
         assert_eq!(records.len(), 2);
@@ -174,9 +238,9 @@ mod tests {
         assert_eq!(records[1].target, "192.168.1.2");
     }
 
-    #[test]
-    fn test_get_dns_records_success_no_matching_record() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_get_dns_records_success_no_matching_record() {
+        let mut server = Server::new_async().await;
         let mock = server
             .mock(
                 "GET",
@@ -190,20 +254,21 @@ mod tests {
                 })
                 .to_string(),
             )
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_dns_records(&client, &server.url(), "test-zone");
+        let result = get_dns_records(&client, &server.url(), "test-zone", &["A", "AAAA"]).await;
 
-        mock.assert();
+        mock.assert_async().await;
         assert!(result.is_ok());
         let records = result.unwrap();
         assert_eq!(records.len(), 0);
     }
 
-    #[test]
-    fn test_get_dns_records_api_error() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_get_dns_records_api_error() {
+        let mut server = Server::new_async().await;
         let mock = server
             .mock(
                 "GET",
@@ -212,12 +277,13 @@ mod tests {
             .with_status(404)
             .with_header("content-type", "application/json")
             .with_body(json!({"error": "Zone not found"}).to_string())
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_dns_records(&client, &server.url(), "test-zone");
+        let result = get_dns_records(&client, &server.url(), "test-zone", &["A", "AAAA"]).await;
 
-        mock.assert();
+        mock.assert_async().await;
         assert!(result.is_err());
         assert!(
             result
@@ -227,9 +293,47 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_update_dns_record_create_new() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_get_dns_records_with_txt() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA&filter[types][]=TXT",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        {
+                            "id": 123,
+                            "source": "_infomaniak-dyndns.test",
+                            "target": "heritage=infomaniak-dyndns-wildcard,owner=home",
+                            "ttl": 300,
+                            "type": "TXT",
+                            "updated_at": 1234567890
+                        }
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = Client::new();
+        let result =
+            get_dns_records(&client, &server.url(), "test-zone", &["A", "AAAA", "TXT"]).await;
+
+        mock.assert_async().await;
+        let records = result.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record_type, "TXT");
+    }
+
+    #[tokio::test]
+    async fn test_update_dns_record_create_new() {
+        let mut server = Server::new_async().await;
         let mock = server
             .mock("POST", "/test-zone/records")
             .with_status(201)
@@ -247,7 +351,8 @@ mod tests {
                 })
                 .to_string(),
             )
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
         let result = update_dns_record(
@@ -258,9 +363,10 @@ mod tests {
             "test-zone",
             "new.example.com",
             "A",
-        );
+        )
+        .await;
 
-        mock.assert();
+        mock.assert_async().await;
         assert!(result.is_ok());
         let record = result.unwrap();
         assert_eq!(record.id, 125);
@@ -268,13 +374,14 @@ mod tests {
         assert_eq!(record.target, "192.168.1.3");
     }
 
-    #[test]
-    fn test_update_dns_record_update_existing() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_update_dns_record_update_existing() {
+        let mut server = Server::new_async().await;
         let delete_mock = server
             .mock("DELETE", "/test-zone/records/123")
             .with_status(200)
-            .create();
+            .create_async()
+            .await;
 
         let create_mock = server
             .mock("POST", "/test-zone/records")
@@ -293,7 +400,8 @@ mod tests {
                 })
                 .to_string(),
             )
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
         let result = update_dns_record(
@@ -304,23 +412,25 @@ mod tests {
             "test-zone",
             "updated.example.com",
             "A",
-        );
+        )
+        .await;
 
-        delete_mock.assert();
-        create_mock.assert();
+        delete_mock.assert_async().await;
+        create_mock.assert_async().await;
         assert!(result.is_ok());
         let record = result.unwrap();
         assert_eq!(record.id, 126);
         assert_eq!(record.target, "192.168.1.4");
     }
 
-    #[test]
-    fn test_update_dns_record_delete_error() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_update_dns_record_delete_error() {
+        let mut server = Server::new_async().await;
         let delete_mock = server
             .mock("DELETE", "/test-zone/records/123")
             .with_status(404)
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
         let result = update_dns_record(
@@ -331,9 +441,10 @@ mod tests {
             "test-zone",
             "updated.example.com",
             "A",
-        );
+        )
+        .await;
 
-        delete_mock.assert();
+        delete_mock.assert_async().await;
         assert!(result.is_err());
         assert!(
             result
@@ -343,14 +454,15 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_update_dns_record_create_error() {
-        let mut server = Server::new();
+    #[tokio::test]
+    async fn test_update_dns_record_create_error() {
+        let mut server = Server::new_async().await;
         let create_mock = server
             .mock("POST", "/test-zone/records")
             .with_status(400)
             .with_body("Bad request")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
         let result = update_dns_record(
@@ -361,9 +473,10 @@ mod tests {
             "test-zone",
             "error.example.com",
             "A",
-        );
+        )
+        .await;
 
-        create_mock.assert();
+        create_mock.assert_async().await;
         assert!(result.is_err());
         assert!(
             result
@@ -372,4 +485,41 @@ mod tests {
                 .contains("Error updating DNS records")
         );
     }
+
+    #[tokio::test]
+    async fn test_delete_dns_record_success() {
+        let mut server = Server::new_async().await;
+        let delete_mock = server
+            .mock("DELETE", "/test-zone/records/123")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let client = Client::new();
+        let result = delete_dns_record(&client, &server.url(), "test-zone", "123").await;
+
+        delete_mock.assert_async().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_dns_record_error() {
+        let mut server = Server::new_async().await;
+        let delete_mock = server
+            .mock("DELETE", "/test-zone/records/123")
+            .with_status(403)
+            .create_async()
+            .await;
+
+        let client = Client::new();
+        let result = delete_dns_record(&client, &server.url(), "test-zone", "123").await;
+
+        delete_mock.assert_async().await;
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Error deleting DNS record 123")
+        );
+    }
 }
@@ -0,0 +1,295 @@
+use crate::address_family::AddressFamily;
+use crate::dns_client::{self, Nameserver, RecordType};
+use crate::propagation;
+use crate::random;
+use crate::records::RecordSet;
+use log::{debug, info};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_LABEL_PREFIX: &str = "dyndns-probe-";
+
+/// How records are checked before calling the Infomaniak API.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckMode {
+    /// Always list the records through the API.
+    Api,
+    /// Resolve the records first, and only use the API when they differ.
+    Dns,
+}
+
+impl std::str::FromStr for CheckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "api" => Ok(CheckMode::Api),
+            "dns" => Ok(CheckMode::Dns),
+            _ => Err(format!(
+                "Invalid check mode {:?}, expected \"api\" or \"dns\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the records are resolved when checking for drift.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriftCheck {
+    /// Query the authoritative nameservers of the zone, found using this resolver.
+    Authoritative(SocketAddr),
+    /// Query this resolver.
+    Resolver(SocketAddr),
+}
+
+/// Returns the name to resolve to check `record_name` of `zone`.
+///
+/// A `*` label can't be queried literally, so it is replaced by a random
+/// label which is answered by the wildcard record.
+pub fn probe_name(record_name: &str, zone: &str) -> String {
+    let record_name = match record_name.strip_prefix('*') {
+        Some(suffix) => format!(
+            "{}{:016x}{}",
+            PROBE_LABEL_PREFIX,
+            random::random_u64(),
+            suffix
+        ),
+        None => record_name.to_string(),
+    };
+    propagation::record_fqdn(&record_name, zone)
+}
+
+async fn nameservers_to_query(
+    drift_check: &DriftCheck,
+    zone: &str,
+) -> Result<Vec<Nameserver>, Box<dyn Error>> {
+    match drift_check {
+        DriftCheck::Authoritative(resolver) => {
+            dns_client::lookup_nameservers(*resolver, zone, QUERY_TIMEOUT).await
+        }
+        DriftCheck::Resolver(resolver) => Ok(vec![Nameserver {
+            name: resolver.to_string(),
+            addresses: vec![*resolver],
+        }]),
+    }
+}
+
+/// Tells whether every nameserver answers exactly the `expected` set for
+/// `name`, in any order.
+async fn answers_match(
+    nameservers: &[Nameserver],
+    name: &str,
+    record_type: RecordType,
+    expected: &[String],
+) -> Result<bool, Box<dyn Error>> {
+    let mut expected = expected.to_vec();
+    expected.sort();
+    for nameserver in nameservers {
+        let response =
+            dns_client::query_nameserver(nameserver, name, record_type, QUERY_TIMEOUT).await?;
+        let mut values: Vec<String> = response
+            .values(record_type)
+            .iter()
+            .map(|value| value.to_string())
+            .collect();
+        values.sort();
+        if values != expected {
+            info!(
+                "Nameserver {} answers {:?} for {} {:?} instead of {:?}",
+                nameserver.name, values, name, record_type, expected
+            );
+            return Ok(false);
+        }
+        debug!(
+            "Nameserver {} answers {:?} for {} {:?}",
+            nameserver.name, expected, name, record_type
+        );
+    }
+    Ok(true)
+}
+
+/// Tells whether all record sets resolve to their addresses, in which case
+/// there is no need to list or update them through the API.
+pub async fn records_in_sync(
+    drift_check: &DriftCheck,
+    zone: &str,
+    record_sets: &[RecordSet],
+) -> Result<bool, Box<dyn Error>> {
+    let nameservers = nameservers_to_query(drift_check, zone).await?;
+
+    for record_set in record_sets {
+        let name = probe_name(&record_set.name, zone);
+        let record_type = match record_set.family {
+            AddressFamily::Ipv4 => RecordType::A,
+            AddressFamily::Ipv6 => RecordType::Aaaa,
+        };
+        if !answers_match(&nameservers, &name, record_type, &record_set.targets).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_family::PublicIps;
+    use crate::dns_client::{RecordData, stub};
+    use crate::records::RecordConfig;
+
+    fn record(name: &str, families: &[AddressFamily]) -> RecordConfig {
+        RecordConfig {
+            name: name.to_string(),
+            zone: "example.com".to_string(),
+            families: families.to_vec(),
+            failover: None,
+            wans: Vec::new(),
+            addresses: Vec::new(),
+            health_check: None,
+        }
+    }
+
+    fn record_sets(records: &[RecordConfig], public_ips: &PublicIps) -> Vec<RecordSet> {
+        records
+            .iter()
+            .flat_map(|record| record.record_sets(public_ips))
+            .collect()
+    }
+
+    #[test]
+    fn test_probe_name() {
+        assert_eq!(probe_name("www", "example.com"), "www.example.com");
+        assert_eq!(probe_name(".", "example.com"), "example.com");
+
+        let probe = probe_name("*.home", "example.com");
+        assert!(probe.starts_with(PROBE_LABEL_PREFIX));
+        assert!(probe.ends_with(".home.example.com"));
+        assert_ne!(probe, probe_name("*.home", "example.com"));
+    }
+
+    #[test]
+    fn test_check_mode_from_str() {
+        assert_eq!("api".parse::<CheckMode>(), Ok(CheckMode::Api));
+        assert_eq!("dns".parse::<CheckMode>(), Ok(CheckMode::Dns));
+        assert!("resolver".parse::<CheckMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_records_in_sync() {
+        let resolver = stub::start(vec![
+            (
+                "*.example.com".to_string(),
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            ),
+            (
+                "*.example.com".to_string(),
+                RecordData::Aaaa("2001:db8::1".parse().unwrap()),
+            ),
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            ),
+        ]);
+        let drift_check = DriftCheck::Resolver(resolver);
+
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(
+                &[record("*", &[AddressFamily::Ipv4, AddressFamily::Ipv6])],
+                &PublicIps::new(
+                    Some("192.168.1.1".parse().unwrap()),
+                    Some("2001:db8::1".parse().unwrap()),
+                ),
+            ),
+        )
+        .await;
+        assert!(result.unwrap());
+
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(
+                &[record("*", &[AddressFamily::Ipv4])],
+                &PublicIps::new(Some("192.168.1.2".parse().unwrap()), None),
+            ),
+        )
+        .await;
+        assert!(!result.unwrap());
+
+        // Only the IPv6 address is published by this record
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(
+                &[record("*", &[AddressFamily::Ipv6])],
+                &PublicIps::new(
+                    Some("192.168.1.2".parse().unwrap()),
+                    Some("2001:db8::1".parse().unwrap()),
+                ),
+            ),
+        )
+        .await;
+        assert!(result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_records_in_sync_missing_record() {
+        let resolver = stub::start(vec![]);
+        let drift_check = DriftCheck::Resolver(resolver);
+
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(
+                &[record("www", &[AddressFamily::Ipv4])],
+                &PublicIps::new(Some("192.168.1.1".parse().unwrap()), None),
+            ),
+        )
+        .await;
+
+        assert!(!result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_records_in_sync_compares_sets() {
+        let resolver = stub::start(vec![
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            ),
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.2".parse().unwrap()),
+            ),
+        ]);
+        let drift_check = DriftCheck::Resolver(resolver);
+        let mut public_ips = PublicIps {
+            ipv4: vec![
+                "192.168.1.2".parse().unwrap(),
+                "192.168.1.1".parse().unwrap(),
+            ],
+            ipv6: Vec::new(),
+        };
+        let records = [record("www", &[AddressFamily::Ipv4])];
+
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(&records, &public_ips),
+        )
+        .await;
+        assert!(result.unwrap());
+
+        public_ips.ipv4.pop();
+        let result = records_in_sync(
+            &drift_check,
+            "example.com",
+            &record_sets(&records, &public_ips),
+        )
+        .await;
+        assert!(!result.unwrap());
+    }
+}
@@ -0,0 +1,260 @@
+use log::{info, warn};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+
+const DEFAULT_FAILURES_BEFORE_WITHDRAWAL: u32 = 3;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_IN_SECONDS: u64 = 5;
+
+fn default_failures() -> u32 {
+    DEFAULT_FAILURES_BEFORE_WITHDRAWAL
+}
+
+fn default_expected_status() -> u16 {
+    200
+}
+
+/// How the service behind a published address is checked.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Probe {
+    /// Opens a TCP connection to `port`.
+    Tcp { port: u16 },
+    /// Sends a GET request to `url`, whose host is resolved to the published
+    /// address, and expects `expected_status`.
+    Http {
+        url: String,
+        #[serde(default = "default_expected_status")]
+        expected_status: u16,
+    },
+}
+
+/// Health check of a record, as written in the configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(flatten)]
+    pub probe: Probe,
+    /// Consecutive failures after which an address is withdrawn.
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    /// Addresses published instead when every address of the record is
+    /// withdrawn. The record is removed when there is none.
+    #[serde(default)]
+    pub fallback: Vec<IpAddr>,
+    pub timeout_in_seconds: Option<u64>,
+}
+
+impl HealthCheckConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.timeout_in_seconds
+                .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_IN_SECONDS),
+        )
+    }
+
+    /// Checks that the service is reachable at `address`.
+    pub async fn check(&self, address: IpAddr) -> Result<(), Box<dyn Error>> {
+        match &self.probe {
+            Probe::Tcp { port } => {
+                time::timeout(self.timeout(), TcpStream::connect((address, *port)))
+                    .await
+                    .map_err(|_| format!("Connection to {} port {} timed out", address, port))??;
+                Ok(())
+            }
+            Probe::Http {
+                url,
+                expected_status,
+            } => {
+                let url = Url::parse(url)?;
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| format!("Health check URL {} has no host", url))?;
+                let client = Client::builder()
+                    .resolve(host, SocketAddr::new(address, 0))
+                    .timeout(self.timeout())
+                    .build()?;
+                let status = client.get(url.clone()).send().await?.status();
+                if status.as_u16() != *expected_status {
+                    return Err(format!(
+                        "{} answered {} instead of {}",
+                        url, status, expected_status
+                    )
+                    .into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TargetHealth {
+    consecutive_failures: u32,
+    withdrawn: bool,
+}
+
+/// Health of the addresses of the health-checked records, shared by the
+/// reconciliation tasks.
+#[derive(Debug, Default)]
+pub struct HealthChecks {
+    targets: Mutex<HashMap<(String, String), TargetHealth>>,
+}
+
+impl HealthChecks {
+    /// Records the result of a check of `target` of the record `fqdn`.
+    /// Addresses are withdrawn after `failures` consecutive failures, and
+    /// restored after a successful check.
+    pub fn record_result(&self, fqdn: &str, target: &str, failures: u32, healthy: bool) {
+        let mut targets = self.targets.lock().unwrap();
+        let health = targets
+            .entry((fqdn.to_string(), target.to_string()))
+            .or_default();
+        if healthy {
+            if health.withdrawn {
+                info!("{} of {} is healthy again, restoring it", target, fqdn);
+            }
+            health.consecutive_failures = 0;
+            health.withdrawn = false;
+        } else {
+            health.consecutive_failures += 1;
+            if !health.withdrawn && health.consecutive_failures >= failures {
+                warn!(
+                    "{} of {} failed {} health checks in a row, withdrawing it",
+                    target, fqdn, health.consecutive_failures
+                );
+                health.withdrawn = true;
+            }
+        }
+    }
+
+    /// Takes over the health of the addresses checked by `previous`, so a
+    /// reload doesn't restore withdrawn addresses.
+    pub fn inherit(&self, previous: &HealthChecks) {
+        *self.targets.lock().unwrap() = std::mem::take(&mut *previous.targets.lock().unwrap());
+    }
+
+    pub fn is_withdrawn(&self, fqdn: &str, target: &str) -> bool {
+        self.targets
+            .lock()
+            .unwrap()
+            .get(&(fqdn.to_string(), target.to_string()))
+            .is_some_and(|health| health.withdrawn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, File, FileFormat};
+    use tokio::net::TcpListener;
+
+    fn health_check(probe: Probe) -> HealthCheckConfig {
+        HealthCheckConfig {
+            probe,
+            failures: 2,
+            fallback: Vec::new(),
+            timeout_in_seconds: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_health_check_from_config() {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                [health_check]
+                type = "http"
+                url = "https://www.example.com/health"
+                fallback = ["192.0.2.1"]
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            config.get::<HealthCheckConfig>("health_check").unwrap(),
+            HealthCheckConfig {
+                probe: Probe::Http {
+                    url: "https://www.example.com/health".to_string(),
+                    expected_status: 200,
+                },
+                failures: 3,
+                fallback: vec!["192.0.2.1".parse().unwrap()],
+                timeout_in_seconds: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_health_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let address = "127.0.0.1".parse().unwrap();
+
+        assert!(
+            health_check(Probe::Tcp { port })
+                .check(address)
+                .await
+                .is_ok()
+        );
+        drop(listener);
+        assert!(
+            health_check(Probe::Tcp { port })
+                .check(address)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_resolves_host_to_address() {
+        let mut server = mockito::Server::new_async().await;
+        let port = server.socket_address().port();
+        let _m = server
+            .mock("GET", "/health")
+            .match_header("host", format!("www.example.com:{}", port).as_str())
+            .with_status(200)
+            .create_async()
+            .await;
+        let address = "127.0.0.1".parse().unwrap();
+
+        let healthy = health_check(Probe::Http {
+            url: format!("http://www.example.com:{}/health", port),
+            expected_status: 200,
+        });
+        assert!(healthy.check(address).await.is_ok());
+
+        let unexpected_status = health_check(Probe::Http {
+            url: format!("http://www.example.com:{}/health", port),
+            expected_status: 204,
+        });
+        assert!(unexpected_status.check(address).await.is_err());
+    }
+
+    #[test]
+    fn test_targets_are_withdrawn_and_restored() {
+        let health_checks = HealthChecks::default();
+
+        health_checks.record_result("www.example.com", "192.0.2.1", 2, false);
+        assert!(!health_checks.is_withdrawn("www.example.com", "192.0.2.1"));
+        health_checks.record_result("www.example.com", "192.0.2.1", 2, false);
+        assert!(health_checks.is_withdrawn("www.example.com", "192.0.2.1"));
+        assert!(!health_checks.is_withdrawn("www.example.com", "192.0.2.2"));
+
+        health_checks.record_result("www.example.com", "192.0.2.1", 2, true);
+        assert!(!health_checks.is_withdrawn("www.example.com", "192.0.2.1"));
+
+        // A reload keeps the withdrawn addresses
+        health_checks.record_result("www.example.com", "192.0.2.1", 1, false);
+        let reloaded = HealthChecks::default();
+        reloaded.inherit(&health_checks);
+        assert!(reloaded.is_withdrawn("www.example.com", "192.0.2.1"));
+    }
+}
@@ -0,0 +1,303 @@
+use config::Config;
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(3600);
+
+/// How long a new public IP must be observed before records are updated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Confirmation {
+    /// Consecutive detections of the new IP after which it is published.
+    pub checks: u32,
+    /// Time after which a new IP detected in every check since is published,
+    /// whatever the number of checks.
+    pub duration: Option<Duration>,
+}
+
+impl Default for Confirmation {
+    fn default() -> Confirmation {
+        Confirmation {
+            checks: 1,
+            duration: None,
+        }
+    }
+}
+
+impl Confirmation {
+    /// Reads `ip_change_confirmation_checks` and
+    /// `ip_change_confirmation_in_seconds` from `config`.
+    pub fn from_config(config: &Config) -> Result<Confirmation, Box<dyn Error>> {
+        let checks = config
+            .get::<u32>("ip_change_confirmation_checks")
+            .unwrap_or(1);
+        if checks == 0 {
+            return Err("ip_change_confirmation_checks must be at least 1".into());
+        }
+        Ok(Confirmation {
+            checks,
+            duration: config
+                .get::<u64>("ip_change_confirmation_in_seconds")
+                .ok()
+                .map(Duration::from_secs),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Candidate<T> {
+    ip: T,
+    checks: u32,
+    first_seen: Instant,
+}
+
+/// Public IP of a family, only changed once a new IP is confirmed.
+#[derive(Debug)]
+pub struct ConfirmedIp<T> {
+    confirmation: Confirmation,
+    confirmed: Option<T>,
+    candidate: Option<Candidate<T>>,
+}
+
+impl<T: Copy + PartialEq + fmt::Display> ConfirmedIp<T> {
+    pub fn new(confirmation: Confirmation) -> ConfirmedIp<T> {
+        ConfirmedIp {
+            confirmation,
+            confirmed: None,
+            candidate: None,
+        }
+    }
+
+    /// Applies `confirmation` to the next detections, keeping the confirmed
+    /// IP and the change being confirmed.
+    pub fn set_confirmation(&mut self, confirmation: Confirmation) {
+        self.confirmation = confirmation;
+    }
+
+    /// Records the detection of `ip` and returns the IP to publish, which is
+    /// the previous one while a new IP isn't confirmed. The first IP detected
+    /// is published right away.
+    pub fn observe(&mut self, ip: T, now: Instant) -> T {
+        let Some(confirmed) = self.confirmed else {
+            self.confirmed = Some(ip);
+            return ip;
+        };
+        if ip == confirmed {
+            if let Some(candidate) = self.candidate.take() {
+                info!(
+                    "{} is detected again, dropping the change to {}",
+                    ip, candidate.ip
+                );
+            }
+            return ip;
+        }
+
+        let candidate = match &mut self.candidate {
+            Some(candidate) if candidate.ip == ip => {
+                candidate.checks += 1;
+                candidate
+            }
+            candidate => candidate.insert(Candidate {
+                ip,
+                checks: 1,
+                first_seen: now,
+            }),
+        };
+        let seen_for = now.saturating_duration_since(candidate.first_seen);
+        let confirmed_by_duration = self
+            .confirmation
+            .duration
+            .is_some_and(|duration| seen_for >= duration);
+        if candidate.checks >= self.confirmation.checks || confirmed_by_duration {
+            self.confirmed = Some(ip);
+            self.candidate = None;
+            return ip;
+        }
+        info!(
+            "Holding back the change from {} to {}: detected {} of {} times in a row{}",
+            confirmed,
+            ip,
+            candidate.checks,
+            self.confirmation.checks,
+            match self.confirmation.duration {
+                Some(duration) => format!(", for {:?} of {:?}", seen_for, duration),
+                None => String::new(),
+            }
+        );
+        confirmed
+    }
+}
+
+/// Update held back by the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimited {
+    /// Updates of the record in the last window.
+    pub updates: usize,
+    /// Time after which the update is allowed.
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "already updated {} times in the last hour, retrying in {:?}",
+            self.updates, self.retry_in
+        )
+    }
+}
+
+/// Maximum number of updates of each record per hour.
+#[derive(Debug)]
+pub struct UpdateRateLimit {
+    max_updates: Option<usize>,
+    window: Duration,
+    updates: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Default for UpdateRateLimit {
+    fn default() -> UpdateRateLimit {
+        UpdateRateLimit::new(None)
+    }
+}
+
+impl UpdateRateLimit {
+    pub fn new(max_updates_per_hour: Option<usize>) -> UpdateRateLimit {
+        UpdateRateLimit::with_window(max_updates_per_hour, RATE_LIMIT_WINDOW)
+    }
+
+    /// Limits the updates per `window` rather than per hour.
+    pub fn with_window(max_updates: Option<usize>, window: Duration) -> UpdateRateLimit {
+        UpdateRateLimit {
+            max_updates,
+            window,
+            updates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes over the updates counted by `previous`, so a reload doesn't
+    /// reset the limit.
+    pub fn inherit(&self, previous: &UpdateRateLimit) {
+        *self.updates.lock().unwrap() = std::mem::take(&mut *previous.updates.lock().unwrap());
+    }
+
+    /// Records an update of `record` at `now` if the limit allows it, or
+    /// returns when it will be.
+    pub fn try_update(&self, record: &str, now: Instant) -> Result<(), RateLimited> {
+        let Some(max_updates) = self.max_updates else {
+            return Ok(());
+        };
+        let mut updates = self.updates.lock().unwrap();
+        let record_updates = updates.entry(record.to_string()).or_default();
+        while record_updates
+            .front()
+            .is_some_and(|update| now.saturating_duration_since(*update) >= self.window)
+        {
+            record_updates.pop_front();
+        }
+        if record_updates.len() >= max_updates {
+            let retry_in = record_updates
+                .front()
+                .map(|update| self.window.saturating_sub(now - *update))
+                .unwrap_or_default();
+            return Err(RateLimited {
+                updates: record_updates.len(),
+                retry_in,
+            });
+        }
+        record_updates.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const OLD_IP: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
+    const NEW_IP: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 5);
+    const FLAP_IP: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 6);
+
+    #[test]
+    fn test_new_ip_needs_consecutive_checks() {
+        let mut ip = ConfirmedIp::new(Confirmation {
+            checks: 3,
+            duration: None,
+        });
+        let now = Instant::now();
+
+        assert_eq!(ip.observe(OLD_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now), OLD_IP);
+        // A flap restarts the count
+        assert_eq!(ip.observe(FLAP_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now), NEW_IP);
+        assert_eq!(ip.observe(OLD_IP, now), NEW_IP);
+    }
+
+    #[test]
+    fn test_new_ip_confirmed_after_duration() {
+        let mut ip = ConfirmedIp::new(Confirmation {
+            checks: 10,
+            duration: Some(Duration::from_secs(60)),
+        });
+        let now = Instant::now();
+
+        ip.observe(OLD_IP, now);
+        assert_eq!(ip.observe(NEW_IP, now), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now + Duration::from_secs(30)), OLD_IP);
+        assert_eq!(ip.observe(NEW_IP, now + Duration::from_secs(60)), NEW_IP);
+    }
+
+    #[test]
+    fn test_update_rate_limit() {
+        let limit = UpdateRateLimit::new(Some(2));
+        let now = Instant::now();
+
+        assert!(limit.try_update("www.example.com A", now).is_ok());
+        assert!(
+            limit
+                .try_update("www.example.com A", now + Duration::from_secs(10))
+                .is_ok()
+        );
+        assert_eq!(
+            limit.try_update("www.example.com A", now + Duration::from_secs(20)),
+            Err(RateLimited {
+                updates: 2,
+                retry_in: RATE_LIMIT_WINDOW - Duration::from_secs(20)
+            })
+        );
+        assert!(
+            limit
+                .try_update("www.example.com AAAA", now + Duration::from_secs(20))
+                .is_ok()
+        );
+        assert!(
+            limit
+                .try_update("www.example.com A", now + RATE_LIMIT_WINDOW)
+                .is_ok()
+        );
+        assert!(
+            UpdateRateLimit::default()
+                .try_update("www.example.com A", now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_update_rate_limit_survives_reload() {
+        let limit = UpdateRateLimit::new(Some(1));
+        let now = Instant::now();
+        assert!(limit.try_update("www.example.com A", now).is_ok());
+
+        let reloaded = UpdateRateLimit::new(Some(1));
+        reloaded.inherit(&limit);
+        assert!(reloaded.try_update("www.example.com A", now).is_err());
+    }
+}
@@ -1,188 +1,837 @@
 use config::Config;
-use log::{error, info};
-use reqwest::blocking::Client;
+use log::{debug, error, info, warn};
+use reqwest::Client;
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue};
-use std::net::Ipv6Addr;
-use std::thread;
-use std::time::Duration;
+use std::env;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::ControlFlow;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task;
+use tokio::time;
 
+mod acme;
+mod address_family;
+mod bogon;
+mod dns_client;
 mod dns_record;
+mod drift;
+mod health_check;
+mod hysteresis;
+mod metrics;
+mod metrics_server;
+mod ownership;
+mod propagation;
 mod public_ip;
+mod random;
+mod reconcile;
+mod records;
+mod redact;
+mod reload;
+mod schedule;
+mod secrets;
+mod signals;
+mod systemd;
+mod token_check;
+mod wan;
+
+use address_family::{AddressFamily, FamilyState, PublicIps};
+use bogon::AddressFilter;
+use drift::{CheckMode, DriftCheck};
+use health_check::HealthChecks;
+use hysteresis::{Confirmation, ConfirmedIp, UpdateRateLimit};
+use metrics::Metrics;
+use ownership::UnownedRecordsPolicy;
+use propagation::VerificationSettings;
+use public_ip::{IPIFY_IPV4_URL, IPIFY_IPV6_URL, IpSource};
+use reconcile::{Reconciler, Reconciliation};
+use records::RecordConfig;
+use redact::RedactingLogger;
+use schedule::{FullVerification, Schedule, ScheduleMode};
+use signals::Signal;
+use systemd::{Notifier, Progress};
+use token_check::{InvalidTokenPolicy, TokenCheck, TokenStatus};
+use wan::{MultiWan, WanReconciliation};
 
-const IPIFY_IPV4_URL: &str = "https://api.ipify.org/";
-const IPIFY_IPV6_URL: &str = "https://api64.ipify.org/";
 const INFOMANIAK_ZONES_API_URL: &str = "https://api.infomaniak.com/2/zones";
+const DEFAULT_VERIFICATION_TIMEOUT_IN_SECONDS: u64 = 300;
+const DEFAULT_MAX_CONCURRENT_API_CALLS: usize = 4;
+const DEFAULT_MAX_BACKOFF_IN_SECONDS: u64 = 3600;
+const DEFAULT_FAILOVER_HOLD_DOWN_IN_SECONDS: u64 = 300;
+const CONFIG_FILE_ENV: &str = "INFOMANIAK_DYNDNS_WILDCARD_CONFIG_FILE";
 
-fn create_http_client(api_token: &str) -> Client {
+fn create_http_client(api_token: &str) -> Result<Client, Box<dyn Error>> {
     let mut headers: HeaderMap = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", api_token))
-            .expect("Failed to create authorization header"),
-    );
+    headers.insert(AUTHORIZATION, {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", api_token))
+            .map_err(|_| "infomaniak_api_token contains invalid characters")?;
+        // Hidden from the debug output of requests
+        value.set_sensitive(true);
+        value
+    });
     headers.insert(
         CONTENT_TYPE,
         HeaderValue::from_static("Content-Type: application/json"),
     );
-    Client::builder()
-        .default_headers(headers)
-        .build()
-        .expect("Failed to build client")
+    Ok(Client::builder().default_headers(headers).build()?)
 }
 
-fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
-    let config = Config::builder()
+fn load_config() -> Result<Config, config::ConfigError> {
+    let mut config_builder = Config::builder();
+    if let Ok(config_file) = env::var(CONFIG_FILE_ENV) {
+        config_builder = config_builder.add_source(config::File::with_name(&config_file));
+    }
+    config_builder
         .add_source(config::Environment::with_prefix(
             "infomaniak_dyndns_wildcard",
         ))
         .build()
-        .unwrap();
-    let time_between_updates_in_seconds = config
-        .get::<u64>("time_between_updates_in_seconds")
-        .expect("time_between_updates_in_seconds must be set");
-    let api_token = config
-        .get_string("infomaniak_api_token")
-        .expect("infomaniak_api_token must be set");
+}
+
+/// Returns the API client and the default zone.
+fn api_settings(config: &Config) -> Result<(Client, String), Box<dyn Error>> {
+    let api_token = secrets::get_secret(config, "infomaniak_api_token")?.ok_or(
+        "infomaniak_api_token, or one of infomaniak_api_token_file, infomaniak_api_token_command and infomaniak_api_token_keyring, must be set",
+    )?;
     let dns_zone_id = config
         .get_string("dns_zone_id")
-        .expect("dns_zone_id must be set");
-    let records_name = config
-        .get_string("records_name")
-        .expect("records_name must be set");
-    let ipv6_enabled = config.get_bool("ipv6_enabled").unwrap_or(false);
+        .map_err(|_| "dns_zone_id must be set")?;
+    Ok((create_http_client(&api_token)?, dns_zone_id))
+}
 
-    let client = create_http_client(&api_token);
+/// Settings of the main loop, all read and validated before being applied.
+struct Settings {
+    records: Vec<RecordConfig>,
+    multi_wan: MultiWan,
+    reconciler: Arc<Reconciler>,
+    metrics: Arc<Metrics>,
+    address_filter: AddressFilter,
+    ipv4_source: IpSource,
+    ipv6_source: IpSource,
+    ipv4_needed: bool,
+    ipv6_needed: bool,
+    time_between_updates: Duration,
+    max_backoff: Duration,
+    schedule: Schedule,
+    full_verification: FullVerification,
+    confirmation: Confirmation,
+    /// Whether records removed from the configuration are deleted on reload.
+    cleanup_removed_records: bool,
+    token_check: TokenCheck,
+    invalid_token_policy: InvalidTokenPolicy,
+    /// Whether the API token was checked, records aren't updated until it is.
+    token_verified: bool,
+}
 
-    loop {
-        let public_ipv4 = match public_ip::get_public_ipv4_with_url(&client, IPIFY_IPV4_URL) {
-            Ok(ip) => {
-                info!("Public IPv4: {}", ip);
-                ip
-            }
-            Err(e) => {
-                error!("Error retrieving public IPv4: {}", e);
-                thread::sleep(Duration::from_secs(time_between_updates_in_seconds));
-                continue;
-            }
+impl Settings {
+    fn from_config(config: &Config, metrics: &Arc<Metrics>) -> Result<Settings, Box<dyn Error>> {
+        let (client, dns_zone_id) = api_settings(config)?;
+        let time_between_updates_in_seconds = config
+            .get::<u64>("time_between_updates_in_seconds")
+            .map_err(|_| "time_between_updates_in_seconds must be set")?;
+        let ipv4_enabled = config.get_bool("ipv4_enabled").unwrap_or(true);
+        let ipv6_enabled = config.get_bool("ipv6_enabled").unwrap_or(false);
+        let default_families: Vec<AddressFamily> = [
+            (AddressFamily::Ipv4, ipv4_enabled),
+            (AddressFamily::Ipv6, ipv6_enabled),
+        ]
+        .into_iter()
+        .filter_map(|(family, enabled)| enabled.then_some(family))
+        .collect();
+        let address_filter = AddressFilter::from_config(config)
+            .map_err(|e| format!("Invalid allowed_addresses: {}", e))?;
+        let (failover_records, records): (Vec<RecordConfig>, Vec<RecordConfig>) =
+            records::load_records(config, &dns_zone_id, &default_families)
+                .map_err(|e| format!("Invalid records configuration: {}", e))?
+                .into_iter()
+                .partition(RecordConfig::uses_wans);
+        let confirmation = Confirmation::from_config(config)
+            .map_err(|e| format!("Invalid IP change confirmation settings: {}", e))?;
+        let multi_wan = MultiWan::new(
+            wan::load_wans(config, &address_filter, confirmation)
+                .map_err(|e| format!("Invalid WAN configuration: {}", e))?,
+            failover_records,
+            Duration::from_secs(
+                config
+                    .get::<u64>("failover_hold_down_in_seconds")
+                    .unwrap_or(DEFAULT_FAILOVER_HOLD_DOWN_IN_SECONDS),
+            ),
+        )
+        .map_err(|e| format!("Invalid WAN configuration: {}", e))?;
+        let ipv4_needed = records::any_publishes(&records, AddressFamily::Ipv4);
+        let ipv6_needed = records::any_publishes(&records, AddressFamily::Ipv6);
+        let owner_id = config.get_string("owner_id").ok();
+        let unowned_records_policy = config
+            .get_string("unowned_records")
+            .unwrap_or_else(|_| "report".to_string())
+            .parse::<UnownedRecordsPolicy>()?;
+        let dns_resolver = config.get_string("dns_resolver").ok();
+        let verification = if config.get_bool("verify_updates").unwrap_or(false) {
+            Some(VerificationSettings {
+                resolver: dns_client::resolver_or_system(dns_resolver.as_deref()).map_err(|e| {
+                    format!(
+                        "dns_resolver must be a valid address when verify_updates is enabled: {}",
+                        e
+                    )
+                })?,
+                timeout: Duration::from_secs(
+                    config
+                        .get::<u64>("verification_timeout_in_seconds")
+                        .unwrap_or(DEFAULT_VERIFICATION_TIMEOUT_IN_SECONDS),
+                ),
+            })
+        } else {
+            None
         };
+        let check_mode = config
+            .get_string("check_mode")
+            .unwrap_or_else(|_| "api".to_string())
+            .parse::<CheckMode>()?;
+        let drift_check = match (check_mode, config.get_string("check_resolver")) {
+            (CheckMode::Api, _) => None,
+            (CheckMode::Dns, Ok(check_resolver)) => Some(DriftCheck::Resolver(
+                dns_client::parse_resolver(&check_resolver)?,
+            )),
+            (CheckMode::Dns, Err(_)) => Some(DriftCheck::Authoritative(
+                dns_client::resolver_or_system(dns_resolver.as_deref()).map_err(|e| {
+                    format!(
+                        "dns_resolver must be a valid address when check_mode is \"dns\": {}",
+                        e
+                    )
+                })?,
+            )),
+        };
+        let max_concurrent_api_calls = config
+            .get::<usize>("max_concurrent_api_calls")
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_API_CALLS);
 
-        let mut public_ipv6: Option<Ipv6Addr> = None;
-        if ipv6_enabled {
-            public_ipv6 = match public_ip::get_public_ipv6_with_url(&client, IPIFY_IPV6_URL) {
-                Ok(ip) => {
-                    info!("Public IPv4: {}", ip);
-                    Some(ip)
-                }
-                Err(e) => {
-                    error!("Error retrieving public IPv4: {}", e);
-                    thread::sleep(Duration::from_secs(time_between_updates_in_seconds));
-                    continue;
+        let time_between_updates = Duration::from_secs(time_between_updates_in_seconds);
+        let schedule = Schedule::new(
+            time_between_updates,
+            config
+                .get_string("schedule_mode")
+                .unwrap_or_else(|_| "fixed-delay".to_string())
+                .parse::<ScheduleMode>()?,
+            Duration::from_secs(config.get::<u64>("jitter_in_seconds").unwrap_or(0)),
+        );
+
+        let reconciler = Arc::new(Reconciler {
+            client,
+            infomaniak_zones_api_url: INFOMANIAK_ZONES_API_URL.to_string(),
+            owner_id,
+            unowned_records_policy,
+            verification,
+            drift_check,
+            health_checks: HealthChecks::default(),
+            update_rate_limit: UpdateRateLimit::new(
+                config.get::<usize>("max_updates_per_hour").ok(),
+            ),
+            api_permits: Semaphore::new(max_concurrent_api_calls),
+            metrics: Arc::clone(metrics),
+            token_rejected: AtomicBool::new(false),
+        });
+        Ok(Settings {
+            records,
+            multi_wan,
+            reconciler,
+            metrics: Arc::clone(metrics),
+            address_filter,
+            ipv4_source: IpSource::from_config(config, AddressFamily::Ipv4, IPIFY_IPV4_URL)
+                .map_err(|e| format!("Invalid IPv4 detection settings: {}", e))?,
+            ipv6_source: IpSource::from_config(config, AddressFamily::Ipv6, IPIFY_IPV6_URL)
+                .map_err(|e| format!("Invalid IPv6 detection settings: {}", e))?,
+            ipv4_needed,
+            ipv6_needed,
+            time_between_updates,
+            max_backoff: Duration::from_secs(
+                config
+                    .get::<u64>("max_backoff_in_seconds")
+                    .unwrap_or(DEFAULT_MAX_BACKOFF_IN_SECONDS),
+            ),
+            schedule,
+            full_verification: FullVerification::from_config(config)
+                .map_err(|e| format!("Invalid full verification settings: {}", e))?,
+            confirmation,
+            cleanup_removed_records: config.get_bool("cleanup_removed_records").unwrap_or(false),
+            token_check: config
+                .get_string("token_check")
+                .unwrap_or_else(|_| "read-write".to_string())
+                .parse::<TokenCheck>()?,
+            invalid_token_policy: config
+                .get_string("invalid_token")
+                .unwrap_or_else(|_| "exit".to_string())
+                .parse::<InvalidTokenPolicy>()?,
+            token_verified: false,
+        })
+    }
+
+    /// Returns the zones of every record.
+    fn zones(&self) -> Vec<String> {
+        let mut zones: Vec<String> = self
+            .all_records()
+            .into_iter()
+            .map(|record| record.zone)
+            .collect();
+        zones.sort();
+        zones.dedup();
+        zones
+    }
+
+    /// Checks the API token has the access to the zones of the records.
+    async fn check_token(&mut self) -> TokenStatus {
+        let status = token_check::check_token(
+            &self.reconciler.client,
+            &self.reconciler.infomaniak_zones_api_url,
+            &self.zones(),
+            self.token_check,
+        )
+        .await;
+        self.token_verified = status == TokenStatus::Valid;
+        status
+    }
+
+    /// Returns every record, including the ones published on several WANs.
+    fn all_records(&self) -> Vec<RecordConfig> {
+        let mut records = self.records.clone();
+        records.extend(self.multi_wan.records.iter().cloned());
+        records
+    }
+}
+
+/// State of the main loop, kept across reloads so they don't reset the
+/// backoff, the confirmation of new IPs or the published IPs.
+struct LoopState {
+    ipv4_state: FamilyState,
+    ipv6_state: FamilyState,
+    confirmed_ipv4: ConfirmedIp<Ipv4Addr>,
+    confirmed_ipv6: ConfirmedIp<Ipv6Addr>,
+    /// IPs the records were last reconciled to.
+    reconciled_ipv4: Option<Ipv4Addr>,
+    reconciled_ipv6: Option<Ipv6Addr>,
+    /// Whether the records outside multi-WAN were up to date at their last
+    /// reconciliation.
+    records_healthy: bool,
+}
+
+impl LoopState {
+    fn new(confirmation: Confirmation) -> LoopState {
+        LoopState {
+            ipv4_state: FamilyState::new(AddressFamily::Ipv4),
+            ipv6_state: FamilyState::new(AddressFamily::Ipv6),
+            confirmed_ipv4: ConfirmedIp::new(confirmation),
+            confirmed_ipv6: ConfirmedIp::new(confirmation),
+            reconciled_ipv4: None,
+            reconciled_ipv6: None,
+            records_healthy: false,
+        }
+    }
+
+    /// Applies the settings changed from `old` to `new`.
+    fn apply(&mut self, old: &Settings, new: &Settings) {
+        if new.confirmation != old.confirmation {
+            self.confirmed_ipv4.set_confirmation(new.confirmation);
+            self.confirmed_ipv6.set_confirmation(new.confirmation);
+        }
+        // Changed records are reconciled at the next cycle, even if the
+        // public IPs didn't change
+        if new.records != old.records {
+            self.reconciled_ipv4 = None;
+            self.reconciled_ipv6 = None;
+        }
+    }
+}
+
+/// Why the main loop stopped.
+#[derive(Debug, PartialEq)]
+enum Stop {
+    Shutdown,
+    Reload,
+}
+
+#[tokio::main]
+async fn main() {
+    let logger = env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(RedactingLogger::new(logger)))
+        .expect("Failed to set the logger");
+    redact::install_panic_hook();
+
+    let mut config = load_config().unwrap_or_else(|e| panic!("Invalid configuration: {}", e));
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        let result = match command.as_str() {
+            "acme" => match api_settings(&config) {
+                Ok((client, dns_zone_id)) => {
+                    acme::run(
+                        &client,
+                        INFOMANIAK_ZONES_API_URL,
+                        &config,
+                        &dns_zone_id,
+                        &args[1..],
+                    )
+                    .await
                 }
-            };
+                Err(e) => Err(e),
+            },
+            "systemd-unit" => systemd::run(&args[1..]),
+            _ => Err(format!("Unknown command {:?}", command).into()),
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let mut settings = Settings::from_config(&config, &metrics).unwrap_or_else(|e| panic!("{}", e));
+    let (sender, mut events) = mpsc::unbounded_channel();
+    signals::listen(sender.clone()).expect("Failed to listen to signals");
+    if let Some(path) = env::var(CONFIG_FILE_ENV)
+        .ok()
+        .and_then(|name| reload::config_file_path(&name))
+    {
+        reload::watch(path, sender);
+    }
+    match settings.check_token().await {
+        TokenStatus::Valid => {}
+        TokenStatus::Unverified(e) => warn!(
+            "Couldn't check the API token, checking it again at the next cycle: {}",
+            e
+        ),
+        TokenStatus::Rejected(e) if settings.invalid_token_policy == InvalidTokenPolicy::Exit => {
+            error!("{}", e);
+            process::exit(1);
+        }
+        TokenStatus::Rejected(e) => {
+            error!("{}, records won't be updated until the token is fixed", e)
+        }
+    }
+    let metrics_listener =
+        match systemd::activated_listener().expect("Failed to use the socket passed by systemd") {
+            Some(listener) => Some(listener),
+            None => match config.get_string("metrics_listen_address") {
+                Ok(address) => Some(
+                    TcpListener::bind(&address)
+                        .await
+                        .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", address, e)),
+                ),
+                Err(_) => None,
+            },
+        };
+    if let Some(listener) = metrics_listener {
+        metrics_server::serve(listener, Arc::clone(&metrics));
+    }
+    let notifier = Arc::new(Notifier::from_env());
+    let progress = Arc::new(Progress::default());
+    systemd::start_watchdog(Arc::clone(&notifier), Arc::clone(&progress));
+
+    if settings.ipv4_needed {
+        bogon::warn_if_behind_cgnat();
+    }
+    let mut state = LoopState::new(settings.confirmation);
+    while run(&mut settings, &mut state, &mut events, &notifier, &progress).await == Stop::Reload {
+        notifier.reloading();
+        progress.step();
+        reload(&mut config, &mut settings, &mut state, &metrics).await;
+        progress.idle();
+        notifier.reloaded();
+    }
+    notifier.stopping();
+    info!("Stopped");
+}
+
+/// Applies the new configuration, or keeps the current one when it is
+/// invalid.
+async fn reload(
+    config: &mut Config,
+    settings: &mut Settings,
+    state: &mut LoopState,
+    metrics: &Arc<Metrics>,
+) {
+    info!("Reloading the configuration");
+    let new_config = match load_config() {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("Invalid configuration, keeping the current one: {}", e);
+            return;
+        }
+    };
+    // Secrets may be printed by commands, which mustn't block the runtime
+    let settings_config = new_config.clone();
+    let settings_metrics = Arc::clone(metrics);
+    let new_settings = task::spawn_blocking(move || {
+        Settings::from_config(&settings_config, &settings_metrics).map_err(|e| e.to_string())
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    let mut new_settings = match new_settings {
+        Ok(new_settings) => new_settings,
+        Err(e) => {
+            error!("Invalid configuration, keeping the current one: {}", e);
+            return;
         }
+    };
+    if let TokenStatus::Rejected(e) = new_settings.check_token().await {
+        error!("Invalid configuration, keeping the current one: {}", e);
+        return;
+    }
+    let changed_settings = reload::changed_settings(config, &new_config);
+    if changed_settings.is_empty() {
+        info!("The configuration didn't change");
+    } else {
+        info!("Changed settings: {}", changed_settings.join(", "));
+    }
+    let (old_records, new_records) = (settings.all_records(), new_settings.all_records());
+    for record in reload::missing_records(&new_records, &old_records) {
+        info!(
+            "Starting to manage record {} of {}",
+            record.name, record.zone
+        );
+    }
+    let removed_records = reload::missing_records(&old_records, &new_records);
+    for record in &removed_records {
+        info!(
+            "No longer managing record {} of {}",
+            record.name, record.zone
+        );
+    }
+    if new_settings.cleanup_removed_records && !removed_records.is_empty() {
+        new_settings
+            .reconciler
+            .remove_records(&removed_records)
+            .await;
+    }
+    // Only the settings are replaced, what was learned at runtime is kept
+    new_settings
+        .reconciler
+        .update_rate_limit
+        .inherit(&settings.reconciler.update_rate_limit);
+    new_settings
+        .reconciler
+        .health_checks
+        .inherit(&settings.reconciler.health_checks);
+    new_settings.multi_wan.inherit(&mut settings.multi_wan);
+    new_settings
+        .full_verification
+        .inherit(&settings.full_verification);
+    state.apply(settings, &new_settings);
+    *config = new_config;
+    *settings = new_settings;
+}
+
+/// Keeps the records up to date until a signal asks to stop or reload.
+async fn run(
+    settings: &mut Settings,
+    state: &mut LoopState,
+    events: &mut mpsc::UnboundedReceiver<Signal>,
+    notifier: &Notifier,
+    progress: &Progress,
+) -> Stop {
+    let zones = settings.zones();
+    let Settings {
+        ref records,
+        ref mut multi_wan,
+        ref reconciler,
+        ref metrics,
+        ref address_filter,
+        ref ipv4_source,
+        ref ipv6_source,
+        ipv4_needed,
+        ipv6_needed,
+        time_between_updates,
+        max_backoff,
+        ref mut schedule,
+        ref mut full_verification,
+        token_check,
+        invalid_token_policy,
+        ref mut token_verified,
+        ..
+    } = *settings;
+    // Health checks run during the reconciliation, which can't be skipped then
+    let health_checked = records.iter().any(|record| record.health_check.is_some());
+    let LoopState {
+        ref mut ipv4_state,
+        ref mut ipv6_state,
+        ref mut confirmed_ipv4,
+        ref mut confirmed_ipv6,
+        ref mut reconciled_ipv4,
+        ref mut reconciled_ipv6,
+        ref mut records_healthy,
+    } = *state;
+    let mut forced = false;
+    metrics
+        .token_valid
+        .store(*token_verified, Ordering::Relaxed);
 
-        let dns_records =
-            match dns_record::get_dns_records(&client, INFOMANIAK_ZONES_API_URL, &dns_zone_id) {
-                Ok(records) => {
-                    info!("Existing DNS record found: {:?}", records);
-                    records
+    loop {
+        let cycle_start = Instant::now();
+        progress.step();
+        if !*token_verified {
+            let degraded = match token_check::check_token(
+                &reconciler.client,
+                &reconciler.infomaniak_zones_api_url,
+                &zones,
+                token_check,
+            )
+            .await
+            {
+                TokenStatus::Valid => {
+                    info!("The API token is valid, updating the records");
+                    *token_verified = true;
+                    metrics.token_valid.store(true, Ordering::Relaxed);
+                    None
                 }
-                Err(e) => {
-                    error!("Error retrieving DNS records for IPv4: {}", e);
-                    thread::sleep(Duration::from_secs(time_between_updates_in_seconds));
-                    continue;
+                TokenStatus::Rejected(e) if invalid_token_policy == InvalidTokenPolicy::Exit => {
+                    error!("{}", e);
+                    notifier.stopping();
+                    process::exit(1);
                 }
-            };
-
-        for record_name in records_name.split(',') {
-            info!("Updating record: {:?}", record_name);
-            let mut record_a_found = false;
-            let mut record_aaaa_found = false;
-            for record in &dns_records {
-                if record.source == record_name && record.record_type == "A" {
-                    info!("Found A record: {:?}", record);
-                    record_a_found = true;
-
-                    if record.target == public_ipv4.to_string() {
-                        info!("DNS record for IPv4 is already up to date.");
-                    } else {
-                        info!("Updating DNS record for IPv4...");
-                        match dns_record::update_dns_record(
-                            &client,
-                            INFOMANIAK_ZONES_API_URL,
-                            &public_ipv4.to_string(),
-                            Some(&record.id.to_string()),
-                            &dns_zone_id,
-                            record_name,
-                            "A",
-                        ) {
-                            Ok(result) => info!("Update IPv4 successful: {:?}", result),
-                            Err(e) => error!("Error updating DNS for IPv4: {}", e),
-                        }
-                    }
+                TokenStatus::Rejected(e) => {
+                    error!("{}, records won't be updated until the token is fixed", e);
+                    Some(e)
                 }
-                if ipv6_enabled && record.source == record_name && record.record_type == "AAAA" {
-                    info!("Found AAAA record: {:?}", record);
-                    record_aaaa_found = true;
-
-                    if record.target == public_ipv6.unwrap().to_string() {
-                        info!("DNS record for IPv6 is already up to date.");
-                    } else {
-                        info!("Updating DNS record for IPv6...");
-                        match dns_record::update_dns_record(
-                            &client,
-                            INFOMANIAK_ZONES_API_URL,
-                            &public_ipv6.unwrap().to_string(),
-                            Some(&record.id.to_string()),
-                            &dns_zone_id,
-                            record_name,
-                            "AAAA",
-                        ) {
-                            Ok(result) => info!("Update IPv6 successful: {:?}", result),
-                            Err(e) => error!("Error updating DNS for IPv6: {}", e),
-                        }
-                    }
+                TokenStatus::Unverified(e) => {
+                    error!(
+                        "Couldn't check the API token, not updating the records: {}",
+                        e
+                    );
+                    Some(e)
                 }
-                if record_a_found && record_aaaa_found {
-                    break; // No need to check further if both records are found
+            };
+            if let Some(e) = degraded {
+                metrics.healthy.store(false, Ordering::Relaxed);
+                notifier.status(&format!("Degraded: {}", e));
+                progress.idle();
+                match wait(schedule.sleep_duration(cycle_start), events).await {
+                    ControlFlow::Continue(forced_now) => forced = forced_now,
+                    ControlFlow::Break(stop) => return stop,
                 }
+                continue;
             }
-            if !record_a_found {
-                info!("No matching A record found for {}", record_name);
-                match dns_record::update_dns_record(
-                    &client,
-                    INFOMANIAK_ZONES_API_URL,
-                    &public_ipv4.to_string(),
-                    None,
-                    &dns_zone_id,
-                    record_name,
-                    "A",
-                ) {
-                    Ok(result) => info!("Update IPv4 successful: {:?}", result),
-                    Err(e) => error!("Error updating DNS for IPv4: {}", e),
+        }
+        let full_verification_due =
+            forced || health_checked || full_verification.is_due(SystemTime::now());
+        let ipv4_due = ipv4_needed && (forced || ipv4_state.is_due(cycle_start));
+        let ipv6_due = ipv6_needed && (forced || ipv6_state.is_due(cycle_start));
+
+        let (public_ipv4, public_ipv6) = tokio::join!(
+            async {
+                if ipv4_due {
+                    Some(ipv4_source.detect_ipv4(address_filter).await)
+                } else {
+                    None
                 }
-            }
-            if ipv6_enabled && !record_aaaa_found {
-                info!("No matching AAAA record found for {}", record_name);
-                match dns_record::update_dns_record(
-                    &client,
-                    INFOMANIAK_ZONES_API_URL,
-                    &public_ipv6.unwrap().to_string(),
-                    None,
-                    &dns_zone_id,
-                    record_name,
-                    "AAAA",
-                ) {
-                    Ok(result) => info!("Update IPv6 successful: {:?}", result),
-                    Err(e) => error!("Error updating DNS for IPv6: {}", e),
+            },
+            async {
+                if ipv6_due {
+                    Some(ipv6_source.detect_ipv6(address_filter).await)
+                } else {
+                    None
                 }
             }
+        );
+
+        progress.step();
+
+        let public_ipv4 = match public_ipv4 {
+            Some(Ok(ip)) => {
+                info!("Public IPv4: {}", ip);
+                metrics.ipv4.record_detection(true);
+                Some(confirmed_ipv4.observe(ip, cycle_start))
+            }
+            Some(Err(e)) => {
+                metrics.ipv4.record_detection(false);
+                let backoff =
+                    ipv4_state.record_failure(cycle_start, time_between_updates, max_backoff);
+                error!(
+                    "Error retrieving public IPv4, retrying in {:?}: {}",
+                    backoff, e
+                );
+                None
+            }
+            None => None,
+        };
+
+        let public_ipv6 = match public_ipv6 {
+            Some(Ok(ip)) => {
+                info!("Public IPv6: {}", ip);
+                metrics.ipv6.record_detection(true);
+                Some(confirmed_ipv6.observe(ip, cycle_start))
+            }
+            Some(Err(e)) => {
+                metrics.ipv6.record_detection(false);
+                let backoff =
+                    ipv6_state.record_failure(cycle_start, time_between_updates, max_backoff);
+                error!(
+                    "Error retrieving public IPv6, retrying in {:?}: {}",
+                    backoff, e
+                );
+                None
+            }
+            None => None,
+        };
+
+        let public_ips_changed = (public_ipv4.is_some() && public_ipv4 != *reconciled_ipv4)
+            || (public_ipv6.is_some() && public_ipv6 != *reconciled_ipv6);
+        let reconciled = (public_ipv4.is_some() || public_ipv6.is_some())
+            && (full_verification_due || public_ips_changed);
+        let reconciliation = if reconciled {
+            reconciler
+                .reconcile_all(records, &PublicIps::new(public_ipv4, public_ipv6))
+                .await
+        } else {
+            if public_ipv4.is_some() || public_ipv6.is_some() {
+                debug!("Public IPs unchanged, skipping the verification of the records");
+            }
+            Reconciliation::default()
+        };
+        progress.step();
+        if reconciled {
+            if reconciliation.is_done(AddressFamily::Ipv4) && public_ipv4.is_some() {
+                *reconciled_ipv4 = public_ipv4;
+            }
+            if reconciliation.is_done(AddressFamily::Ipv6) && public_ipv6.is_some() {
+                *reconciled_ipv6 = public_ipv6;
+            }
+        }
+        for (state, public_ip_found) in [
+            (&mut *ipv4_state, public_ipv4.is_some()),
+            (&mut *ipv6_state, public_ipv6.is_some()),
+        ] {
+            if !public_ip_found {
+                continue;
+            }
+            if reconciliation.failed.contains(&state.family) {
+                let backoff = state.record_failure(cycle_start, time_between_updates, max_backoff);
+                warn!(
+                    "Some {} records failed to update, retrying in {:?}",
+                    state.family, backoff
+                );
+            } else if let Some(retry_in) = reconciliation.deferred(state.family) {
+                state.defer(cycle_start, retry_in);
+            } else {
+                state.record_success();
+            }
+        }
+
+        let wan_reconciliation = if multi_wan.records.is_empty() {
+            WanReconciliation::default()
+        } else {
+            let wan_reconciliation = multi_wan
+                .reconcile(
+                    reconciler,
+                    full_verification_due,
+                    time_between_updates,
+                    max_backoff,
+                )
+                .await;
+            progress.step();
+            wan_reconciliation
+        };
+
+        if reconciler.take_token_rejection() {
+            warn!("The API refused the token, checking it again");
+            *token_verified = false;
+            metrics.token_valid.store(false, Ordering::Relaxed);
         }
 
-        thread::sleep(Duration::from_secs(time_between_updates_in_seconds));
+        let records_verified = records.is_empty() || (reconciled && reconciliation.is_complete());
+        if full_verification_due && records_verified && wan_reconciliation.is_complete() {
+            full_verification.record_done(SystemTime::now());
+        }
+
+        let detection_failed =
+            (ipv4_due && public_ipv4.is_none()) || (ipv6_due && public_ipv6.is_none());
+        if detection_failed || reconciled || records.is_empty() {
+            *records_healthy = !detection_failed && reconciliation.failed.is_empty();
+        }
+        let healthy = *records_healthy && wan_reconciliation.is_healthy();
+        metrics.healthy.store(healthy, Ordering::Relaxed);
+        // Started once the records are up to date, so units ordered after
+        // this one can rely on them
+        if healthy {
+            notifier.ready();
+        }
+        notifier.status(&status(
+            ipv4_needed.then_some(reconciled_ipv4.map(IpAddr::V4)),
+            ipv6_needed.then_some(reconciled_ipv6.map(IpAddr::V6)),
+            &wan_reconciliation.records_down,
+            healthy,
+        ));
+
+        debug!("IPv4 metrics: {}", metrics.ipv4);
+        debug!("IPv6 metrics: {}", metrics.ipv6);
+        progress.idle();
+        match wait(schedule.sleep_duration(cycle_start), events).await {
+            ControlFlow::Continue(forced_now) => forced = forced_now,
+            ControlFlow::Break(stop) => return stop,
+        }
+    }
+}
+
+/// Waits for the next cycle, or until a signal interrupts the wait. Returns
+/// whether the next cycle is forced, or why the loop must stop.
+///
+/// Signals received during the cycle are handled once it is done, so records
+/// are never left half updated.
+async fn wait(
+    duration: Duration,
+    events: &mut mpsc::UnboundedReceiver<Signal>,
+) -> ControlFlow<Stop, bool> {
+    tokio::select! {
+        _ = time::sleep(duration) => ControlFlow::Continue(false),
+        signal = events.recv() => match signal {
+            Some(Signal::Shutdown) | None => ControlFlow::Break(Stop::Shutdown),
+            Some(Signal::Reload) => ControlFlow::Break(Stop::Reload),
+            Some(Signal::Reconcile) => {
+                info!("Reconciling now");
+                ControlFlow::Continue(true)
+            }
+        },
+    }
+}
+
+/// Returns the status shown by systemd, with the published IP of each
+/// family used and the multi-WAN records whose uplinks are all down.
+fn status(
+    ipv4: Option<Option<IpAddr>>,
+    ipv6: Option<Option<IpAddr>>,
+    records_down: &[String],
+    healthy: bool,
+) -> String {
+    let ips: Vec<String> = [("IPv4", ipv4), ("IPv6", ipv6)]
+        .into_iter()
+        .filter_map(|(family, ip)| {
+            let ip = ip?;
+            Some(format!(
+                "{} {}",
+                family,
+                ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+            ))
+        })
+        .collect();
+    let mut status = if ips.is_empty() {
+        "Managing multi-WAN records".to_string()
+    } else {
+        format!("Public {}", ips.join(", "))
+    };
+    if !records_down.is_empty() {
+        status.push_str(&format!(
+            ", all WANs of {} are down",
+            records_down.join(", ")
+        ));
+    }
+    if !healthy {
+        status.push_str(", some records aren't up to date");
     }
+    status
 }
@@ -0,0 +1,200 @@
+use crate::address_family::AddressFamily;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counters of an address family.
+#[derive(Debug, Default)]
+pub struct FamilyMetrics {
+    pub detections_succeeded: AtomicU64,
+    pub detections_failed: AtomicU64,
+    pub updates_succeeded: AtomicU64,
+    pub updates_failed: AtomicU64,
+    /// Unix timestamp of the last successful detection.
+    pub last_success_timestamp: AtomicU64,
+}
+
+impl FamilyMetrics {
+    pub fn record_detection(&self, succeeded: bool) {
+        if succeeded {
+            self.detections_succeeded.fetch_add(1, Ordering::Relaxed);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            self.last_success_timestamp.store(now, Ordering::Relaxed);
+        } else {
+            self.detections_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_update(&self, succeeded: bool) {
+        if succeeded {
+            self.updates_succeeded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.updates_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Display for FamilyMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "detections succeeded={} failed={}, updates succeeded={} failed={}, last success at {}",
+            self.detections_succeeded.load(Ordering::Relaxed),
+            self.detections_failed.load(Ordering::Relaxed),
+            self.updates_succeeded.load(Ordering::Relaxed),
+            self.updates_failed.load(Ordering::Relaxed),
+            self.last_success_timestamp.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters of the daemon, kept separately for each address family.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub ipv4: FamilyMetrics,
+    pub ipv6: FamilyMetrics,
+    /// Whether every record was up to date after the last reconciliation.
+    pub healthy: AtomicBool,
+    /// Whether the API token was accepted with the access it needs.
+    pub token_valid: AtomicBool,
+}
+
+impl Metrics {
+    pub fn family(&self, family: AddressFamily) -> &FamilyMetrics {
+        match family {
+            AddressFamily::Ipv4 => &self.ipv4,
+            AddressFamily::Ipv6 => &self.ipv6,
+        }
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let families = [("ipv4", &self.ipv4), ("ipv6", &self.ipv6)];
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut text = String::new();
+        for (name, help, succeeded, failed) in [
+            (
+                "detections",
+                "Detections of the public IP.",
+                families.map(|(family, metrics)| (family, load(&metrics.detections_succeeded))),
+                families.map(|(family, metrics)| (family, load(&metrics.detections_failed))),
+            ),
+            (
+                "updates",
+                "Updates of the DNS records.",
+                families.map(|(family, metrics)| (family, load(&metrics.updates_succeeded))),
+                families.map(|(family, metrics)| (family, load(&metrics.updates_failed))),
+            ),
+        ] {
+            let name = format!("infomaniak_dyndns_{}_total", name);
+            write_header(&mut text, &name, "counter", help);
+            for (result, values) in [("success", succeeded), ("failure", failed)] {
+                for (family, value) in values {
+                    let _ = writeln!(
+                        text,
+                        "{}{{family=\"{}\",result=\"{}\"}} {}",
+                        name, family, result, value
+                    );
+                }
+            }
+        }
+        let name = "infomaniak_dyndns_last_success_timestamp_seconds";
+        write_header(
+            &mut text,
+            name,
+            "gauge",
+            "Last successful detection of the public IP.",
+        );
+        for (family, metrics) in families {
+            let _ = writeln!(
+                text,
+                "{}{{family=\"{}\"}} {}",
+                name,
+                family,
+                load(&metrics.last_success_timestamp)
+            );
+        }
+        let name = "infomaniak_dyndns_healthy";
+        write_header(
+            &mut text,
+            name,
+            "gauge",
+            "Whether every record was up to date after the last reconciliation.",
+        );
+        let _ = writeln!(
+            text,
+            "{} {}",
+            name,
+            u8::from(self.healthy.load(Ordering::Relaxed))
+        );
+        let name = "infomaniak_dyndns_token_valid";
+        write_header(
+            &mut text,
+            name,
+            "gauge",
+            "Whether the API token was accepted with the access it needs.",
+        );
+        let _ = writeln!(
+            text,
+            "{} {}",
+            name,
+            u8::from(self.token_valid.load(Ordering::Relaxed))
+        );
+        text
+    }
+}
+
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_kept_per_family() {
+        let metrics = Metrics::default();
+
+        metrics.family(AddressFamily::Ipv4).record_detection(true);
+        metrics.family(AddressFamily::Ipv4).record_update(true);
+        metrics.family(AddressFamily::Ipv6).record_detection(false);
+
+        assert_eq!(metrics.ipv4.detections_succeeded.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.ipv4.updates_succeeded.load(Ordering::Relaxed), 1);
+        assert!(metrics.ipv4.last_success_timestamp.load(Ordering::Relaxed) > 0);
+        assert_eq!(metrics.ipv6.detections_failed.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.ipv6.detections_succeeded.load(Ordering::Relaxed), 0);
+        assert!(
+            metrics
+                .ipv6
+                .to_string()
+                .starts_with("detections succeeded=0 failed=1")
+        );
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = Metrics::default();
+
+        metrics.ipv4.record_update(false);
+        metrics.healthy.store(true, Ordering::Relaxed);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE infomaniak_dyndns_updates_total counter\n"));
+        assert!(
+            text.contains(
+                "infomaniak_dyndns_updates_total{family=\"ipv4\",result=\"failure\"} 1\n"
+            )
+        );
+        assert!(text.contains(
+            "infomaniak_dyndns_detections_total{family=\"ipv6\",result=\"success\"} 0\n"
+        ));
+        assert!(text.contains("infomaniak_dyndns_healthy 1\n"));
+    }
+}
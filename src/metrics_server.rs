@@ -0,0 +1,132 @@
+use crate::metrics::Metrics;
+use crate::redact;
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the metrics on `/metrics` and the health on `/health`, which
+/// answers 503 while records aren't up to date.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving metrics and health on {}", address);
+    }
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error accepting a metrics connection: {}", e);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let metrics = Arc::clone(&metrics);
+            tokio::spawn(async move {
+                match time::timeout(REQUEST_TIMEOUT, handle(stream, &metrics)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("Error answering a metrics request: {}", e),
+                    Err(_) => debug!("Metrics request timed out"),
+                }
+            });
+        }
+    });
+}
+
+/// Reads the request up to the end of its headers and returns its path.
+async fn read_path(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let size = stream.read(&mut buffer).await?;
+        if size == 0 || request.len() + size > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..size]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    Ok(match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => Some(path.to_string()),
+        _ => None,
+    })
+}
+
+async fn handle(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let (status, content_type, body) = match read_path(&mut stream).await?.as_deref() {
+        Some("/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            redact::redact(&metrics.to_prometheus()),
+        ),
+        Some("/health") if metrics.healthy.load(Ordering::Relaxed) => {
+            ("200 OK", "text/plain", "OK\n".to_string())
+        }
+        Some("/health") => (
+            "503 Service Unavailable",
+            "text/plain",
+            "Records aren't up to date\n".to_string(),
+        ),
+        Some(_) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        None => ("400 Bad Request", "text/plain", "Bad request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics_and_health() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        serve(listener, Arc::clone(&metrics));
+
+        assert!(
+            get(address, "/health")
+                .await
+                .starts_with("HTTP/1.1 503 Service Unavailable\r\n")
+        );
+        metrics.healthy.store(true, Ordering::Relaxed);
+        assert!(
+            get(address, "/health")
+                .await
+                .starts_with("HTTP/1.1 200 OK\r\n")
+        );
+        let response = get(address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ninfomaniak_dyndns_healthy 1\n"));
+        assert!(
+            get(address, "/unknown")
+                .await
+                .starts_with("HTTP/1.1 404 Not Found\r\n")
+        );
+    }
+}
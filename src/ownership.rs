@@ -0,0 +1,316 @@
+use crate::dns_record::DnsRecord;
+
+/// Prefix of the TXT records used to mark a record as managed by an instance.
+pub const OWNERSHIP_RECORD_PREFIX: &str = "_infomaniak-dyndns";
+
+const OWNERSHIP_HERITAGE: &str = "heritage=infomaniak-dyndns-wildcard";
+
+/// Ownership state of a record name, computed from the records of the zone.
+#[derive(Debug, PartialEq)]
+pub enum Ownership {
+    /// A marker with our owner id exists.
+    Owned,
+    /// A marker with another owner id exists.
+    OwnedByOther(String),
+    /// No marker exists but A or AAAA records already exist for this name.
+    Unowned,
+    /// No marker and no A or AAAA records exist for this name.
+    Free,
+}
+
+/// What to do with A/AAAA records that exist but are not owned by anyone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnownedRecordsPolicy {
+    /// Log the conflict and leave the records untouched.
+    Report,
+    /// Take over the records and mark them as ours.
+    Adopt,
+}
+
+impl std::str::FromStr for UnownedRecordsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(UnownedRecordsPolicy::Report),
+            "adopt" => Ok(UnownedRecordsPolicy::Adopt),
+            _ => Err(format!(
+                "Invalid unowned records policy {:?}, expected \"report\" or \"adopt\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns the name of the TXT record marking the ownership of `record_name`.
+///
+/// Wildcard labels are replaced by `wildcard` since a `*` label is only valid
+/// as the leftmost label of a name, so `*.home` and `wildcard.home` share
+/// the name of their markers: markers tell which record they mark.
+pub fn ownership_record_name(record_name: &str) -> String {
+    if record_name == "." || record_name.is_empty() {
+        return OWNERSHIP_RECORD_PREFIX.to_string();
+    }
+    let labels: Vec<&str> = record_name
+        .split('.')
+        .map(|label| if label == "*" { "wildcard" } else { label })
+        .collect();
+    format!("{}.{}", OWNERSHIP_RECORD_PREFIX, labels.join("."))
+}
+
+/// Returns the content of the TXT record marking `record_name` as owned by
+/// `owner_id`.
+pub fn ownership_marker(owner_id: &str, record_name: &str) -> String {
+    format!(
+        "{},owner={},record={}",
+        OWNERSHIP_HERITAGE, owner_id, record_name
+    )
+}
+
+/// Extracts the owner id and the marked record from a TXT record content, if
+/// it is one of our markers. Markers written by older versions don't tell the
+/// record.
+fn parse_ownership_marker(target: &str) -> Option<(&str, Option<&str>)> {
+    let target = target.trim().trim_matches('"');
+    let owner = target
+        .strip_prefix(OWNERSHIP_HERITAGE)?
+        .strip_prefix(",owner=")?;
+    Some(match owner.rsplit_once(",record=") {
+        Some((owner, record_name)) => (owner, Some(record_name)),
+        None => (owner, None),
+    })
+}
+
+/// Returns the owner id of `marker` if it is a marker of `record_name`.
+///
+/// Markers written by older versions don't tell the record, so they are only
+/// taken for the marker of `record_name` when no other name of `records`
+/// sharing their name, like `*.home` and `wildcard.home`, has A or AAAA
+/// records they could mark.
+pub fn marker_owner<'a>(
+    marker: &'a DnsRecord,
+    record_name: &str,
+    records: &[DnsRecord],
+) -> Option<&'a str> {
+    if marker.record_type != "TXT" || marker.source != ownership_record_name(record_name) {
+        return None;
+    }
+    match parse_ownership_marker(&marker.target)? {
+        (owner, None) => {
+            let ambiguous = records.iter().any(|record| {
+                (record.record_type == "A" || record.record_type == "AAAA")
+                    && record.source != record_name
+                    && ownership_record_name(&record.source) == marker.source
+            });
+            (!ambiguous).then_some(owner)
+        }
+        (owner, Some(marked_name)) if marked_name == record_name => Some(owner),
+        _ => None,
+    }
+}
+
+/// Computes the ownership of `record_name` for the instance `owner_id`.
+pub fn get_record_ownership(records: &[DnsRecord], record_name: &str, owner_id: &str) -> Ownership {
+    let marker_owner = records
+        .iter()
+        .find_map(|record| marker_owner(record, record_name, records));
+
+    match marker_owner {
+        Some(owner) if owner == owner_id => Ownership::Owned,
+        Some(owner) => Ownership::OwnedByOther(owner.to_string()),
+        None => {
+            let has_address_records = records.iter().any(|record| {
+                record.source == record_name
+                    && (record.record_type == "A" || record.record_type == "AAAA")
+            });
+            if has_address_records {
+                Ownership::Unowned
+            } else {
+                Ownership::Free
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: &str, record_type: &str, target: &str) -> DnsRecord {
+        DnsRecord {
+            id: 1,
+            source: source.to_string(),
+            target: target.to_string(),
+            ttl: 300,
+            record_type: record_type.to_string(),
+            updated_at: 1234567890,
+        }
+    }
+
+    #[test]
+    fn test_ownership_record_name() {
+        assert_eq!(ownership_record_name("."), "_infomaniak-dyndns");
+        assert_eq!(ownership_record_name("home"), "_infomaniak-dyndns.home");
+        assert_eq!(ownership_record_name("*"), "_infomaniak-dyndns.wildcard");
+        assert_eq!(
+            ownership_record_name("*.home"),
+            "_infomaniak-dyndns.wildcard.home"
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_owned() {
+        let records = vec![
+            record("*", "A", "192.168.1.1"),
+            record(
+                "_infomaniak-dyndns.wildcard",
+                "TXT",
+                "heritage=infomaniak-dyndns-wildcard,owner=home,record=*",
+            ),
+        ];
+
+        assert_eq!(
+            get_record_ownership(&records, "*", "home"),
+            Ownership::Owned
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_colliding_marker_names() {
+        let records = vec![
+            record("*.home", "A", "192.168.1.1"),
+            record(
+                "_infomaniak-dyndns.wildcard.home",
+                "TXT",
+                &ownership_marker("office", "*.home"),
+            ),
+        ];
+
+        // Both names share the marker name, not the marker
+        assert_eq!(
+            ownership_record_name("wildcard.home"),
+            ownership_record_name("*.home")
+        );
+        assert_eq!(
+            get_record_ownership(&records, "wildcard.home", "home"),
+            Ownership::Free
+        );
+        assert_eq!(
+            get_record_ownership(&records, "*.home", "home"),
+            Ownership::OwnedByOther("office".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_legacy_marker() {
+        let records = vec![record(
+            "_infomaniak-dyndns.www",
+            "TXT",
+            "heritage=infomaniak-dyndns-wildcard,owner=home",
+        )];
+
+        assert_eq!(
+            get_record_ownership(&records, "www", "home"),
+            Ownership::Owned
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_colliding_legacy_marker() {
+        let legacy_marker = record(
+            "_infomaniak-dyndns.wildcard.home",
+            "TXT",
+            "heritage=infomaniak-dyndns-wildcard,owner=home",
+        );
+        let records = vec![record("*.home", "A", "192.168.1.1"), legacy_marker];
+
+        // The marker can only be the one of the name having records
+        assert_eq!(
+            get_record_ownership(&records, "*.home", "home"),
+            Ownership::Owned
+        );
+        assert_eq!(
+            get_record_ownership(&records, "wildcard.home", "home"),
+            Ownership::Free
+        );
+
+        // With records on both names, it can't tell which one it marks
+        let mut records = records;
+        records.push(record("wildcard.home", "A", "192.168.1.2"));
+        assert_eq!(
+            get_record_ownership(&records, "*.home", "home"),
+            Ownership::Unowned
+        );
+        assert_eq!(
+            get_record_ownership(&records, "wildcard.home", "home"),
+            Ownership::Unowned
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_quoted_marker() {
+        let records = vec![record(
+            "_infomaniak-dyndns.www",
+            "TXT",
+            "\"heritage=infomaniak-dyndns-wildcard,owner=home,record=www\"",
+        )];
+
+        assert_eq!(
+            get_record_ownership(&records, "www", "home"),
+            Ownership::Owned
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_owned_by_other() {
+        let records = vec![
+            record("www", "A", "192.168.1.1"),
+            record(
+                "_infomaniak-dyndns.www",
+                "TXT",
+                "heritage=infomaniak-dyndns-wildcard,owner=office",
+            ),
+        ];
+
+        assert_eq!(
+            get_record_ownership(&records, "www", "home"),
+            Ownership::OwnedByOther("office".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_unowned() {
+        let records = vec![
+            record("www", "AAAA", "2001:db8::1"),
+            record("_infomaniak-dyndns.www", "TXT", "some other content"),
+        ];
+
+        assert_eq!(
+            get_record_ownership(&records, "www", "home"),
+            Ownership::Unowned
+        );
+    }
+
+    #[test]
+    fn test_get_record_ownership_free() {
+        let records = vec![record("other", "A", "192.168.1.1")];
+
+        assert_eq!(
+            get_record_ownership(&records, "www", "home"),
+            Ownership::Free
+        );
+    }
+
+    #[test]
+    fn test_unowned_records_policy_from_str() {
+        assert_eq!(
+            "report".parse::<UnownedRecordsPolicy>(),
+            Ok(UnownedRecordsPolicy::Report)
+        );
+        assert_eq!(
+            "adopt".parse::<UnownedRecordsPolicy>(),
+            Ok(UnownedRecordsPolicy::Adopt)
+        );
+        assert!("delete".parse::<UnownedRecordsPolicy>().is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::dns_client::{self, Nameserver, RecordType};
+use log::{debug, error, info};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const VERIFICATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings of the verification of updated records.
+#[derive(Debug, Clone)]
+pub struct VerificationSettings {
+    /// Resolver used to look up the nameservers of the zone.
+    pub resolver: SocketAddr,
+    /// Time after which nameservers still serving an old value are reported.
+    pub timeout: Duration,
+}
+
+/// Returns the fully qualified name of `record_name` in `zone`.
+pub fn record_fqdn(record_name: &str, zone: &str) -> String {
+    let zone = zone.trim_end_matches('.');
+    if record_name.is_empty() || record_name == "." {
+        zone.to_string()
+    } else {
+        format!("{}.{}", record_name, zone)
+    }
+}
+
+/// Returns the values `nameserver` currently serves for `name` and `record_type`.
+pub async fn served_values(
+    nameserver: &Nameserver,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let response =
+        dns_client::query_nameserver(nameserver, name, record_type, QUERY_TIMEOUT).await?;
+    if !response.authoritative {
+        return Err(format!(
+            "Nameserver {} is not authoritative for {}",
+            nameserver.name, name
+        )
+        .into());
+    }
+    let values: Vec<String> = response
+        .values(record_type)
+        .iter()
+        .map(|value| value.to_string())
+        .collect();
+    debug!(
+        "Nameserver {} serves {:?} for {} {:?}",
+        nameserver.name, values, name, record_type
+    );
+    Ok(values)
+}
+
+/// Waits until every nameserver serves all of `expected` and none of
+/// `previous` for `name` and `record_type`.
+///
+/// A nameserver still serving a previous value along with the new ones hasn't
+/// caught up with the removal yet. Returns the time it took, or an error
+/// listing the nameservers still lagging behind, with what they serve, once
+/// `timeout` is reached.
+pub async fn wait_for_values(
+    nameservers: &[Nameserver],
+    name: &str,
+    record_type: RecordType,
+    expected: &[String],
+    previous: &[String],
+    timeout: Duration,
+    interval: Duration,
+) -> Result<Duration, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut pending: Vec<&Nameserver> = nameservers.iter().collect();
+
+    loop {
+        let mut still_pending = Vec::new();
+        let mut lagging = Vec::new();
+        for nameserver in pending {
+            match served_values(nameserver, name, record_type).await {
+                Ok(values)
+                    if expected.iter().all(|value| values.contains(value))
+                        && !previous.iter().any(|value| values.contains(value)) =>
+                {
+                    info!(
+                        "Nameserver {} serves {:?} for {} after {:?}",
+                        nameserver.name,
+                        expected,
+                        name,
+                        start.elapsed()
+                    )
+                }
+                Ok(values) => {
+                    lagging.push(format!("{} serves {:?}", nameserver.name, values));
+                    still_pending.push(nameserver);
+                }
+                Err(e) => {
+                    lagging.push(e.to_string());
+                    still_pending.push(nameserver);
+                }
+            }
+        }
+        pending = still_pending;
+
+        if pending.is_empty() {
+            return Ok(start.elapsed());
+        }
+        if start.elapsed() + interval > timeout {
+            return Err(format!(
+                "Nameservers still don't serve {:?} for {} after {:?}: {}",
+                expected,
+                name,
+                start.elapsed(),
+                lagging.join(", ")
+            )
+            .into());
+        }
+        time::sleep(interval).await;
+    }
+}
+
+/// Checks in a background task that the `record_type` records of
+/// `record_name` are served with all of `expected` and none of `previous` by
+/// every authoritative nameserver of `zone`, logging how long it took or an
+/// error if some nameservers still serve other values after the timeout.
+pub fn spawn_verification(
+    settings: &VerificationSettings,
+    zone: &str,
+    record_name: &str,
+    record_type: &str,
+    expected: Vec<String>,
+    previous: Vec<String>,
+) {
+    let record_type = match record_type.parse::<RecordType>() {
+        Ok(record_type) => record_type,
+        Err(e) => {
+            error!("Can't verify the records of {}: {}", record_name, e);
+            return;
+        }
+    };
+    let settings = settings.clone();
+    let zone = zone.to_string();
+    let name = record_fqdn(record_name, &zone);
+
+    tokio::spawn(async move {
+        let nameservers =
+            match dns_client::lookup_nameservers(settings.resolver, &zone, QUERY_TIMEOUT).await {
+                Ok(nameservers) => nameservers,
+                Err(e) => {
+                    error!("Error looking up nameservers to verify {}: {}", name, e);
+                    return;
+                }
+            };
+        match wait_for_values(
+            &nameservers,
+            &name,
+            record_type,
+            &expected,
+            &previous,
+            settings.timeout,
+            VERIFICATION_INTERVAL,
+        )
+        .await
+        {
+            Ok(elapsed) => info!(
+                "Record {} {:?} is live on all nameservers after {:?}",
+                name, record_type, elapsed
+            ),
+            Err(e) => error!("Record {} {:?} is not live: {}", name, record_type, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_client::{RecordData, stub};
+
+    #[test]
+    fn test_record_fqdn() {
+        assert_eq!(record_fqdn("*", "example.com"), "*.example.com");
+        assert_eq!(record_fqdn(".", "example.com."), "example.com");
+        assert_eq!(record_fqdn("", "example.com"), "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_value_success() {
+        let server = stub::start(vec![(
+            "_acme-challenge.example.com".to_string(),
+            RecordData::Txt("token".to_string()),
+        )]);
+        let nameservers = vec![Nameserver {
+            name: "ns1.example.com".to_string(),
+            addresses: vec![server],
+        }];
+
+        let result = wait_for_values(
+            &nameservers,
+            "_acme-challenge.example.com",
+            RecordType::Txt,
+            &["token".to_string()],
+            &[],
+            Duration::from_secs(1),
+            Duration::from_millis(100),
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_value_timeout_reports_old_value() {
+        let up_to_date_server = stub::start(vec![(
+            "www.example.com".to_string(),
+            RecordData::A("192.168.1.2".parse().unwrap()),
+        )]);
+        let lagging_server = stub::start(vec![(
+            "www.example.com".to_string(),
+            RecordData::A("192.168.1.1".parse().unwrap()),
+        )]);
+        let nameservers = vec![
+            Nameserver {
+                name: "ns1.example.com".to_string(),
+                addresses: vec![up_to_date_server],
+            },
+            Nameserver {
+                name: "ns2.example.com".to_string(),
+                addresses: vec![lagging_server],
+            },
+        ];
+
+        let result = wait_for_values(
+            &nameservers,
+            "www.example.com",
+            RecordType::A,
+            &["192.168.1.2".to_string()],
+            &["192.168.1.1".to_string()],
+            Duration::from_millis(300),
+            Duration::from_millis(100),
+        )
+        .await;
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("ns2.example.com serves [\"192.168.1.1\"]"));
+        assert!(!error.contains("ns1.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_values_reports_previous_value_still_served() {
+        let server = stub::start(vec![
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.1".parse().unwrap()),
+            ),
+            (
+                "www.example.com".to_string(),
+                RecordData::A("192.168.1.2".parse().unwrap()),
+            ),
+        ]);
+        let nameservers = vec![Nameserver {
+            name: "ns1.example.com".to_string(),
+            addresses: vec![server],
+        }];
+
+        let result = wait_for_values(
+            &nameservers,
+            "www.example.com",
+            RecordType::A,
+            &["192.168.1.2".to_string()],
+            &["192.168.1.1".to_string()],
+            Duration::from_millis(300),
+            Duration::from_millis(100),
+        )
+        .await;
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("ns1.example.com serves [\"192.168.1.1\", \"192.168.1.2\"]"));
+    }
+}
@@ -1,25 +1,791 @@
-use reqwest::blocking::Client;
+use crate::address_family::AddressFamily;
+use crate::bogon::AddressFilter;
+use crate::secrets;
+use config::Config;
+use reqwest::{Client, Url};
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+mod dns;
+mod fritzbox;
+mod local;
+mod nat_pmp;
+mod openwrt;
+mod stun;
+mod upnp;
+
+pub const IPIFY_IPV4_URL: &str = "https://api.ipify.org/";
+pub const IPIFY_IPV6_URL: &str = "https://api64.ipify.org/";
+const DETECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Local side of the connections used to detect a public IP.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DetectionBinding {
+    /// Source address of the connections, default to the unspecified address
+    /// of the family.
+    pub source_address: Option<IpAddr>,
+    /// Network interface the connections go through.
+    pub interface: Option<String>,
+}
+
+impl DetectionBinding {
+    /// Reads `<prefix>_source_address` and `<prefix>_interface` from `config`.
+    pub fn from_config(config: &Config, prefix: &str) -> Result<DetectionBinding, Box<dyn Error>> {
+        let source_address = match config.get_string(&format!("{}_source_address", prefix)) {
+            Ok(source_address) => Some(source_address.parse::<IpAddr>().map_err(|e| {
+                format!(
+                    "Invalid {}_source_address {:?}: {}",
+                    prefix, source_address, e
+                )
+            })?),
+            Err(_) => None,
+        };
+        Ok(DetectionBinding {
+            source_address,
+            interface: config.get_string(&format!("{}_interface", prefix)).ok(),
+        })
+    }
+
+    /// Returns the source address of the connections over `family`, default
+    /// to the unspecified address of the family.
+    fn local_address(&self, family: AddressFamily) -> Result<IpAddr, Box<dyn Error>> {
+        match (family, self.source_address) {
+            (AddressFamily::Ipv4, None) => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            (AddressFamily::Ipv6, None) => Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            (AddressFamily::Ipv4, Some(address @ IpAddr::V4(_)))
+            | (AddressFamily::Ipv6, Some(address @ IpAddr::V6(_))) => Ok(address),
+            (_, Some(address)) => Err(format!(
+                "Source address {} can't be used to detect the public {}",
+                address, family
+            )
+            .into()),
+        }
+    }
+
+    /// Checks the sockets opened by the sources can be bound to the
+    /// interface, which is only supported on Linux.
+    fn check_socket_interface(&self) -> Result<(), Box<dyn Error>> {
+        match &self.interface {
+            Some(interface) if !cfg!(any(target_os = "android", target_os = "linux")) => {
+                Err(format!(
+                    "Can't bind to interface {}, not supported on this platform",
+                    interface
+                )
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Binds a UDP socket of `family` to the source address and interface.
+    pub async fn bind_udp(&self, family: AddressFamily) -> Result<UdpSocket, Box<dyn Error>> {
+        let local_address = self.local_address(family)?;
+        let socket = UdpSocket::bind((local_address, 0)).await?;
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        Ok(socket)
+    }
+
+    /// Connects to `server` over TCP from the source address and interface.
+    pub async fn connect_tcp(&self, server: SocketAddr) -> Result<TcpStream, Box<dyn Error>> {
+        let socket = match server {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(
+            self.local_address(AddressFamily::of(&server.ip()))?,
+            0,
+        ))?;
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        Ok(socket.connect(server).await?)
+    }
+}
+
+/// Builds the client used to detect the public IP of `family`.
+///
+/// Its sockets are bound to an address of `family`, so requests can't go out
+/// over the other family even when the detection service has both A and AAAA
+/// records.
+pub fn create_detection_client(
+    family: AddressFamily,
+    binding: &DetectionBinding,
+) -> Result<Client, Box<dyn Error>> {
+    let builder = Client::builder()
+        .local_address(binding.local_address(family)?)
+        .timeout(DETECTION_TIMEOUT);
+    let builder = match &binding.interface {
+        Some(interface) => bind_interface(builder, interface)?,
+        None => builder,
+    };
+    Ok(builder.build()?)
+}
+
+/// Builds the client used to ask a router for the public IP, bound to the
+/// source address and interface of `binding` when set.
+///
+/// Unlike detection clients, it isn't bound to an address of the family
+/// detected, since the router may only be reachable over the other one.
+pub fn create_router_client(binding: &DetectionBinding) -> Result<Client, Box<dyn Error>> {
+    let builder = Client::builder()
+        .local_address(binding.source_address)
+        .timeout(DETECTION_TIMEOUT);
+    let builder = match &binding.interface {
+        Some(interface) => bind_interface(builder, interface)?,
+        None => builder,
+    };
+    Ok(builder.build()?)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "macos"))]
+fn bind_interface(
+    builder: reqwest::ClientBuilder,
+    interface: &str,
+) -> Result<reqwest::ClientBuilder, Box<dyn Error>> {
+    Ok(builder.interface(interface))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux", target_os = "macos")))]
+fn bind_interface(
+    _builder: reqwest::ClientBuilder,
+    interface: &str,
+) -> Result<reqwest::ClientBuilder, Box<dyn Error>> {
+    Err(format!(
+        "Can't bind to interface {}, not supported on this platform",
+        interface
+    )
+    .into())
+}
+
+/// Returns the prefix of the settings of `family`.
+fn family_prefix(family: AddressFamily) -> &'static str {
+    match family {
+        AddressFamily::Ipv4 => "ipv4",
+        AddressFamily::Ipv6 => "ipv6",
+    }
+}
+
+/// Parses `address`, with or without a port, using `default_port` when
+/// there is none.
+fn parse_socket_address(address: &str, default_port: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    let ip = address
+        .parse::<IpAddr>()
+        .map_err(|e| format!("Invalid address {:?}: {}", address, e))?;
+    Ok(SocketAddr::new(ip, default_port))
+}
+
+/// Reads `key` from `config`, either a list or a string of values separated
+/// by `,`.
+fn config_list(config: &Config, key: &str) -> Option<Vec<String>> {
+    match config.get::<Vec<String>>(key) {
+        Ok(values) => Some(values),
+        Err(_) => config.get_string(key).ok().map(|values| {
+            values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect()
+        }),
+    }
+}
+
+/// Credentials of a router user allowed to read the WAN status.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Where the public IP of a family is detected.
+#[derive(Debug)]
+pub enum IpSource {
+    /// Web service answering the address in plain text, like ipify.
+    Http { client: Client, url: String },
+    /// Internet gateway device, at a known description URL or found via SSDP.
+    Upnp {
+        client: Client,
+        location: Option<Url>,
+        binding: DetectionBinding,
+    },
+    /// Gateway answering NAT-PMP requests.
+    NatPmp {
+        gateway: SocketAddr,
+        binding: DetectionBinding,
+    },
+    /// PCP server.
+    Pcp {
+        server: SocketAddr,
+        binding: DetectionBinding,
+    },
+    /// STUN servers, asked in turn until one answers.
+    Stun {
+        servers: Vec<String>,
+        binding: DetectionBinding,
+    },
+    /// Resolver answering a well-known name with the address of the client.
+    Dns(dns::DnsLookup),
+    /// Shell command printing the address, killed after `timeout`.
+    Command { command: String, timeout: Duration },
+    /// File containing the address, read at every detection.
+    File(PathBuf),
+    /// FritzBox answering TR-064 requests.
+    FritzBox {
+        client: Client,
+        url: Url,
+        credentials: Option<Credentials>,
+    },
+    /// OpenWrt router answering ubus JSON-RPC requests, asked for the address
+    /// of `interface`.
+    OpenWrt {
+        client: Client,
+        url: Url,
+        credentials: Option<Credentials>,
+        sessions: openwrt::SessionCache,
+        interface: String,
+    },
+}
+
+impl IpSource {
+    /// Builds the source of `family` from `<family>_source`, default to the
+    /// web service at `<family>_url` or `default_url`.
+    pub fn from_config(
+        config: &Config,
+        family: AddressFamily,
+        default_url: &str,
+    ) -> Result<IpSource, Box<dyn Error>> {
+        let binding = DetectionBinding::from_config(config, family_prefix(family))?;
+        IpSource::with_binding(config, family, default_url, &binding)
+    }
+
+    /// Builds the source of `family` of an uplink, whose connections go
+    /// through `binding`. Sources answering the same address whatever the
+    /// uplink, like routers or commands, are refused.
+    pub fn for_uplink(
+        config: &Config,
+        family: AddressFamily,
+        default_url: &str,
+        binding: &DetectionBinding,
+    ) -> Result<IpSource, Box<dyn Error>> {
+        match IpSource::with_binding(config, family, default_url, binding)? {
+            source @ (IpSource::Http { .. } | IpSource::Stun { .. } | IpSource::Dns(_)) => {
+                Ok(source)
+            }
+            _ => Err(format!(
+                "The {}_source can't detect the public IPs of each WAN, only \"http\", \"stun\" and \"dns\" can",
+                family_prefix(family)
+            )
+            .into()),
+        }
+    }
+
+    fn with_binding(
+        config: &Config,
+        family: AddressFamily,
+        default_url: &str,
+        binding: &DetectionBinding,
+    ) -> Result<IpSource, Box<dyn Error>> {
+        let prefix = family_prefix(family);
+        let source = config
+            .get_string(&format!("{}_source", prefix))
+            .unwrap_or_else(|_| "http".to_string());
+        let gateway = |key: &str, default_port: u16| match config.get_string(key) {
+            Ok(gateway) => parse_socket_address(&gateway, default_port),
+            Err(_) => Ok(SocketAddr::new(
+                IpAddr::V4(nat_pmp::default_gateway()?),
+                default_port,
+            )),
+        };
+        // Credentials of routers, `<router>_username` default to `root` on OpenWrt
+        let credentials = |router: &str| -> Result<Option<Credentials>, Box<dyn Error>> {
+            let Some(password) = secrets::get_secret(config, &format!("{}_password", router))?
+            else {
+                return Ok(None);
+            };
+            let username = config
+                .get_string(&format!("{}_username", router))
+                .unwrap_or_else(|_| match router {
+                    "openwrt" => "root".to_string(),
+                    _ => String::new(),
+                });
+            Ok(Some(Credentials { username, password }))
+        };
+        match (source.as_str(), family) {
+            ("http", _) => Ok(IpSource::Http {
+                client: create_detection_client(family, binding)?,
+                url: config
+                    .get_string(&format!("{}_url", prefix))
+                    .unwrap_or_else(|_| default_url.to_string()),
+            }),
+            ("upnp", AddressFamily::Ipv4) => {
+                binding.check_socket_interface()?;
+                Ok(IpSource::Upnp {
+                    client: create_router_client(binding)?,
+                    location: match config.get_string("upnp_location") {
+                        Ok(location) => Some(Url::parse(&location)?),
+                        Err(_) => None,
+                    },
+                    binding: binding.clone(),
+                })
+            }
+            ("natpmp", AddressFamily::Ipv4) => {
+                binding.check_socket_interface()?;
+                Ok(IpSource::NatPmp {
+                    gateway: gateway("nat_pmp_gateway", nat_pmp::NAT_PMP_PORT)?,
+                    binding: binding.clone(),
+                })
+            }
+            ("pcp", AddressFamily::Ipv4) => {
+                binding.check_socket_interface()?;
+                Ok(IpSource::Pcp {
+                    server: gateway("pcp_server", nat_pmp::NAT_PMP_PORT)?,
+                    binding: binding.clone(),
+                })
+            }
+            // The default gateway found is the IPv4 one
+            ("pcp", AddressFamily::Ipv6) => {
+                let server = parse_socket_address(
+                    &config
+                        .get_string("pcp_server")
+                        .map_err(|_| "pcp_server must be set to detect the public IPv6 with PCP")?,
+                    nat_pmp::NAT_PMP_PORT,
+                )?;
+                if !server.is_ipv6() {
+                    return Err(format!(
+                        "pcp_server must be an IPv6 address to detect the public IPv6 with PCP, got {}",
+                        server
+                    )
+                    .into());
+                }
+                binding.check_socket_interface()?;
+                Ok(IpSource::Pcp {
+                    server,
+                    binding: binding.clone(),
+                })
+            }
+            ("stun", _) => {
+                binding.check_socket_interface()?;
+                Ok(IpSource::Stun {
+                    servers: config_list(config, "stun_servers").unwrap_or_else(|| {
+                        stun::DEFAULT_STUN_SERVERS
+                            .iter()
+                            .map(|server| server.to_string())
+                            .collect()
+                    }),
+                    binding: binding.clone(),
+                })
+            }
+            ("dns", _) => {
+                binding.check_socket_interface()?;
+                let provider = config
+                    .get_string("dns_provider")
+                    .unwrap_or_else(|_| "opendns".to_string());
+                let lookup = match provider.as_str() {
+                    "opendns" => dns::DnsLookup::opendns(),
+                    "google" => dns::DnsLookup::google(),
+                    _ => {
+                        return Err(format!(
+                            "Invalid dns_provider {:?}, expected \"opendns\" or \"google\"",
+                            provider
+                        )
+                        .into());
+                    }
+                };
+                Ok(IpSource::Dns(dns::DnsLookup {
+                    binding: binding.clone(),
+                    ..lookup
+                }))
+            }
+            ("command", _) => Ok(IpSource::Command {
+                command: config.get_string(&format!("{}_command", prefix))?,
+                timeout: Duration::from_secs(
+                    config
+                        .get::<u64>(&format!("{}_command_timeout_in_seconds", prefix))
+                        .unwrap_or(local::DEFAULT_COMMAND_TIMEOUT_IN_SECONDS),
+                ),
+            }),
+            ("file", _) => Ok(IpSource::File(PathBuf::from(
+                config.get_string(&format!("{}_file", prefix))?,
+            ))),
+            ("fritzbox", _) => Ok(IpSource::FritzBox {
+                client: create_router_client(binding)?,
+                url: Url::parse(
+                    &config
+                        .get_string("fritzbox_url")
+                        .unwrap_or_else(|_| fritzbox::DEFAULT_FRITZBOX_URL.to_string()),
+                )?,
+                credentials: credentials("fritzbox")?,
+            }),
+            ("openwrt", _) => Ok(IpSource::OpenWrt {
+                client: create_router_client(binding)?,
+                url: Url::parse(
+                    &config
+                        .get_string("openwrt_url")
+                        .unwrap_or_else(|_| openwrt::DEFAULT_OPENWRT_URL.to_string()),
+                )?,
+                credentials: credentials("openwrt")?,
+                sessions: openwrt::SessionCache::default(),
+                interface: config
+                    .get_string(&format!("{}_openwrt_interface", prefix))
+                    .unwrap_or_else(|_| match family {
+                        AddressFamily::Ipv4 => "wan".to_string(),
+                        AddressFamily::Ipv6 => "wan6".to_string(),
+                    }),
+            }),
+            ("upnp" | "natpmp", AddressFamily::Ipv6) => Err(format!(
+                "The {} source only reports the public IPv4 of the gateway",
+                source
+            )
+            .into()),
+            _ => Err(format!(
+                "Invalid {}_source {:?}, expected \"http\", \"upnp\", \"natpmp\", \"pcp\", \"stun\", \"dns\", \"command\", \"file\", \"fritzbox\" or \"openwrt\"",
+                prefix, source
+            )
+            .into()),
+        }
+    }
+
+    async fn detect(&self, family: AddressFamily) -> Result<IpAddr, Box<dyn Error>> {
+        let address = match self {
+            IpSource::Http { client, url } => match family {
+                AddressFamily::Ipv4 => IpAddr::V4(get_public_ipv4_with_url(client, url).await?),
+                AddressFamily::Ipv6 => IpAddr::V6(get_public_ipv6_with_url(client, url).await?),
+            },
+            IpSource::Upnp {
+                client,
+                location,
+                binding,
+            } => {
+                upnp::get_external_ip(
+                    client,
+                    location.as_ref(),
+                    upnp::SSDP_MULTICAST_ADDRESS,
+                    binding,
+                )
+                .await?
+            }
+            IpSource::NatPmp { gateway, binding } => {
+                IpAddr::V4(nat_pmp::get_public_address(*gateway, binding).await?)
+            }
+            IpSource::Pcp { server, binding } => {
+                nat_pmp::get_external_address(*server, binding).await?
+            }
+            IpSource::Stun { servers, binding } => {
+                stun::get_public_ip(servers, family, binding).await?
+            }
+            IpSource::Dns(lookup) => lookup.public_ip(family).await?,
+            IpSource::Command { command, timeout } => local::run_command(command, *timeout).await?,
+            IpSource::File(path) => local::read_file(path).await?,
+            IpSource::FritzBox {
+                client,
+                url,
+                credentials,
+            } => fritzbox::get_external_ip(client, url, credentials.as_ref(), family).await?,
+            IpSource::OpenWrt {
+                client,
+                url,
+                credentials,
+                sessions,
+                interface,
+            } => {
+                openwrt::get_interface_address(
+                    client,
+                    url,
+                    credentials.as_ref(),
+                    sessions,
+                    interface,
+                    family,
+                )
+                .await?
+            }
+        };
+        if AddressFamily::of(&address) != family {
+            return Err(format!("Detected {}, which is not an {} address", address, family).into());
+        }
+        Ok(address)
+    }
+
+    /// Detects the public IPv4, refusing the addresses `filter` rejects.
+    pub async fn detect_ipv4(&self, filter: &AddressFilter) -> Result<Ipv4Addr, Box<dyn Error>> {
+        let address = self.detect(AddressFamily::Ipv4).await?;
+        filter.check(address)?;
+        match address {
+            IpAddr::V4(address) => Ok(address),
+            IpAddr::V6(_) => unreachable!("detect only returns addresses of the family"),
+        }
+    }
+
+    /// Detects the public IPv6, refusing the addresses `filter` rejects.
+    pub async fn detect_ipv6(&self, filter: &AddressFilter) -> Result<Ipv6Addr, Box<dyn Error>> {
+        let address = self.detect(AddressFamily::Ipv6).await?;
+        filter.check(address)?;
+        match address {
+            IpAddr::V6(address) => Ok(address),
+            IpAddr::V4(_) => unreachable!("detect only returns addresses of the family"),
+        }
+    }
+}
 
 /// Function that get public IPv4 address from a given URL.
-pub fn get_public_ipv4_with_url(client: &Client, url: &str) -> Result<Ipv4Addr, Box<dyn Error>> {
-    Ok(client.get(url).send()?.text()?.trim().parse::<Ipv4Addr>()?)
+pub async fn get_public_ipv4_with_url(
+    client: &Client,
+    url: &str,
+) -> Result<Ipv4Addr, Box<dyn Error>> {
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .text()
+        .await?
+        .trim()
+        .parse::<Ipv4Addr>()?)
 }
 
 /// Function that get public IPv6 address from a given URL.
-pub fn get_public_ipv6_with_url(client: &Client, url: &str) -> Result<Ipv6Addr, Box<dyn Error>> {
-    Ok(client.get(url).send()?.text()?.trim().parse::<Ipv6Addr>()?)
+pub async fn get_public_ipv6_with_url(
+    client: &Client,
+    url: &str,
+) -> Result<Ipv6Addr, Box<dyn Error>> {
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .text()
+        .await?
+        .trim()
+        .parse::<Ipv6Addr>()?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use reqwest::blocking::Client;
+
+    #[tokio::test]
+    async fn test_detection_client_is_bound_to_family() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/")
+            .with_status(200)
+            .with_body("192.168.1.1")
+            .create_async()
+            .await;
+        // The mock server only listens on 127.0.0.1.
+        let url = server.url();
+
+        let ipv4_client =
+            create_detection_client(AddressFamily::Ipv4, &DetectionBinding::default()).unwrap();
+        let ipv6_client =
+            create_detection_client(AddressFamily::Ipv6, &DetectionBinding::default()).unwrap();
+
+        assert!(get_public_ipv4_with_url(&ipv4_client, &url).await.is_ok());
+        assert!(ipv6_client.get(&url).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router_client_reaches_router_over_any_family() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        // Routers asked for the public IPv6 may only listen on IPv4
+        let client = create_router_client(&DetectionBinding::default()).unwrap();
+
+        assert!(client.get(server.url()).send().await.is_ok());
+    }
 
     #[test]
-    fn test_get_public_ipv4_success() {
-        let mut server = mockito::Server::new();
+    fn test_detection_client_rejects_source_address_of_other_family() {
+        let binding = DetectionBinding {
+            source_address: Some("2001:db8::1".parse().unwrap()),
+            interface: None,
+        };
+
+        assert!(create_detection_client(AddressFamily::Ipv4, &binding).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_detection_binding_binds_sockets() {
+        let binding = DetectionBinding {
+            source_address: Some("127.0.0.1".parse().unwrap()),
+            interface: None,
+        };
+
+        let socket = binding.bind_udp(AddressFamily::Ipv4).await.unwrap();
+
+        assert_eq!(
+            socket.local_addr().unwrap().ip(),
+            "127.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(binding.bind_udp(AddressFamily::Ipv6).await.is_err());
+    }
+
+    #[test]
+    fn test_ip_source_from_config() {
+        let config = Config::builder()
+            .set_override("ipv4_source", "natpmp")
+            .unwrap()
+            .set_override("nat_pmp_gateway", "192.168.1.1")
+            .unwrap()
+            .set_override("ipv6_source", "upnp")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv4, "http://localhost"),
+            Ok(IpSource::NatPmp { gateway, .. }) if gateway == "192.168.1.1:5351".parse().unwrap()
+        ));
+        assert!(IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost").is_err());
+
+        // The IPv6 PCP server can't be the IPv4 default gateway
+        let config = Config::builder()
+            .set_override("ipv6_source", "pcp")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost").is_err());
+        let config = Config::builder()
+            .set_override("ipv6_source", "pcp")
+            .unwrap()
+            .set_override("pcp_server", "192.168.1.1")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost").is_err());
+        let config = Config::builder()
+            .set_override("ipv6_source", "pcp")
+            .unwrap()
+            .set_override("pcp_server", "2001:db8::1")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost"),
+            Ok(IpSource::Pcp { server, .. }) if server == "[2001:db8::1]:5351".parse().unwrap()
+        ));
+
+        let config = Config::builder()
+            .set_override("ipv6_source", "stun")
+            .unwrap()
+            .set_override("stun_servers", "stun1.example.com:3478, stun2.example.com")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost"),
+            Ok(IpSource::Stun { servers, .. }) if servers == ["stun1.example.com:3478", "stun2.example.com"]
+        ));
+
+        let config = Config::builder()
+            .set_override("ipv4_source", "dns")
+            .unwrap()
+            .set_override("dns_provider", "google")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv4, "http://localhost"),
+            Ok(IpSource::Dns(lookup)) if lookup.txt
+        ));
+
+        let config = Config::builder()
+            .set_override("ipv4_source", "command")
+            .unwrap()
+            .set_override("ipv4_command", "cat /run/wan-ip")
+            .unwrap()
+            .set_override("ipv6_source", "file")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv4, "http://localhost"),
+            Ok(IpSource::Command { command, timeout })
+                if command == "cat /run/wan-ip" && timeout == Duration::from_secs(10)
+        ));
+        // The file source needs a path
+        assert!(IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost").is_err());
+
+        let config = Config::builder()
+            .set_override("ipv6_source", "openwrt")
+            .unwrap()
+            .set_override("openwrt_password", "secret")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            IpSource::from_config(&config, AddressFamily::Ipv6, "http://localhost"),
+            Ok(IpSource::OpenWrt { credentials: Some(credentials), interface, .. })
+                if credentials.username == "root" && interface == "wan6"
+        ));
+    }
+
+    #[test]
+    fn test_detection_binding_from_config() {
+        let config = Config::builder()
+            .set_override("wan_source_address", "192.0.2.1")
+            .unwrap()
+            .set_override("wan_interface", "eth1")
+            .unwrap()
+            .set_override("bad_source_address", "eth1")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            DetectionBinding::from_config(&config, "wan").unwrap(),
+            DetectionBinding {
+                source_address: Some("192.0.2.1".parse().unwrap()),
+                interface: Some("eth1".to_string()),
+            }
+        );
+        assert_eq!(
+            DetectionBinding::from_config(&config, "ipv4").unwrap(),
+            DetectionBinding::default()
+        );
+        assert!(DetectionBinding::from_config(&config, "bad").is_err());
+    }
+
+    #[test]
+    fn test_credentials_debug_hides_password() {
+        let credentials = Credentials {
+            username: "root".to_string(),
+            password: "r0ut3r-p4ss".to_string(),
+        };
+
+        assert_eq!(
+            format!("{:?}", credentials),
+            "Credentials { username: \"root\", password: \"[REDACTED]\" }"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_public_ipv4_success() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response
         let _m = server
@@ -27,18 +793,19 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("192.168.1.1")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv4_with_url(&client, &server.url());
+        let result = get_public_ipv4_with_url(&client, &server.url()).await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "192.168.1.1".parse::<Ipv4Addr>().unwrap());
     }
 
-    #[test]
-    fn test_get_public_ipv4_invalid_ip() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv4_invalid_ip() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response with invalid IP
         let _m = server
@@ -46,17 +813,18 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("invalid_ip")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv4_with_url(&client, &server.url());
+        let result = get_public_ipv4_with_url(&client, &server.url()).await;
 
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_get_public_ipv4_with_whitespace() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv4_with_whitespace() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response with whitespace
         let _m = server
@@ -64,41 +832,47 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("  10.0.0.1  \n")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv4_with_url(&client, &server.url());
+        let result = get_public_ipv4_with_url(&client, &server.url()).await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "10.0.0.1".parse::<Ipv4Addr>().unwrap());
     }
 
-    #[test]
-    fn test_get_public_ipv4_server_error() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv4_server_error() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock a server error
-        let _m = server.mock("GET", "/").with_status(500).create();
+        let _m = server
+            .mock("GET", "/")
+            .with_status(500)
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv4_with_url(&client, &server.url());
+        let result = get_public_ipv4_with_url(&client, &server.url()).await;
 
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_get_public_ipv4_network_error() {
+    #[tokio::test]
+    async fn test_get_public_ipv4_network_error() {
         let client = Client::new();
         // Test with invalid URL to simulate network error
         let result =
-            get_public_ipv4_with_url(&client, "http://invalid-url-that-does-not-exist.invalid");
+            get_public_ipv4_with_url(&client, "http://invalid-url-that-does-not-exist.invalid")
+                .await;
 
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_get_public_ipv6_success() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv6_success() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response
         let _m = server
@@ -106,18 +880,19 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("2001:db8::1")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv6_with_url(&client, &server.url());
+        let result = get_public_ipv6_with_url(&client, &server.url()).await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "2001:db8::1".parse::<Ipv6Addr>().unwrap());
     }
 
-    #[test]
-    fn test_get_public_ipv6_invalid_ip() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv6_invalid_ip() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response with invalid IP
         let _m = server
@@ -125,17 +900,18 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("invalid_ip")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv6_with_url(&client, &server.url());
+        let result = get_public_ipv6_with_url(&client, &server.url()).await;
 
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_get_public_ipv6_with_whitespace() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv6_with_whitespace() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock the API response with whitespace
         let _m = server
@@ -143,34 +919,40 @@ mod tests {
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body("  2001:db8::2  \n")
-            .create();
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv6_with_url(&client, &server.url());
+        let result = get_public_ipv6_with_url(&client, &server.url()).await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "2001:db8::2".parse::<Ipv6Addr>().unwrap());
     }
 
-    #[test]
-    fn test_get_public_ipv6_server_error() {
-        let mut server = mockito::Server::new();
+    #[tokio::test]
+    async fn test_get_public_ipv6_server_error() {
+        let mut server = mockito::Server::new_async().await;
 
         // Mock a server error
-        let _m = server.mock("GET", "/").with_status(500).create();
+        let _m = server
+            .mock("GET", "/")
+            .with_status(500)
+            .create_async()
+            .await;
 
         let client = Client::new();
-        let result = get_public_ipv6_with_url(&client, &server.url());
+        let result = get_public_ipv6_with_url(&client, &server.url()).await;
 
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_get_public_ipv6_network_error() {
+    #[tokio::test]
+    async fn test_get_public_ipv6_network_error() {
         let client = Client::new();
         // Test with invalid URL to simulate network error
         let result =
-            get_public_ipv6_with_url(&client, "http://invalid-url-that-does-not-exist.invalid");
+            get_public_ipv6_with_url(&client, "http://invalid-url-that-does-not-exist.invalid")
+                .await;
 
         assert!(result.is_err());
     }
@@ -0,0 +1,148 @@
+use super::DetectionBinding;
+use crate::address_family::AddressFamily;
+use crate::dns_client::{self, RCODE_NO_ERROR, RecordData, RecordType};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name a resolver answers with the address the query came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsLookup {
+    /// Resolver asked directly, with its port.
+    pub resolver: String,
+    pub name: String,
+    /// Whether the address is answered in a TXT record rather than an A or
+    /// AAAA record.
+    pub txt: bool,
+    /// Source address and interface the queries are sent from.
+    pub binding: DetectionBinding,
+}
+
+impl DnsLookup {
+    pub fn opendns() -> DnsLookup {
+        DnsLookup {
+            resolver: "resolver1.opendns.com:53".to_string(),
+            name: "myip.opendns.com".to_string(),
+            txt: false,
+            binding: DetectionBinding::default(),
+        }
+    }
+
+    pub fn google() -> DnsLookup {
+        DnsLookup {
+            resolver: "ns1.google.com:53".to_string(),
+            name: "o-o.myaddr.l.google.com".to_string(),
+            txt: true,
+            binding: DetectionBinding::default(),
+        }
+    }
+
+    /// Asks the resolver, over `family`, for the address the query came from.
+    pub async fn public_ip(&self, family: AddressFamily) -> Result<IpAddr, Box<dyn Error>> {
+        // The query goes out over the family of the resolver address
+        let resolver = net::lookup_host(self.resolver.as_str())
+            .await?
+            .find(|address| AddressFamily::of(&address.ip()) == family)
+            .ok_or_else(|| format!("Resolver {} has no {} address", self.resolver, family))?;
+        let record_type = match (self.txt, family) {
+            (true, _) => RecordType::Txt,
+            (false, AddressFamily::Ipv4) => RecordType::A,
+            (false, AddressFamily::Ipv6) => RecordType::Aaaa,
+        };
+        let response = dns_client::query_from(
+            resolver,
+            &self.name,
+            record_type,
+            QUERY_TIMEOUT,
+            &self.binding,
+        )
+        .await?;
+        if response.response_code != RCODE_NO_ERROR {
+            return Err(format!(
+                "Error looking up {} at {}: response code {}",
+                self.name, self.resolver, response.response_code
+            )
+            .into());
+        }
+        // Google also answers the EDNS client subnet in another TXT record
+        response
+            .values(record_type)
+            .iter()
+            .find_map(|data| match data {
+                RecordData::A(ip) => Some(IpAddr::V4(*ip)),
+                RecordData::Aaaa(ip) => Some(IpAddr::V6(*ip)),
+                RecordData::Txt(text) => text
+                    .parse::<IpAddr>()
+                    .ok()
+                    .filter(|ip| AddressFamily::of(ip) == family),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{} at {} didn't answer an {} address",
+                    self.name, self.resolver, family
+                )
+                .into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_client::stub;
+
+    #[tokio::test]
+    async fn test_public_ip_from_a_record() {
+        let resolver = stub::start(vec![(
+            "myip.opendns.com".to_string(),
+            RecordData::A("198.41.0.4".parse().unwrap()),
+        )]);
+        let lookup = DnsLookup {
+            resolver: resolver.to_string(),
+            ..DnsLookup::opendns()
+        };
+
+        let result = lookup.public_ip(AddressFamily::Ipv4).await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_public_ip_from_txt_record() {
+        let resolver = stub::start(vec![
+            (
+                "o-o.myaddr.l.google.com".to_string(),
+                RecordData::Txt("edns0-client-subnet 198.41.0.0/24".to_string()),
+            ),
+            (
+                "o-o.myaddr.l.google.com".to_string(),
+                RecordData::Txt("198.41.0.4".to_string()),
+            ),
+        ]);
+        let lookup = DnsLookup {
+            resolver: resolver.to_string(),
+            ..DnsLookup::google()
+        };
+
+        let result = lookup.public_ip(AddressFamily::Ipv4).await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_public_ip_without_answer() {
+        let resolver = stub::start(Vec::new());
+        let lookup = DnsLookup {
+            resolver: resolver.to_string(),
+            ..DnsLookup::opendns()
+        };
+
+        assert!(lookup.public_ip(AddressFamily::Ipv4).await.is_err());
+        // The stub resolver has no IPv6 address
+        assert!(lookup.public_ip(AddressFamily::Ipv6).await.is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use super::Credentials;
+use super::upnp::{HTTP_TIMEOUT, soap_call, xml_element};
+use crate::address_family::AddressFamily;
+use crate::random;
+use md5::{Digest, Md5};
+use reqwest::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Client, StatusCode, Url};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+
+pub const DEFAULT_FRITZBOX_URL: &str = "http://fritz.box:49000";
+const CONTROL_PATH: &str = "/upnp/control/wanipconnection1";
+const WAN_IP_CONNECTION: &str = "urn:dslforum-org:service:WANIPConnection:1";
+
+fn md5_hex(data: &str) -> String {
+    Md5::digest(data.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parses the parameters of a `Digest` challenge.
+fn parse_challenge(header: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let parameters = header
+        .trim()
+        .strip_prefix("Digest ")
+        .ok_or_else(|| format!("Unsupported authentication challenge {:?}", header))?;
+    Ok(parameters
+        .split(',')
+        .filter_map(|parameter| {
+            let (name, value) = parameter.split_once('=')?;
+            Some((
+                name.trim().to_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect())
+}
+
+/// Builds the `Authorization` header answering the digest `challenge`
+/// (RFC 2617) for a request of `method` to `uri`.
+fn digest_authorization(
+    challenge: &HashMap<String, String>,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> Result<String, Box<dyn Error>> {
+    let realm = challenge
+        .get("realm")
+        .ok_or("Digest challenge has no realm")?;
+    let nonce = challenge
+        .get("nonce")
+        .ok_or("Digest challenge has no nonce")?;
+    let ha1 = md5_hex(&format!(
+        "{}:{}:{}",
+        credentials.username, realm, credentials.password
+    ));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    let header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=MD5",
+        credentials.username, realm, nonce, uri
+    );
+    let with_qop = challenge
+        .get("qop")
+        .is_some_and(|qop| qop.split(',').any(|qop| qop.trim() == "auth"));
+    if with_qop {
+        let nc = "00000001";
+        let response = md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        Ok(format!(
+            "{}, qop=auth, nc={}, cnonce=\"{}\", response=\"{}\"",
+            header, nc, cnonce, response
+        ))
+    } else {
+        let response = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+        Ok(format!("{}, response=\"{}\"", header, response))
+    }
+}
+
+/// Asks the FritzBox at `url` for its external address of `family` with the
+/// TR-064 `WANIPConnection` service.
+pub async fn get_external_ip(
+    client: &Client,
+    url: &Url,
+    credentials: Option<&Credentials>,
+    family: AddressFamily,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let control_url = url.join(CONTROL_PATH)?;
+    let (action, element) = match family {
+        AddressFamily::Ipv4 => ("GetExternalIPAddress", "NewExternalIPAddress"),
+        AddressFamily::Ipv6 => ("X_AVM_DE_GetExternalIPv6Address", "NewExternalIPv6Address"),
+    };
+
+    let mut request = client.post(control_url.clone());
+    if let Some(credentials) = credentials {
+        // The FritzBox answers the digest challenge to an empty request
+        let response = client
+            .post(control_url.clone())
+            .timeout(HTTP_TIMEOUT)
+            .send()
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .ok_or("The FritzBox didn't send an authentication challenge")?
+                .to_str()?;
+            let cnonce = format!("{:016x}", random::random_u64());
+            request = request.header(
+                AUTHORIZATION,
+                digest_authorization(
+                    &parse_challenge(challenge)?,
+                    credentials,
+                    "POST",
+                    control_url.path(),
+                    &cnonce,
+                )?,
+            );
+        }
+    }
+    let response = soap_call(request, WAN_IP_CONNECTION, action).await?;
+    let address = xml_element(&response, element)
+        .filter(|address| !address.is_empty())
+        .ok_or_else(|| format!("The FritzBox has no external {} address", family))?;
+    Ok(address.parse::<IpAddr>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    const EXTERNAL_IP_RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u="urn:dslforum-org:service:WANIPConnection:1">
+<NewExternalIPAddress>198.41.0.4</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>"#;
+
+    const EXTERNAL_IPV6_RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:X_AVM_DE_GetExternalIPv6AddressResponse xmlns:u="urn:dslforum-org:service:WANIPConnection:1">
+<NewExternalIPv6Address>2a00:1450::1</NewExternalIPv6Address>
+<NewPrefixLength>64</NewPrefixLength>
+<NewValidLifetime>86400</NewValidLifetime>
+<NewPreferedLifetime>14400</NewPreferedLifetime>
+</u:X_AVM_DE_GetExternalIPv6AddressResponse>
+</s:Body>
+</s:Envelope>"#;
+
+    #[test]
+    fn test_digest_authorization() {
+        // Example of RFC 2617
+        let challenge = parse_challenge(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let credentials = Credentials {
+            username: "Mufasa".to_string(),
+            password: "Circle Of Life".to_string(),
+        };
+
+        let authorization = digest_authorization(
+            &challenge,
+            &credentials,
+            "GET",
+            "/dir/index.html",
+            "0a4f113b",
+        )
+        .unwrap();
+
+        assert!(authorization.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+    }
+
+    #[tokio::test]
+    async fn test_get_external_ip_with_credentials() {
+        let mut server = mockito::Server::new_async().await;
+        let challenge_mock = server
+            .mock("POST", CONTROL_PATH)
+            .match_header("authorization", Matcher::Missing)
+            .with_status(401)
+            .with_header(
+                "www-authenticate",
+                "Digest realm=\"F!Box SOAP-Auth\", nonce=\"F758BE72FB999CEA\", algorithm=MD5, qop=\"auth\"",
+            )
+            .create_async()
+            .await;
+        let soap_mock = server
+            .mock("POST", CONTROL_PATH)
+            .match_header(
+                "authorization",
+                Matcher::Regex(
+                    "^Digest username=\"dyndns\", realm=\"F!Box SOAP-Auth\"".to_string(),
+                ),
+            )
+            .match_header(
+                "soapaction",
+                "\"urn:dslforum-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            )
+            .with_status(200)
+            .with_body(EXTERNAL_IP_RESPONSE)
+            .create_async()
+            .await;
+        let credentials = Credentials {
+            username: "dyndns".to_string(),
+            password: "secret".to_string(),
+        };
+
+        let result = get_external_ip(
+            &Client::new(),
+            &Url::parse(&server.url()).unwrap(),
+            Some(&credentials),
+            AddressFamily::Ipv4,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+        challenge_mock.assert_async().await;
+        soap_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_external_ipv6_without_credentials() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("POST", CONTROL_PATH)
+            .match_header(
+                "soapaction",
+                "\"urn:dslforum-org:service:WANIPConnection:1#X_AVM_DE_GetExternalIPv6Address\"",
+            )
+            .with_status(200)
+            .with_body(EXTERNAL_IPV6_RESPONSE)
+            .create_async()
+            .await;
+
+        let result = get_external_ip(
+            &Client::new(),
+            &Url::parse(&server.url()).unwrap(),
+            None,
+            AddressFamily::Ipv6,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "2a00:1450::1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::{fs, time};
+
+pub const DEFAULT_COMMAND_TIMEOUT_IN_SECONDS: u64 = 10;
+
+/// Parses the first non-empty line of `output` as an address.
+fn parse_address(output: &str, origin: &str) -> Result<IpAddr, Box<dyn Error>> {
+    let line = output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .ok_or_else(|| format!("{} returned no address", origin))?;
+    line.parse::<IpAddr>()
+        .map_err(|e| format!("{} returned an invalid address {:?}: {}", origin, line, e).into())
+}
+
+/// Runs `command` with `sh -c` and parses its standard output. The command is
+/// killed when it runs longer than `timeout`.
+pub async fn run_command(command: &str, timeout: Duration) -> Result<IpAddr, Box<dyn Error>> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let output = time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| format!("Command {:?} timed out after {:?}", command, timeout))??;
+    if !output.status.success() {
+        return Err(format!(
+            "Command {:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    parse_address(
+        &String::from_utf8_lossy(&output.stdout),
+        &format!("Command {:?}", command),
+    )
+}
+
+/// Reads the address from `path`, which is read again at every detection so
+/// changes are picked up on the next cycle.
+pub async fn read_file(path: &Path) -> Result<IpAddr, Box<dyn Error>> {
+    let content = fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    parse_address(&content, &path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_run_command() {
+        assert_eq!(
+            run_command("echo; echo ' 198.41.0.4 '", TIMEOUT)
+                .await
+                .unwrap(),
+            "198.41.0.4".parse::<IpAddr>().unwrap()
+        );
+        assert!(run_command("echo not-an-ip", TIMEOUT).await.is_err());
+        assert!(
+            run_command("echo 198.41.0.4; exit 1", TIMEOUT)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_command_timeout() {
+        let error = run_command("sleep 5", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_read_file_is_read_again() {
+        let path = std::env::temp_dir().join(format!(
+            "infomaniak-dyndns-wildcard-{}.txt",
+            crate::random::random_u64()
+        ));
+
+        std::fs::write(&path, "2a00:1450::1\n").unwrap();
+        assert_eq!(
+            read_file(&path).await.unwrap(),
+            "2a00:1450::1".parse::<IpAddr>().unwrap()
+        );
+        std::fs::write(&path, "2a00:1450::2\n").unwrap();
+        assert_eq!(
+            read_file(&path).await.unwrap(),
+            "2a00:1450::2".parse::<IpAddr>().unwrap()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_file(&path).await.is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use super::DetectionBinding;
+use crate::address_family::AddressFamily;
+use crate::random;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: u32 = 4;
+
+const NAT_PMP_VERSION: u8 = 0;
+const NAT_PMP_OPCODE_PUBLIC_ADDRESS: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const PCP_REQUEST_LENGTH: usize = 60;
+const PROTOCOL_UDP: u8 = 17;
+const PCP_MAPPING_LIFETIME_IN_SECONDS: u32 = 60;
+
+/// Returns the default IPv4 gateway, read from the kernel routing table.
+pub fn default_gateway() -> Result<Ipv4Addr, Box<dyn Error>> {
+    let routes = fs::read_to_string("/proc/net/route")?;
+    for route in routes.lines().skip(1) {
+        let fields: Vec<&str> = route.split_whitespace().collect();
+        if let [_, "00000000", gateway, ..] = fields.as_slice() {
+            let gateway = u32::from_str_radix(gateway, 16)?;
+            return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+        }
+    }
+    Err("No default gateway found in /proc/net/route".into())
+}
+
+/// Sends `request` to `gateway` and waits for a response, retrying with a
+/// doubling timeout like NAT-PMP clients do.
+async fn exchange(
+    socket: &UdpSocket,
+    gateway: SocketAddr,
+    request: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buffer = [0u8; 1100];
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send_to(request, gateway).await?;
+        if let Ok(result) = time::timeout(timeout, socket.recv_from(&mut buffer)).await {
+            let (length, from) = result?;
+            if from.ip() == gateway.ip() {
+                return Ok(buffer[..length].to_vec());
+            }
+        }
+        timeout *= 2;
+    }
+    Err(format!("Gateway {} didn't answer", gateway).into())
+}
+
+/// Asks the NAT-PMP gateway for its public IPv4 address, from a socket bound
+/// with `binding`.
+pub async fn get_public_address(
+    gateway: SocketAddr,
+    binding: &DetectionBinding,
+) -> Result<Ipv4Addr, Box<dyn Error>> {
+    let socket = binding.bind_udp(AddressFamily::of(&gateway.ip())).await?;
+    let response = exchange(
+        &socket,
+        gateway,
+        &[NAT_PMP_VERSION, NAT_PMP_OPCODE_PUBLIC_ADDRESS],
+    )
+    .await?;
+    if response.len() < 12 || response[1] != 128 + NAT_PMP_OPCODE_PUBLIC_ADDRESS {
+        return Err("Invalid NAT-PMP response".into());
+    }
+    let result_code = u16::from_be_bytes([response[2], response[3]]);
+    if result_code != 0 {
+        return Err(format!("NAT-PMP request failed with result code {}", result_code).into());
+    }
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+fn to_pcp_address(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+fn from_pcp_address(octets: [u8; 16]) -> IpAddr {
+    let address = Ipv6Addr::from(octets);
+    match address.to_ipv4_mapped() {
+        Some(address) => IpAddr::V4(address),
+        None => IpAddr::V6(address),
+    }
+}
+
+/// Encodes a PCP MAP request for UDP packets to `internal_port` of `client`.
+pub fn encode_map_request(
+    client: IpAddr,
+    internal_port: u16,
+    lifetime: u32,
+    nonce: [u8; 12],
+) -> [u8; PCP_REQUEST_LENGTH] {
+    let mut request = [0u8; PCP_REQUEST_LENGTH];
+    request[0] = PCP_VERSION;
+    request[1] = PCP_OPCODE_MAP;
+    request[4..8].copy_from_slice(&lifetime.to_be_bytes());
+    request[8..24].copy_from_slice(&to_pcp_address(client));
+    request[24..36].copy_from_slice(&nonce);
+    request[36] = PROTOCOL_UDP;
+    request[40..42].copy_from_slice(&internal_port.to_be_bytes());
+    // No suggested external port, and the unspecified address of the family
+    let unspecified = match client {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    request[44..60].copy_from_slice(&to_pcp_address(unspecified));
+    request
+}
+
+/// Returns the assigned external address of a PCP MAP response.
+pub fn decode_map_response(response: &[u8], nonce: [u8; 12]) -> Result<IpAddr, Box<dyn Error>> {
+    if response.len() < PCP_REQUEST_LENGTH
+        || response[0] != PCP_VERSION
+        || response[1] != PCP_RESPONSE_BIT | PCP_OPCODE_MAP
+    {
+        return Err("Invalid PCP response".into());
+    }
+    let result_code = response[3];
+    if result_code != 0 {
+        return Err(format!("PCP request failed with result code {}", result_code).into());
+    }
+    if response[24..36] != nonce {
+        return Err("PCP response doesn't match the request".into());
+    }
+    let mut address = [0u8; 16];
+    address.copy_from_slice(&response[44..60]);
+    Ok(from_pcp_address(address))
+}
+
+/// Asks the PCP server for the external address of a short-lived mapping,
+/// deleted right after, from a socket bound with `binding`.
+pub async fn get_external_address(
+    server: SocketAddr,
+    binding: &DetectionBinding,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let socket = binding.bind_udp(AddressFamily::of(&server.ip())).await?;
+    socket.connect(server).await?;
+    let client = socket.local_addr()?;
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&random::random_u64().to_be_bytes());
+    nonce[8..].copy_from_slice(&(random::random_u64() as u32).to_be_bytes());
+
+    let request = encode_map_request(
+        client.ip(),
+        client.port(),
+        PCP_MAPPING_LIFETIME_IN_SECONDS,
+        nonce,
+    );
+    let response = exchange(&socket, server, &request).await?;
+    let external_address = decode_map_response(&response, nonce)?;
+
+    let delete = encode_map_request(client.ip(), client.port(), 0, nonce);
+    socket.send_to(&delete, server).await?;
+    Ok(external_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers one request with `respond(request)`.
+    fn start_gateway(respond: fn(&[u8]) -> Vec<u8>) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1100];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            socket.send_to(&respond(&buffer[..length]), client).unwrap();
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp_public_address() {
+        let gateway = start_gateway(|request| {
+            assert_eq!(request, [0, 0]);
+            vec![0, 128, 0, 0, 0, 0, 0x12, 0x34, 198, 41, 0, 4]
+        });
+
+        let result = get_public_address(gateway, &DetectionBinding::default()).await;
+
+        assert_eq!(result.unwrap(), Ipv4Addr::new(198, 41, 0, 4));
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp_error() {
+        let gateway = start_gateway(|_| vec![0, 128, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let error = get_public_address(gateway, &DetectionBinding::default())
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("result code 3"));
+    }
+
+    #[tokio::test]
+    async fn test_pcp_external_address() {
+        let server = start_gateway(|request| {
+            assert_eq!(request[..2], [PCP_VERSION, PCP_OPCODE_MAP]);
+            let mut response = request.to_vec();
+            response[1] = PCP_RESPONSE_BIT | PCP_OPCODE_MAP;
+            response[44..60].copy_from_slice(&to_pcp_address("198.41.0.4".parse().unwrap()));
+            response
+        });
+
+        let result = get_external_address(server, &DetectionBinding::default()).await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_pcp_response_with_other_nonce_is_rejected() {
+        let request = encode_map_request("192.168.1.2".parse().unwrap(), 1234, 60, [1; 12]);
+        let mut response = request.to_vec();
+        response[1] = PCP_RESPONSE_BIT | PCP_OPCODE_MAP;
+
+        assert!(decode_map_response(&response, [1; 12]).is_ok());
+        assert!(decode_map_response(&response, [2; 12]).is_err());
+    }
+}
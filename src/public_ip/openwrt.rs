@@ -0,0 +1,350 @@
+use super::Credentials;
+use crate::address_family::AddressFamily;
+use reqwest::{Client, Url};
+use serde_json::{Value, json};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_OPENWRT_URL: &str = "http://192.168.1.1/ubus";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Session of unauthenticated calls, only allowed what the ACLs of the
+/// anonymous user allow.
+const ANONYMOUS_SESSION: &str = "00000000000000000000000000000000";
+/// Idle time after which rpcd expires sessions when the login doesn't tell.
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+/// Sessions are renewed this long before they expire, so they don't expire
+/// during a call.
+const SESSION_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+struct Session {
+    id: String,
+    timeout: Duration,
+    expires_at: Instant,
+}
+
+/// Session kept between detections, so each doesn't open a new one on the
+/// router.
+#[derive(Default)]
+pub struct SessionCache {
+    session: Mutex<Option<Session>>,
+}
+
+impl fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCache").finish_non_exhaustive()
+    }
+}
+
+impl SessionCache {
+    /// Returns the cached session if it didn't expire at `now`.
+    fn get(&self, now: Instant) -> Option<Session> {
+        self.session
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|session| now + SESSION_EXPIRY_MARGIN < session.expires_at)
+    }
+
+    /// Caches `session`, just used at `now`, which extends it.
+    fn store(&self, mut session: Session, now: Instant) {
+        session.expires_at = now + session.timeout;
+        *self.session.lock().unwrap() = Some(session);
+    }
+
+    fn clear(&self) {
+        *self.session.lock().unwrap() = None;
+    }
+}
+
+/// Calls `method` of the ubus `object` via JSON-RPC and returns its data.
+async fn call(
+    client: &Client,
+    url: &Url,
+    session: &str,
+    object: &str,
+    method: &str,
+    arguments: Value,
+) -> Result<Value, Box<dyn Error>> {
+    let response: Value = client
+        .post(url.clone())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "call",
+            "params": [session, object, method, arguments],
+        }))
+        .timeout(HTTP_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if let Some(error) = response.get("error") {
+        return Err(format!(
+            "ubus call {} {} failed: {}",
+            object,
+            method,
+            error["message"].as_str().unwrap_or_default()
+        )
+        .into());
+    }
+    // The result is the status code of the call, followed by its data
+    match response["result"].as_array().map(Vec::as_slice) {
+        Some([status, data]) if status == 0 => Ok(data.clone()),
+        Some([status]) if status == 0 => Ok(Value::Null),
+        Some([status, ..]) => Err(format!(
+            "ubus call {} {} failed with status {}",
+            object, method, status
+        )
+        .into()),
+        _ => Err(format!("Invalid response to ubus call {} {}", object, method).into()),
+    }
+}
+
+async fn login(
+    client: &Client,
+    url: &Url,
+    credentials: &Credentials,
+) -> Result<Session, Box<dyn Error>> {
+    let data = call(
+        client,
+        url,
+        ANONYMOUS_SESSION,
+        "session",
+        "login",
+        json!({
+            "username": credentials.username,
+            "password": credentials.password,
+        }),
+    )
+    .await?;
+    let id = data["ubus_rpc_session"]
+        .as_str()
+        .ok_or("The ubus login didn't return a session")?;
+    let timeout = data["timeout"]
+        .as_u64()
+        .map_or(DEFAULT_SESSION_TIMEOUT, Duration::from_secs);
+    Ok(Session {
+        id: id.to_string(),
+        timeout,
+        expires_at: Instant::now() + timeout,
+    })
+}
+
+/// Calls the `status` method of the network `interface` with the cached
+/// session, or a new one when it expired or the router dropped it, for
+/// instance when it rebooted.
+async fn interface_status(
+    client: &Client,
+    url: &Url,
+    credentials: &Credentials,
+    sessions: &SessionCache,
+    object: &str,
+) -> Result<Value, Box<dyn Error>> {
+    if let Some(session) = sessions.get(Instant::now()) {
+        let result = call(client, url, &session.id, object, "status", json!({}))
+            .await
+            .map_err(|e| e.to_string());
+        match result {
+            Ok(status) => {
+                sessions.store(session, Instant::now());
+                return Ok(status);
+            }
+            Err(_) => sessions.clear(),
+        }
+    }
+    let session = login(client, url, credentials).await?;
+    let status = call(client, url, &session.id, object, "status", json!({})).await?;
+    sessions.store(session, Instant::now());
+    Ok(status)
+}
+
+/// Asks the OpenWrt router at `url` for the address of `family` of the
+/// network `interface`, like `ubus call network.interface.wan status`.
+pub async fn get_interface_address(
+    client: &Client,
+    url: &Url,
+    credentials: Option<&Credentials>,
+    sessions: &SessionCache,
+    interface: &str,
+    family: AddressFamily,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let object = format!("network.interface.{}", interface);
+    let status = match credentials {
+        Some(credentials) => interface_status(client, url, credentials, sessions, &object).await?,
+        None => call(client, url, ANONYMOUS_SESSION, &object, "status", json!({})).await?,
+    };
+    let key = match family {
+        AddressFamily::Ipv4 => "ipv4-address",
+        AddressFamily::Ipv6 => "ipv6-address",
+    };
+    let address = status[key]
+        .as_array()
+        .and_then(|addresses| addresses.first())
+        .and_then(|address| address["address"].as_str())
+        .ok_or_else(|| format!("Interface {} has no {} address", interface, family))?;
+    Ok(address.parse::<IpAddr>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    const LOGIN_RESPONSE: &str = r#"{"jsonrpc":"2.0","id":1,"result":[0,{"ubus_rpc_session":"c1ed6c7b025d0caca723a816fa61b668","timeout":300,"expires":299,"acls":{"ubus":{"network.interface.*":["status"]}},"data":{"username":"dyndns"}}]}"#;
+
+    const STATUS_RESPONSE: &str = r#"{"jsonrpc":"2.0","id":1,"result":[0,{"up":true,"pending":false,"available":true,"autostart":true,"dynamic":false,"uptime":86112,"l3_device":"pppoe-wan","proto":"pppoe","device":"eth1","metric":0,"dns_metric":0,"delegation":true,"ipv4-address":[{"address":"198.41.0.4","mask":32,"ptpaddress":"198.41.0.1"}],"ipv6-address":[],"ipv6-prefix":[],"ipv6-prefix-assignment":[],"route":[{"target":"0.0.0.0","mask":0,"nexthop":"198.41.0.1","source":"0.0.0.0/0"}],"dns-server":["198.41.0.53"],"dns-search":[],"neighbors":[],"inactive":{"ipv4-address":[],"ipv6-address":[],"route":[],"dns-server":[],"dns-search":[],"neighbors":[]},"data":{}}]}"#;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "dyndns".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_interface_address() {
+        let mut server = mockito::Server::new_async().await;
+        let login_mock = server
+            .mock("POST", "/ubus")
+            .match_body(Matcher::PartialJson(json!({
+                "params": [ANONYMOUS_SESSION, "session", "login", {"username": "dyndns", "password": "secret"}],
+            })))
+            .with_status(200)
+            .with_body(LOGIN_RESPONSE)
+            .expect(1)
+            .create_async()
+            .await;
+        let status_mock = server
+            .mock("POST", "/ubus")
+            .match_body(Matcher::PartialJson(json!({
+                "params": ["c1ed6c7b025d0caca723a816fa61b668", "network.interface.wan", "status", {}],
+            })))
+            .with_status(200)
+            .with_body(STATUS_RESPONSE)
+            .expect(2)
+            .create_async()
+            .await;
+        let url = Url::parse(&format!("{}/ubus", server.url())).unwrap();
+        let sessions = SessionCache::default();
+
+        let result = get_interface_address(
+            &Client::new(),
+            &url,
+            Some(&credentials()),
+            &sessions,
+            "wan",
+            AddressFamily::Ipv4,
+        )
+        .await;
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+
+        // The session is reused rather than opened again
+        let result = get_interface_address(
+            &Client::new(),
+            &url,
+            Some(&credentials()),
+            &sessions,
+            "wan",
+            AddressFamily::Ipv6,
+        )
+        .await;
+        assert!(result.unwrap_err().to_string().contains("no IPv6 address"));
+
+        login_mock.assert_async().await;
+        status_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_interface_address_access_denied() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("POST", "/ubus")
+            .with_status(200)
+            .with_body(r#"{"jsonrpc":"2.0","id":1,"result":[6]}"#)
+            .create_async()
+            .await;
+        let url = Url::parse(&format!("{}/ubus", server.url())).unwrap();
+
+        let error = get_interface_address(
+            &Client::new(),
+            &url,
+            None,
+            &SessionCache::default(),
+            "wan",
+            AddressFamily::Ipv4,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(error.to_string().contains("status 6"));
+    }
+
+    #[tokio::test]
+    async fn test_get_interface_address_renews_dropped_session() {
+        let mut server = mockito::Server::new_async().await;
+        let login_mock = server
+            .mock("POST", "/ubus")
+            .match_body(Matcher::PartialJson(json!({
+                "params": [ANONYMOUS_SESSION, "session", "login", {"username": "dyndns", "password": "secret"}],
+            })))
+            .with_status(200)
+            .with_body(LOGIN_RESPONSE)
+            .expect(1)
+            .create_async()
+            .await;
+        let dropped_session_mock = server
+            .mock("POST", "/ubus")
+            .match_body(Matcher::PartialJson(json!({
+                "params": ["dropped", "network.interface.wan", "status", {}],
+            })))
+            .with_status(200)
+            .with_body(r#"{"jsonrpc":"2.0","id":1,"result":[6]}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let status_mock = server
+            .mock("POST", "/ubus")
+            .match_body(Matcher::PartialJson(json!({
+                "params": ["c1ed6c7b025d0caca723a816fa61b668", "network.interface.wan", "status", {}],
+            })))
+            .with_status(200)
+            .with_body(STATUS_RESPONSE)
+            .expect(1)
+            .create_async()
+            .await;
+        let url = Url::parse(&format!("{}/ubus", server.url())).unwrap();
+        let sessions = SessionCache::default();
+        sessions.store(
+            Session {
+                id: "dropped".to_string(),
+                timeout: DEFAULT_SESSION_TIMEOUT,
+                expires_at: Instant::now(),
+            },
+            Instant::now(),
+        );
+
+        let result = get_interface_address(
+            &Client::new(),
+            &url,
+            Some(&credentials()),
+            &sessions,
+            "wan",
+            AddressFamily::Ipv4,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+        login_mock.assert_async().await;
+        dropped_session_mock.assert_async().await;
+        status_mock.assert_async().await;
+        assert_eq!(
+            sessions.get(Instant::now()).unwrap().id,
+            "c1ed6c7b025d0caca723a816fa61b668"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use super::DetectionBinding;
+use crate::address_family::AddressFamily;
+use crate::random;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net;
+use tokio::time;
+
+pub const DEFAULT_STUN_SERVERS: &[&str] = &["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: u32 = 3;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LENGTH: usize = 20;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Encodes a binding request without attributes.
+pub fn encode_binding_request(transaction_id: [u8; 12]) -> [u8; HEADER_LENGTH] {
+    let mut request = [0u8; HEADER_LENGTH];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..20].copy_from_slice(&transaction_id);
+    request
+}
+
+/// Decodes the address of a MAPPED-ADDRESS or XOR-MAPPED-ADDRESS attribute,
+/// `mask` being the bytes the address is XORed with.
+fn decode_address(value: &[u8], mask: &[u8]) -> Result<SocketAddr, Box<dyn Error>> {
+    let unmask = |bytes: &[u8]| -> Vec<u8> {
+        bytes
+            .iter()
+            .zip(mask.iter().chain(std::iter::repeat(&0)))
+            .map(|(byte, mask)| byte ^ mask)
+            .collect()
+    };
+    let (family, port) = match value {
+        [_, family, port @ ..] if port.len() >= 2 => (*family, unmask(&port[..2])),
+        _ => return Err("Truncated STUN address".into()),
+    };
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let address = unmask(&value[4..]);
+    match (family, address.len()) {
+        (FAMILY_IPV4, 4) => {
+            let octets: [u8; 4] = address.try_into().unwrap();
+            Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port))
+        }
+        (FAMILY_IPV6, 16) => {
+            let octets: [u8; 16] = address.try_into().unwrap();
+            Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => Err(format!("Invalid STUN address family {}", family).into()),
+    }
+}
+
+/// Returns the address a binding response reports, preferring the
+/// XOR-MAPPED-ADDRESS attribute to the legacy MAPPED-ADDRESS.
+pub fn decode_binding_response(
+    response: &[u8],
+    transaction_id: [u8; 12],
+) -> Result<SocketAddr, Box<dyn Error>> {
+    if response.len() < HEADER_LENGTH {
+        return Err("Truncated STUN response".into());
+    }
+    let message_type = u16::from_be_bytes([response[0], response[1]]);
+    if message_type != BINDING_SUCCESS_RESPONSE {
+        return Err(format!("Unexpected STUN message type {:#06x}", message_type).into());
+    }
+    if response[4..8] != MAGIC_COOKIE.to_be_bytes() || response[8..20] != transaction_id {
+        return Err("STUN response doesn't match the request".into());
+    }
+    let length = usize::from(u16::from_be_bytes([response[2], response[3]]));
+    let attributes = response
+        .get(HEADER_LENGTH..HEADER_LENGTH + length)
+        .ok_or("Truncated STUN response")?;
+
+    let mut mapped_address = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let attribute_type = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let attribute_length = usize::from(u16::from_be_bytes([
+            attributes[offset + 2],
+            attributes[offset + 3],
+        ]));
+        let value = attributes
+            .get(offset + 4..offset + 4 + attribute_length)
+            .ok_or("Truncated STUN attribute")?;
+        match attribute_type {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => {
+                // The port is XORed with the cookie, the address with the
+                // cookie and the transaction id.
+                let mask = &response[4..20];
+                return decode_address(value, mask);
+            }
+            ATTRIBUTE_MAPPED_ADDRESS => mapped_address = Some(decode_address(value, &[])?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes
+        offset += 4 + attribute_length.div_ceil(4) * 4;
+    }
+    mapped_address.ok_or_else(|| "No mapped address in the STUN response".into())
+}
+
+/// Sends a binding request to `server` over `family`, from a socket bound
+/// with `binding`, and returns the address the server saw the request coming
+/// from.
+pub async fn get_mapped_address(
+    server: &str,
+    family: AddressFamily,
+    binding: &DetectionBinding,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let server_address = net::lookup_host(server)
+        .await?
+        .find(|address| AddressFamily::of(&address.ip()) == family)
+        .ok_or_else(|| format!("STUN server {} has no {} address", server, family))?;
+    let socket = binding.bind_udp(family).await?;
+    socket.connect(server_address).await?;
+
+    let mut transaction_id = [0u8; 12];
+    transaction_id[..8].copy_from_slice(&random::random_u64().to_be_bytes());
+    transaction_id[8..].copy_from_slice(&(random::random_u64() as u32).to_be_bytes());
+    let request = encode_binding_request(transaction_id);
+
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buffer = [0u8; 1500];
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(&request).await?;
+        if let Ok(result) = time::timeout(timeout, socket.recv(&mut buffer)).await {
+            let length = result?;
+            return Ok(decode_binding_response(&buffer[..length], transaction_id)?.ip());
+        }
+        timeout *= 2;
+    }
+    Err(format!("STUN server {} didn't answer", server).into())
+}
+
+/// Asks each of `servers` in turn, returns the first address reported.
+pub async fn get_public_ip(
+    servers: &[String],
+    family: AddressFamily,
+    binding: &DetectionBinding,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let mut errors = Vec::new();
+    for server in servers {
+        match get_mapped_address(server, family, binding).await {
+            Ok(address) => return Ok(address),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    Err(format!("No STUN server answered: {}", errors.join(", ")).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_xor_mapped_address(address: SocketAddr, transaction_id: &[u8]) -> Vec<u8> {
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(transaction_id);
+        let (family, octets) = match address.ip() {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let port = address.port().to_be_bytes();
+        let mut value = vec![0, family, port[0] ^ mask[0], port[1] ^ mask[1]];
+        value.extend(octets.iter().zip(&mask).map(|(byte, mask)| byte ^ mask));
+
+        let mut attribute = ATTRIBUTE_XOR_MAPPED_ADDRESS.to_be_bytes().to_vec();
+        attribute.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        attribute.extend(value);
+        attribute
+    }
+
+    fn binding_response(request: &[u8], attributes: &[u8]) -> Vec<u8> {
+        let mut response = BINDING_SUCCESS_RESPONSE.to_be_bytes().to_vec();
+        response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        response.extend_from_slice(&request[4..20]);
+        response.extend_from_slice(attributes);
+        response
+    }
+
+    /// Answers one binding request with the address it came from.
+    fn start_stun_responder() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            let request = &buffer[..length];
+            assert_eq!(request[..2], BINDING_REQUEST.to_be_bytes());
+            // Unknown attributes before the mapped address are skipped
+            let mut attributes = vec![0x80, 0x22, 0x00, 0x03, b'f', b'o', b'o', 0x00];
+            attributes.extend(encode_xor_mapped_address(client, &request[8..20]));
+            socket
+                .send_to(&binding_response(request, &attributes), client)
+                .unwrap();
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_get_public_ip_from_stun_responder() {
+        let server = start_stun_responder();
+
+        let result = get_public_ip(
+            &["127.0.0.1:1".to_string(), server.to_string()],
+            AddressFamily::Ipv4,
+            &DetectionBinding::default(),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_decode_xor_mapped_ipv6_address() {
+        let transaction_id = [7u8; 12];
+        let request = encode_binding_request(transaction_id);
+        let address: SocketAddr = "[2001:db8:1234::1]:32853".parse().unwrap();
+        let response = binding_response(
+            &request,
+            &encode_xor_mapped_address(address, &transaction_id),
+        );
+
+        assert_eq!(
+            decode_binding_response(&response, transaction_id).unwrap(),
+            address
+        );
+        assert!(decode_binding_response(&response, [8u8; 12]).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use super::DetectionBinding;
+use crate::address_family::AddressFamily;
+use reqwest::{Client, Url};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+pub const SSDP_MULTICAST_ADDRESS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const INTERNET_GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services of an internet gateway device answering `GetExternalIPAddress`.
+const WAN_CONNECTION_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Returns the text of the first `name` element of `xml`, ignoring namespace
+/// prefixes.
+pub fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')? + 1;
+        rest = &rest[start..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+        if local_name == name && !tag.ends_with('/') {
+            let close = rest.find("</")?;
+            return Some(rest[..close].trim());
+        }
+    }
+}
+
+/// Sends an SSDP search for internet gateway devices to `ssdp_address`, from
+/// a socket bound with `binding`, and returns the location of the
+/// description of the first one answering.
+pub async fn discover(
+    ssdp_address: SocketAddr,
+    binding: &DetectionBinding,
+) -> Result<Url, Box<dyn Error>> {
+    let socket = binding.bind_udp(AddressFamily::Ipv4).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_MULTICAST_ADDRESS, INTERNET_GATEWAY_DEVICE
+    );
+    socket.send_to(search.as_bytes(), ssdp_address).await?;
+
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buffer = [0u8; 2048];
+    loop {
+        let (length, _) = time::timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| "No UPnP internet gateway device answered the SSDP search")??;
+        let response = String::from_utf8_lossy(&buffer[..length]);
+        let location = response.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            return Ok(Url::parse(&location)?);
+        }
+    }
+}
+
+/// Internet gateway device whose WAN connection service is known.
+#[derive(Debug, Clone)]
+pub struct WanConnection {
+    pub service_type: String,
+    pub control_url: Url,
+}
+
+/// Finds the WAN connection service in the device description at `location`.
+pub async fn wan_connection(
+    client: &Client,
+    location: &Url,
+) -> Result<WanConnection, Box<dyn Error>> {
+    let description = client
+        .get(location.clone())
+        .timeout(HTTP_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let base_url = match xml_element(&description, "URLBase") {
+        Some(base_url) => Url::parse(base_url)?,
+        None => location.clone(),
+    };
+    for service in description.split("<service>").skip(1) {
+        let Some(service_type) = xml_element(service, "serviceType") else {
+            continue;
+        };
+        if !WAN_CONNECTION_SERVICES.contains(&service_type) {
+            continue;
+        }
+        let control_url = xml_element(service, "controlURL")
+            .ok_or_else(|| format!("Service {} has no controlURL", service_type))?;
+        return Ok(WanConnection {
+            service_type: service_type.to_string(),
+            control_url: base_url.join(control_url)?,
+        });
+    }
+    Err(format!("No WAN connection service found in {}", location).into())
+}
+
+/// Calls `action` of `service_type` at `control_url` without arguments and
+/// returns the response envelope.
+pub async fn soap_call(
+    request: reqwest::RequestBuilder,
+    service_type: &str,
+    action: &str,
+) -> Result<String, Box<dyn Error>> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service_type}\"></u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let response = request
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service_type, action))
+        .body(body)
+        .timeout(HTTP_TIMEOUT)
+        .send()
+        .await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        let error = xml_element(&text, "errorDescription").unwrap_or_default();
+        return Err(format!("SOAP call {} failed with {}: {}", action, status, error).into());
+    }
+    Ok(text)
+}
+
+/// Asks the WAN connection service for its external IP address.
+pub async fn external_ip_address(
+    client: &Client,
+    connection: &WanConnection,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let response = soap_call(
+        client.post(connection.control_url.clone()),
+        &connection.service_type,
+        "GetExternalIPAddress",
+    )
+    .await?;
+    let address = xml_element(&response, "NewExternalIPAddress")
+        .ok_or("The gateway didn't return its external IP address")?;
+    Ok(address.parse::<IpAddr>()?)
+}
+
+/// Asks the internet gateway device at `location`, or the first one found
+/// via SSDP at `ssdp_address`, for its external IP address.
+pub async fn get_external_ip(
+    client: &Client,
+    location: Option<&Url>,
+    ssdp_address: SocketAddr,
+    binding: &DetectionBinding,
+) -> Result<IpAddr, Box<dyn Error>> {
+    let location = match location {
+        Some(location) => location.clone(),
+        None => discover(ssdp_address, binding).await?,
+    };
+    let connection = wan_connection(client, &location).await?;
+    external_ip_address(client, &connection).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+
+    const EXTERNAL_IP_RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+      <NewExternalIPAddress>198.41.0.4</NewExternalIPAddress>
+    </u:GetExternalIPAddressResponse>
+  </s:Body>
+</s:Envelope>"#;
+
+    /// Answers one SSDP search with the location of `description_url`.
+    fn start_ssdp_responder(description_url: String) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 2048];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            let search = String::from_utf8_lossy(&buffer[..length]);
+            assert!(search.starts_with("M-SEARCH"));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nST: {}\r\nLOCATION: {}\r\n\r\n",
+                INTERNET_GATEWAY_DEVICE, description_url
+            );
+            socket.send_to(response.as_bytes(), client).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn test_xml_element() {
+        assert_eq!(
+            xml_element(EXTERNAL_IP_RESPONSE, "NewExternalIPAddress"),
+            Some("198.41.0.4")
+        );
+        assert_eq!(xml_element("<a><b/><c> x </c></a>", "c"), Some("x"));
+        assert_eq!(xml_element("<a></a>", "c"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_external_ip_via_ssdp() {
+        let mut server = mockito::Server::new_async().await;
+        let description_mock = server
+            .mock("GET", "/rootDesc.xml")
+            .with_status(200)
+            .with_body(DESCRIPTION)
+            .create_async()
+            .await;
+        let soap_mock = server
+            .mock("POST", "/ctl/IPConn")
+            .match_header(
+                "soapaction",
+                "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            )
+            .match_body(Matcher::Regex("<u:GetExternalIPAddress ".to_string()))
+            .with_status(200)
+            .with_body(EXTERNAL_IP_RESPONSE)
+            .create_async()
+            .await;
+        let ssdp_address = start_ssdp_responder(format!("{}/rootDesc.xml", server.url()));
+
+        let result = get_external_ip(
+            &Client::new(),
+            None,
+            ssdp_address,
+            &DetectionBinding::default(),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "198.41.0.4".parse::<IpAddr>().unwrap());
+        description_mock.assert_async().await;
+        soap_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_external_ip_soap_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _description_mock = server
+            .mock("GET", "/rootDesc.xml")
+            .with_status(200)
+            .with_body(DESCRIPTION)
+            .create_async()
+            .await;
+        let _soap_mock = server
+            .mock("POST", "/ctl/IPConn")
+            .with_status(500)
+            .with_body(
+                "<errorCode>501</errorCode><errorDescription>Action Failed</errorDescription>",
+            )
+            .create_async()
+            .await;
+        let location = Url::parse(&format!("{}/rootDesc.xml", server.url())).unwrap();
+
+        let error = get_external_ip(
+            &Client::new(),
+            Some(&location),
+            SSDP_MULTICAST_ADDRESS,
+            &DetectionBinding::default(),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("Action Failed"));
+    }
+}
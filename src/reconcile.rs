@@ -0,0 +1,1172 @@
+use crate::address_family::{AddressFamily, PublicIps};
+use crate::dns_record::{self, DnsRecord};
+use crate::drift::{self, DriftCheck};
+use crate::health_check::HealthChecks;
+use crate::hysteresis::UpdateRateLimit;
+use crate::metrics::Metrics;
+use crate::ownership::{self, Ownership, UnownedRecordsPolicy};
+use crate::propagation::{self, VerificationSettings};
+use crate::records::{self, RecordConfig, RecordSet};
+use log::{error, info, warn};
+use reqwest::Client;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Updates the records of the zones to the public IPs, sharing the API
+/// client and limiting the number of concurrent API calls.
+pub struct Reconciler {
+    pub client: Client,
+    pub infomaniak_zones_api_url: String,
+    pub owner_id: Option<String>,
+    pub unowned_records_policy: UnownedRecordsPolicy,
+    pub verification: Option<VerificationSettings>,
+    pub drift_check: Option<DriftCheck>,
+    pub health_checks: HealthChecks,
+    pub update_rate_limit: UpdateRateLimit,
+    pub api_permits: Semaphore,
+    pub metrics: Arc<Metrics>,
+    /// Whether the API refused the token since it was last asked.
+    pub token_rejected: AtomicBool,
+}
+
+/// Outcome of the update of the records of a family.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpdateOutcome {
+    /// The records point to the targets.
+    Done,
+    /// An update failed.
+    Failed,
+    /// The update was held back by the rate limit, and is allowed after this
+    /// delay.
+    Deferred(Duration),
+}
+
+/// Outcome of a reconciliation, for each address family.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciliation {
+    /// Families for which at least one update failed.
+    pub failed: Vec<AddressFamily>,
+    /// Families for which at least one update was held back by the rate
+    /// limit, with the delay after which the first one is allowed.
+    pub deferred: Vec<(AddressFamily, Duration)>,
+}
+
+impl Reconciliation {
+    /// Returns the reconciliation where the records of `families` failed.
+    fn failed(families: Vec<AddressFamily>) -> Reconciliation {
+        Reconciliation {
+            failed: families,
+            deferred: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, family: AddressFamily, outcome: UpdateOutcome) {
+        match outcome {
+            UpdateOutcome::Done => {}
+            UpdateOutcome::Failed => self.merge(Reconciliation::failed(vec![family])),
+            UpdateOutcome::Deferred(retry_in) => self.merge(Reconciliation {
+                failed: Vec::new(),
+                deferred: vec![(family, retry_in)],
+            }),
+        }
+    }
+
+    pub fn merge(&mut self, other: Reconciliation) {
+        self.failed.extend(other.failed);
+        self.failed
+            .sort_by_key(|family| *family == AddressFamily::Ipv6);
+        self.failed.dedup();
+        for (family, retry_in) in other.deferred {
+            match self.deferred.iter_mut().find(|(known, _)| *known == family) {
+                Some((_, known_retry_in)) => *known_retry_in = retry_in.min(*known_retry_in),
+                None => self.deferred.push((family, retry_in)),
+            }
+        }
+        self.deferred
+            .sort_by_key(|(family, _)| *family == AddressFamily::Ipv6);
+    }
+
+    /// Returns when the held back updates of `family` are allowed, if any.
+    pub fn deferred(&self, family: AddressFamily) -> Option<Duration> {
+        self.deferred
+            .iter()
+            .find(|(deferred_family, _)| *deferred_family == family)
+            .map(|(_, retry_in)| *retry_in)
+    }
+
+    /// Tells whether every record of `family` points to its targets.
+    pub fn is_done(&self, family: AddressFamily) -> bool {
+        !self.failed.contains(&family) && self.deferred(family).is_none()
+    }
+
+    /// Tells whether every record points to its targets.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty() && self.deferred.is_empty()
+    }
+}
+
+fn records_by_zone(records: &[RecordConfig]) -> Vec<(String, Vec<RecordConfig>)> {
+    let mut zones: Vec<(String, Vec<RecordConfig>)> = Vec::new();
+    for record in records {
+        match zones.iter_mut().find(|(zone, _)| *zone == record.zone) {
+            Some((_, zone_records)) => zone_records.push(record.clone()),
+            None => zones.push((record.zone.clone(), vec![record.clone()])),
+        }
+    }
+    zones
+}
+
+impl Reconciler {
+    /// Remembers the API refused the token, if `error` tells so.
+    fn note_error(&self, error: &(dyn Error + 'static)) {
+        if dns_record::rejects_token(error) {
+            self.token_rejected.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Tells whether the API refused the token since the last call, so it
+    /// can be checked again.
+    pub fn take_token_rejection(&self) -> bool {
+        self.token_rejected.swap(false, Ordering::Relaxed)
+    }
+
+    /// Reconciles the records of each zone concurrently.
+    pub async fn reconcile_all(
+        self: &Arc<Self>,
+        records: &[RecordConfig],
+        public_ips: &PublicIps,
+    ) -> Reconciliation {
+        let mut tasks = JoinSet::new();
+        for (zone, zone_records) in records_by_zone(records) {
+            let reconciler = Arc::clone(self);
+            let public_ips = public_ips.clone();
+            tasks.spawn(async move {
+                let result = reconciler
+                    .reconcile_zone(&zone, &zone_records, &public_ips)
+                    .await
+                    .map_err(|e| {
+                        reconciler.note_error(&*e);
+                        e.to_string()
+                    });
+                match result {
+                    Ok(reconciliation) => reconciliation,
+                    Err(e) => {
+                        error!("Error retrieving DNS records of zone {}: {}", zone, e);
+                        // None of the records of the zone could be updated
+                        Reconciliation::failed(
+                            [AddressFamily::Ipv4, AddressFamily::Ipv6]
+                                .into_iter()
+                                .filter(|family| {
+                                    !public_ips.targets(*family).is_empty()
+                                        && records::any_publishes(&zone_records, *family)
+                                })
+                                .collect(),
+                        )
+                    }
+                }
+            });
+        }
+        let mut reconciliation = Reconciliation::default();
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(zone_reconciliation) => reconciliation.merge(zone_reconciliation),
+                Err(e) => error!("Zone reconciliation task failed: {}", e),
+            }
+        }
+        reconciliation
+    }
+
+    /// Lists the records of `zone`, then reconciles each of `records` concurrently.
+    ///
+    /// Families without a public IP are left untouched.
+    pub async fn reconcile_zone(
+        self: &Arc<Self>,
+        zone: &str,
+        records: &[RecordConfig],
+        public_ips: &PublicIps,
+    ) -> Result<Reconciliation, Box<dyn Error>> {
+        self.check_health(records, public_ips).await;
+
+        if let Some(drift_check) = &self.drift_check {
+            let record_sets: Vec<RecordSet> = records
+                .iter()
+                .flat_map(|record| self.published_record_sets(record, public_ips))
+                .collect();
+            let in_sync = drift::records_in_sync(drift_check, zone, &record_sets)
+                .await
+                .map_err(|e| e.to_string());
+            match in_sync {
+                Ok(true) => {
+                    info!(
+                        "DNS records of {} already resolve to the public IPs, nothing to update.",
+                        zone
+                    );
+                    return Ok(Reconciliation::default());
+                }
+                Ok(false) => info!(
+                    "DNS records of {} differ from the public IPs, checking them via the API",
+                    zone
+                ),
+                Err(e) => warn!(
+                    "Error resolving DNS records of {}, checking them via the API: {}",
+                    zone, e
+                ),
+            }
+        }
+
+        let record_types: &[&str] = if self.owner_id.is_some() {
+            &["A", "AAAA", "TXT"]
+        } else {
+            &["A", "AAAA"]
+        };
+        let dns_records = {
+            let _permit = self.api_permits.acquire().await?;
+            dns_record::get_dns_records(
+                &self.client,
+                &self.infomaniak_zones_api_url,
+                zone,
+                record_types,
+            )
+            .await?
+        };
+        info!("Existing DNS record found: {:?}", dns_records);
+        let dns_records = Arc::new(dns_records);
+
+        let mut tasks = JoinSet::new();
+        for record in records {
+            let reconciler = Arc::clone(self);
+            let dns_records = Arc::clone(&dns_records);
+            let record = record.clone();
+            let public_ips = public_ips.clone();
+            tasks.spawn(async move {
+                reconciler
+                    .reconcile_record(&record, &dns_records, &public_ips)
+                    .await
+            });
+        }
+        let mut reconciliation = Reconciliation::default();
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(record_reconciliation) => reconciliation.merge(record_reconciliation),
+                Err(e) => error!("Record reconciliation task failed: {}", e),
+            }
+        }
+        Ok(reconciliation)
+    }
+
+    /// Deletes the A and AAAA records of `records`, which were removed from
+    /// the configuration. With an owner id, only owned records are deleted,
+    /// along with their ownership marker.
+    pub async fn remove_records(&self, records: &[RecordConfig]) {
+        for (zone, zone_records) in records_by_zone(records) {
+            if let Err(e) = self.remove_zone_records(&zone, &zone_records).await {
+                error!("Error removing the records of zone {}: {}", zone, e);
+            }
+        }
+    }
+
+    async fn remove_zone_records(
+        &self,
+        zone: &str,
+        records: &[RecordConfig],
+    ) -> Result<(), Box<dyn Error>> {
+        let dns_records = {
+            let _permit = self.api_permits.acquire().await?;
+            dns_record::get_dns_records(
+                &self.client,
+                &self.infomaniak_zones_api_url,
+                zone,
+                &["A", "AAAA", "TXT"],
+            )
+            .await?
+        };
+        for record in records {
+            if let Some(id) = &self.owner_id {
+                let ownership = ownership::get_record_ownership(&dns_records, &record.name, id);
+                if ownership != Ownership::Owned {
+                    warn!(
+                        "Record {} removed from the configuration is not owned by this instance, \
+                         leaving it untouched",
+                        record.name
+                    );
+                    continue;
+                }
+            }
+            info!("Removing record {} from zone {}", record.name, zone);
+            for family in &record.families {
+                self.reconcile_address_set(zone, &record.name, *family, &[], &dns_records)
+                    .await;
+            }
+            if self.owner_id.is_none() {
+                continue;
+            }
+            for marker in dns_records.iter().filter(|dns_record| {
+                ownership::marker_owner(dns_record, &record.name, &dns_records).is_some()
+            }) {
+                let _permit = self.api_permits.acquire().await?;
+                if let Err(e) = dns_record::delete_dns_record(
+                    &self.client,
+                    &self.infomaniak_zones_api_url,
+                    zone,
+                    &marker.id.to_string(),
+                )
+                .await
+                {
+                    error!(
+                        "Error removing the ownership marker of {}: {}",
+                        record.name, e
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the addresses of the health-checked records concurrently.
+    async fn check_health(&self, records: &[RecordConfig], public_ips: &PublicIps) {
+        let mut tasks = JoinSet::new();
+        for record in records {
+            let Some(health_check) = &record.health_check else {
+                continue;
+            };
+            let fqdn = propagation::record_fqdn(&record.name, &record.zone);
+            for record_set in record.record_sets(public_ips) {
+                for target in record_set.targets {
+                    let health_check = health_check.clone();
+                    let fqdn = fqdn.clone();
+                    tasks.spawn(async move {
+                        let result = match target.parse::<IpAddr>() {
+                            Ok(address) => {
+                                health_check.check(address).await.map_err(|e| e.to_string())
+                            }
+                            Err(e) => Err(e.to_string()),
+                        };
+                        (fqdn, target, health_check.failures, result)
+                    });
+                }
+            }
+        }
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok((fqdn, target, failures, result)) => {
+                    if let Err(e) = &result {
+                        warn!("Health check of {} of {} failed: {}", target, fqdn, e);
+                    }
+                    self.health_checks
+                        .record_result(&fqdn, &target, failures, result.is_ok());
+                }
+                Err(e) => error!("Health check task failed: {}", e),
+            }
+        }
+    }
+
+    /// Returns the record sets of `record` without its withdrawn addresses,
+    /// or with its fallback addresses when they are all withdrawn.
+    fn published_record_sets(
+        &self,
+        record: &RecordConfig,
+        public_ips: &PublicIps,
+    ) -> Vec<RecordSet> {
+        let mut record_sets = record.record_sets(public_ips);
+        let Some(health_check) = &record.health_check else {
+            return record_sets;
+        };
+        let fqdn = propagation::record_fqdn(&record.name, &record.zone);
+        for record_set in &mut record_sets {
+            record_set
+                .targets
+                .retain(|target| !self.health_checks.is_withdrawn(&fqdn, target));
+            if record_set.targets.is_empty() {
+                record_set.targets = health_check
+                    .fallback
+                    .iter()
+                    .filter(|address| AddressFamily::of(address) == record_set.family)
+                    .map(|address| address.to_string())
+                    .collect();
+            }
+        }
+        record_sets
+    }
+
+    /// Reconciles the A and AAAA record sets of `record` for the families it
+    /// publishes.
+    async fn reconcile_record(
+        &self,
+        record: &RecordConfig,
+        dns_records: &[DnsRecord],
+        public_ips: &PublicIps,
+    ) -> Reconciliation {
+        let zone = record.zone.as_str();
+        let record_name = record.name.as_str();
+        let ownership = self
+            .owner_id
+            .as_deref()
+            .map(|id| ownership::get_record_ownership(dns_records, record_name, id));
+        match &ownership {
+            Some(Ownership::OwnedByOther(other_owner_id)) => {
+                warn!(
+                    "Record {} is owned by instance {:?}, leaving it untouched",
+                    record_name, other_owner_id
+                );
+                return Reconciliation::default();
+            }
+            Some(Ownership::Unowned)
+                if self.unowned_records_policy == UnownedRecordsPolicy::Report =>
+            {
+                warn!(
+                    "Record {} was not created by this instance, leaving it untouched",
+                    record_name
+                );
+                return Reconciliation::default();
+            }
+            _ => {}
+        }
+
+        let record_sets = self.published_record_sets(record, public_ips);
+        // The marker is created first, so records we create are never left
+        // without it, to be taken for someone else's at the next cycle
+        if let (Some(Ownership::Unowned | Ownership::Free), Some(id)) = (&ownership, &self.owner_id)
+        {
+            info!("Marking record {} as owned by {:?}", record_name, id);
+            let _permit = self.api_permits.acquire().await;
+            match dns_record::update_dns_record(
+                &self.client,
+                &self.infomaniak_zones_api_url,
+                &ownership::ownership_marker(id, record_name),
+                None,
+                zone,
+                &ownership::ownership_record_name(record_name),
+                "TXT",
+            )
+            .await
+            {
+                Ok(result) => info!("Ownership marker created: {:?}", result),
+                Err(e) => {
+                    self.note_error(&*e);
+                    error!(
+                        "Error creating the ownership marker of {}, not updating it: {}",
+                        record_name, e
+                    );
+                    return Reconciliation::failed(
+                        record_sets
+                            .iter()
+                            .map(|record_set| record_set.family)
+                            .collect(),
+                    );
+                }
+            }
+        }
+
+        info!("Updating record: {:?}", record_name);
+        let mut reconciliation = Reconciliation::default();
+        for record_set in record_sets {
+            let outcome = self
+                .reconcile_address_set(
+                    zone,
+                    record_name,
+                    record_set.family,
+                    &record_set.targets,
+                    dns_records,
+                )
+                .await;
+            reconciliation.record(record_set.family, outcome);
+        }
+        reconciliation
+    }
+
+    /// Makes the `family` records of `record_name` point to exactly `targets`,
+    /// creating the missing ones before deleting the stale ones, so the name
+    /// always resolves.
+    async fn reconcile_address_set(
+        &self,
+        zone: &str,
+        record_name: &str,
+        family: AddressFamily,
+        targets: &[String],
+        dns_records: &[DnsRecord],
+    ) -> UpdateOutcome {
+        let record_type = family.record_type();
+        let existing_records: Vec<&DnsRecord> = dns_records
+            .iter()
+            .filter(|record| record.source == record_name && record.record_type == record_type)
+            .collect();
+        info!(
+            "Found {} records for {}: {:?}",
+            record_type, record_name, existing_records
+        );
+
+        let mut kept_targets: Vec<&str> = Vec::new();
+        let mut stale_records = Vec::new();
+        for record in &existing_records {
+            if targets.contains(&record.target) && !kept_targets.contains(&record.target.as_str()) {
+                kept_targets.push(&record.target);
+            } else {
+                stale_records.push(*record);
+            }
+        }
+        let missing_targets: Vec<&String> = targets
+            .iter()
+            .filter(|target| !kept_targets.contains(&target.as_str()))
+            .collect();
+        if missing_targets.is_empty() && stale_records.is_empty() {
+            info!("DNS records for {} are already up to date.", family);
+            return UpdateOutcome::Done;
+        }
+        let record_key = format!(
+            "{} {}",
+            propagation::record_fqdn(record_name, zone),
+            record_type
+        );
+        if let Err(limited) = self
+            .update_rate_limit
+            .try_update(&record_key, Instant::now())
+        {
+            warn!("Holding back the update of {}: {}", record_key, limited);
+            return UpdateOutcome::Deferred(limited.retry_in);
+        }
+
+        let mut succeeded = true;
+        for target in missing_targets {
+            info!(
+                "Adding {} to the {} records of {}",
+                target, family, record_name
+            );
+            let _permit = self.api_permits.acquire().await;
+            match dns_record::update_dns_record(
+                &self.client,
+                &self.infomaniak_zones_api_url,
+                target,
+                None,
+                zone,
+                record_name,
+                record_type,
+            )
+            .await
+            {
+                Ok(result) => info!("Update {} successful: {:?}", family, result),
+                Err(e) => {
+                    self.note_error(&*e);
+                    error!("Error updating DNS for {}: {}", family, e);
+                    succeeded = false;
+                }
+            }
+        }
+
+        // Keep the old addresses rather than leaving the name without any.
+        if succeeded {
+            for record in &stale_records {
+                info!(
+                    "Removing {} from the {} records of {}",
+                    record.target, family, record_name
+                );
+                let _permit = self.api_permits.acquire().await;
+                if let Err(e) = dns_record::delete_dns_record(
+                    &self.client,
+                    &self.infomaniak_zones_api_url,
+                    zone,
+                    &record.id.to_string(),
+                )
+                .await
+                {
+                    self.note_error(&*e);
+                    error!("Error updating DNS for {}: {}", family, e);
+                    succeeded = false;
+                }
+            }
+        }
+        self.metrics.family(family).record_update(succeeded);
+        if succeeded {
+            if let Some(verification) = &self.verification {
+                let previous = stale_records
+                    .iter()
+                    .filter(|record| !targets.contains(&record.target))
+                    .map(|record| record.target.clone())
+                    .collect();
+                propagation::spawn_verification(
+                    verification,
+                    zone,
+                    record_name,
+                    record_type,
+                    targets.to_vec(),
+                    previous,
+                );
+            }
+            UpdateOutcome::Done
+        } else {
+            UpdateOutcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::{HealthCheckConfig, Probe};
+    use mockito::{Matcher, Server};
+    use serde_json::json;
+
+    fn reconciler(url: &str, owner_id: Option<&str>) -> Arc<Reconciler> {
+        Arc::new(Reconciler {
+            client: Client::new(),
+            infomaniak_zones_api_url: url.to_string(),
+            owner_id: owner_id.map(String::from),
+            unowned_records_policy: UnownedRecordsPolicy::Report,
+            verification: None,
+            drift_check: None,
+            health_checks: HealthChecks::default(),
+            update_rate_limit: UpdateRateLimit::default(),
+            api_permits: Semaphore::new(2),
+            metrics: Arc::new(Metrics::default()),
+            token_rejected: AtomicBool::new(false),
+        })
+    }
+
+    fn record(name: &str, families: &[AddressFamily]) -> RecordConfig {
+        RecordConfig {
+            name: name.to_string(),
+            zone: "test-zone".to_string(),
+            families: families.to_vec(),
+            failover: None,
+            wans: Vec::new(),
+            addresses: Vec::new(),
+            health_check: None,
+        }
+    }
+
+    fn record_json(id: u64, source: &str, record_type: &str, target: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "source": source,
+            "target": target,
+            "ttl": 300,
+            "type": record_type,
+            "updated_at": 1234567890
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_updates_records_concurrently() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        record_json(1, "up-to-date", "A", "192.168.1.1"),
+                        record_json(2, "outdated", "A", "192.168.1.2"),
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let delete_mock = server
+            .mock("DELETE", "/test-zone/records/2")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({"target": "192.168.1.1"})))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": record_json(3, "outdated", "A", "192.168.1.1")}).to_string())
+            .expect(2)
+            .create_async()
+            .await;
+
+        let result = reconciler(&server.url(), None)
+            .reconcile_zone(
+                "test-zone",
+                &[
+                    record("up-to-date", &[AddressFamily::Ipv4]),
+                    record("outdated", &[AddressFamily::Ipv4]),
+                    record("missing", &[AddressFamily::Ipv4]),
+                ],
+                &PublicIps::new(Some("192.168.1.1".parse().unwrap()), None),
+            )
+            .await;
+
+        assert_eq!(result.unwrap(), Reconciliation::default());
+        list_mock.assert_async().await;
+        delete_mock.assert_async().await;
+        create_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_leaves_unowned_records_untouched() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA&filter[types][]=TXT",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [record_json(1, "manual", "A", "192.168.1.2")]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let delete_mock = server
+            .mock("DELETE", Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let result = reconciler(&server.url(), Some("home"))
+            .reconcile_zone(
+                "test-zone",
+                &[record("manual", &[AddressFamily::Ipv4])],
+                &PublicIps::new(Some("192.168.1.1".parse().unwrap()), None),
+            )
+            .await;
+
+        assert!(result.is_ok());
+        list_mock.assert_async().await;
+        delete_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_skips_records_without_marker() {
+        let mut server = Server::new_async().await;
+        let _list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA&filter[types][]=TXT",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": []}).to_string())
+            .create_async()
+            .await;
+        let marker_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({
+                "source": "_infomaniak-dyndns.www",
+                "type": "TXT"
+            })))
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({"type": "A"})))
+            .expect(0)
+            .create_async()
+            .await;
+
+        let result = reconciler(&server.url(), Some("home"))
+            .reconcile_zone(
+                "test-zone",
+                &[record("www", &[AddressFamily::Ipv4])],
+                &PublicIps::new(Some("192.168.1.1".parse().unwrap()), None),
+            )
+            .await;
+
+        assert_eq!(result.unwrap().failed, vec![AddressFamily::Ipv4]);
+        marker_mock.assert_async().await;
+        create_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_reports_failed_family_only() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        record_json(1, "www", "A", "192.168.1.1"),
+                        record_json(2, "www", "AAAA", "2001:db8::2"),
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .with_status(500)
+            .create_async()
+            .await;
+        let delete_mock = server
+            .mock("DELETE", Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let reconciler = reconciler(&server.url(), None);
+        let result = reconciler
+            .reconcile_zone(
+                "test-zone",
+                &[record("www", &[AddressFamily::Ipv4, AddressFamily::Ipv6])],
+                &PublicIps::new(
+                    Some("192.168.1.1".parse().unwrap()),
+                    Some("2001:db8::1".parse().unwrap()),
+                ),
+            )
+            .await;
+
+        assert_eq!(result.unwrap().failed, vec![AddressFamily::Ipv6]);
+        list_mock.assert_async().await;
+        create_mock.assert_async().await;
+        delete_mock.assert_async().await;
+        assert_eq!(
+            reconciler
+                .metrics
+                .ipv6
+                .updates_failed
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_all_only_updates_published_families() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": []}).to_string())
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({
+                "source": "v6-only",
+                "type": "AAAA"
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({"data": record_json(1, "v6-only", "AAAA", "2001:db8::1")}).to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let reconciliation = reconciler(&server.url(), None)
+            .reconcile_all(
+                &[record("v6-only", &[AddressFamily::Ipv6])],
+                &PublicIps::new(
+                    Some("192.168.1.1".parse().unwrap()),
+                    Some("2001:db8::1".parse().unwrap()),
+                ),
+            )
+            .await;
+
+        assert!(reconciliation.is_complete());
+        list_mock.assert_async().await;
+        create_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_all_reports_families_of_unlisted_zone() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let reconciliation = reconciler(&server.url(), None)
+            .reconcile_all(
+                &[record("v4-only", &[AddressFamily::Ipv4])],
+                &PublicIps::new(
+                    Some("192.168.1.1".parse().unwrap()),
+                    Some("2001:db8::1".parse().unwrap()),
+                ),
+            )
+            .await;
+
+        assert_eq!(reconciliation.failed, vec![AddressFamily::Ipv4]);
+        list_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_diffs_record_sets() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        record_json(1, "www", "A", "192.168.1.1"),
+                        record_json(2, "www", "A", "192.168.1.3"),
+                        record_json(3, "www", "A", "192.168.1.1"),
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({"target": "192.168.1.2"})))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": record_json(4, "www", "A", "192.168.1.2")}).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let delete_stale_mock = server
+            .mock("DELETE", "/test-zone/records/2")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let delete_duplicate_mock = server
+            .mock("DELETE", "/test-zone/records/3")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let public_ips = PublicIps {
+            ipv4: vec![
+                "192.168.1.1".parse().unwrap(),
+                "192.168.1.2".parse().unwrap(),
+            ],
+            ipv6: Vec::new(),
+        };
+
+        let result = reconciler(&server.url(), None)
+            .reconcile_zone(
+                "test-zone",
+                &[record("www", &[AddressFamily::Ipv4])],
+                &public_ips,
+            )
+            .await;
+
+        assert_eq!(result.unwrap(), Reconciliation::default());
+        list_mock.assert_async().await;
+        create_mock.assert_async().await;
+        delete_stale_mock.assert_async().await;
+        delete_duplicate_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_records_only_deletes_owned_records() {
+        let mut server = Server::new_async().await;
+        let _list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA&filter[types][]=TXT",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": [
+                        record_json(1, "owned", "A", "192.168.1.1"),
+                        record_json(2, "_infomaniak-dyndns.owned", "TXT", &ownership::ownership_marker("home", "owned")),
+                        record_json(3, "manual", "A", "192.168.1.1"),
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let delete_record_mock = server
+            .mock("DELETE", "/test-zone/records/1")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let delete_marker_mock = server
+            .mock("DELETE", "/test-zone/records/2")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let delete_manual_mock = server
+            .mock("DELETE", "/test-zone/records/3")
+            .expect(0)
+            .create_async()
+            .await;
+
+        reconciler(&server.url(), Some("home"))
+            .remove_records(&[
+                record("owned", &[AddressFamily::Ipv4]),
+                record("manual", &[AddressFamily::Ipv4]),
+            ])
+            .await;
+
+        delete_record_mock.assert_async().await;
+        delete_marker_mock.assert_async().await;
+        delete_manual_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_zone_defers_rate_limited_updates() {
+        let mut server = Server::new_async().await;
+        let _list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": [record_json(1, "www", "A", "192.168.1.1")]}).to_string())
+            .create_async()
+            .await;
+        let first_create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({"target": "192.168.1.2"})))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": record_json(2, "www", "A", "192.168.1.2")}).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let second_create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(json!({"target": "192.168.1.3"})))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": record_json(3, "www", "A", "192.168.1.3")}).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let _delete_mock = server
+            .mock("DELETE", "/test-zone/records/1")
+            .with_status(200)
+            .create_async()
+            .await;
+        let window = Duration::from_millis(300);
+        let mut reconciler = reconciler(&server.url(), None);
+        Arc::get_mut(&mut reconciler).unwrap().update_rate_limit =
+            UpdateRateLimit::with_window(Some(1), window);
+        let reconcile = |target: &'static str| {
+            let reconciler = Arc::clone(&reconciler);
+            async move {
+                reconciler
+                    .reconcile_zone(
+                        "test-zone",
+                        &[record("www", &[AddressFamily::Ipv4])],
+                        &PublicIps::new(Some(target.parse().unwrap()), None),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(reconcile("192.168.1.2").await, Reconciliation::default());
+        first_create_mock.assert_async().await;
+
+        // The limit is hit, the update is deferred rather than reported done
+        let reconciliation = reconcile("192.168.1.3").await;
+        assert!(reconciliation.failed.is_empty());
+        let retry_in = reconciliation.deferred(AddressFamily::Ipv4).unwrap();
+        assert!(retry_in <= window);
+        assert!(!reconciliation.is_done(AddressFamily::Ipv4));
+
+        // Once the window passed, the update goes through
+        tokio::time::sleep(retry_in).await;
+        assert_eq!(reconcile("192.168.1.3").await, Reconciliation::default());
+        second_create_mock.assert_async().await;
+    }
+
+    #[test]
+    fn test_withdrawn_addresses_are_replaced_by_fallback() {
+        let reconciler = reconciler("http://localhost", None);
+        let mut record = record("www", &[AddressFamily::Ipv4, AddressFamily::Ipv6]);
+        record.health_check = Some(HealthCheckConfig {
+            probe: Probe::Tcp { port: 443 },
+            failures: 1,
+            fallback: vec!["192.168.1.100".parse().unwrap()],
+            timeout_in_seconds: None,
+        });
+        let public_ips = PublicIps::new(
+            Some("192.168.1.1".parse().unwrap()),
+            Some("2001:db8::1".parse().unwrap()),
+        );
+        for target in ["192.168.1.1", "2001:db8::1"] {
+            reconciler
+                .health_checks
+                .record_result("www.test-zone", target, 1, false);
+        }
+
+        let record_sets = reconciler.published_record_sets(&record, &public_ips);
+
+        assert_eq!(
+            record_sets,
+            vec![
+                RecordSet {
+                    name: "www".to_string(),
+                    family: AddressFamily::Ipv4,
+                    targets: vec!["192.168.1.100".to_string()],
+                },
+                RecordSet {
+                    name: "www".to_string(),
+                    family: AddressFamily::Ipv6,
+                    targets: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_all_reports_refused_token() {
+        let mut server = Server::new_async().await;
+        let _list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({"data": []}).to_string())
+            .create_async()
+            .await;
+        let _create_mock = server
+            .mock("POST", "/test-zone/records")
+            .with_status(401)
+            .create_async()
+            .await;
+        let reconciler = reconciler(&server.url(), None);
+
+        let reconciliation = reconciler
+            .reconcile_all(
+                &[record("www", &[AddressFamily::Ipv4])],
+                &PublicIps::new(Some("192.168.1.1".parse().unwrap()), None),
+            )
+            .await;
+
+        assert_eq!(reconciliation.failed, vec![AddressFamily::Ipv4]);
+        assert!(reconciler.take_token_rejection());
+        assert!(!reconciler.take_token_rejection());
+    }
+}
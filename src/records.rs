@@ -0,0 +1,430 @@
+use crate::address_family::{AddressFamily, PublicIps};
+use crate::health_check::HealthCheckConfig;
+use crate::wan::FailoverWans;
+use config::{Config, ConfigError};
+use serde::Deserialize;
+use std::error::Error;
+use std::net::IpAddr;
+
+/// A record managed by the daemon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordConfig {
+    pub name: String,
+    pub zone: String,
+    /// Address families published by the record.
+    pub families: Vec<AddressFamily>,
+    /// Uplinks the record is published through, when it fails over between
+    /// two WANs instead of using the public IPs of the host.
+    pub failover: Option<FailoverWans>,
+    /// Uplinks whose public IPs are all published by the record.
+    pub wans: Vec<String>,
+    /// Fixed addresses published along with the public IPs.
+    pub addresses: Vec<IpAddr>,
+    /// Check of the service behind the published addresses.
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+/// Addresses of a family a name must point to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordSet {
+    pub name: String,
+    pub family: AddressFamily,
+    /// Targets of the records, the name has no record of the family when empty.
+    pub targets: Vec<String>,
+}
+
+impl RecordConfig {
+    pub fn publishes(&self, family: AddressFamily) -> bool {
+        self.families.contains(&family)
+    }
+
+    /// Tells whether the record is published through WANs rather than the
+    /// public IPs of the host.
+    pub fn uses_wans(&self) -> bool {
+        self.failover.is_some() || !self.wans.is_empty()
+    }
+
+    /// Tells whether the record is published through the WAN named `wan`.
+    pub fn uses_wan(&self, wan: &str) -> bool {
+        self.wans.iter().any(|name| name == wan)
+            || self
+                .failover
+                .as_ref()
+                .is_some_and(|failover| failover.primary == wan || failover.secondary == wan)
+    }
+
+    /// Returns the targets the `family` records of the set must point to.
+    ///
+    /// The fixed addresses are only added to the public IPs, so the set is
+    /// empty, and left untouched, when the public IPs of `family` are unknown.
+    pub fn targets(&self, family: AddressFamily, public_ips: &PublicIps) -> Vec<String> {
+        let mut targets = public_ips.targets(family);
+        if !self.publishes(family) || targets.is_empty() {
+            return Vec::new();
+        }
+        for address in &self.addresses {
+            let target = address.to_string();
+            if AddressFamily::of(address) == family && !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        targets
+    }
+
+    /// Returns the record sets of the families whose public IPs are known.
+    pub fn record_sets(&self, public_ips: &PublicIps) -> Vec<RecordSet> {
+        [AddressFamily::Ipv4, AddressFamily::Ipv6]
+            .into_iter()
+            .filter_map(|family| {
+                let targets = self.targets(family, public_ips);
+                (!targets.is_empty()).then(|| RecordSet {
+                    name: self.name.clone(),
+                    family,
+                    targets,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Record as written in the `records` list of the configuration file.
+#[derive(Debug, Deserialize)]
+struct RecordEntry {
+    name: String,
+    zone: Option<String>,
+    families: Option<Vec<AddressFamily>>,
+    primary_wan: Option<String>,
+    secondary_wan: Option<String>,
+    #[serde(default)]
+    wans: Vec<String>,
+    #[serde(default)]
+    addresses: Vec<IpAddr>,
+    health_check: Option<HealthCheckConfig>,
+}
+
+fn parse_family(family: &str) -> Result<AddressFamily, Box<dyn Error>> {
+    match family.trim().to_lowercase().as_str() {
+        "ipv4" | "a" => Ok(AddressFamily::Ipv4),
+        "ipv6" | "aaaa" => Ok(AddressFamily::Ipv6),
+        _ => Err(format!(
+            "Invalid address family {:?}, expected \"ipv4\" or \"ipv6\"",
+            family
+        )
+        .into()),
+    }
+}
+
+fn check_families(name: &str, families: &[AddressFamily]) -> Result<(), Box<dyn Error>> {
+    if families.is_empty() {
+        return Err(format!("Record {} must publish at least one address family", name).into());
+    }
+    Ok(())
+}
+
+/// Parses records separated by `,`, each optionally followed by the
+/// families it publishes, like `*:ipv4+ipv6,www:ipv4,api:ipv6`.
+pub fn parse_records_name(
+    records_name: &str,
+    zone: &str,
+    default_families: &[AddressFamily],
+) -> Result<Vec<RecordConfig>, Box<dyn Error>> {
+    records_name
+        .split(',')
+        .map(|record| {
+            let (name, families) = match record.split_once(':') {
+                Some((name, families)) => (
+                    name,
+                    families
+                        .split('+')
+                        .map(parse_family)
+                        .collect::<Result<Vec<AddressFamily>, Box<dyn Error>>>()?,
+                ),
+                None => (record, default_families.to_vec()),
+            };
+            check_families(name, &families)?;
+            Ok(RecordConfig {
+                name: name.to_string(),
+                zone: zone.to_string(),
+                families,
+                failover: None,
+                wans: Vec::new(),
+                addresses: Vec::new(),
+                health_check: None,
+            })
+        })
+        .collect()
+}
+
+/// Loads the managed records from the `records` list of the configuration
+/// file, or from `records_name`.
+pub fn load_records(
+    config: &Config,
+    default_zone: &str,
+    default_families: &[AddressFamily],
+) -> Result<Vec<RecordConfig>, Box<dyn Error>> {
+    let records = match config.get::<Vec<RecordEntry>>("records") {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|entry| {
+                let families = entry.families.unwrap_or_else(|| default_families.to_vec());
+                check_families(&entry.name, &families)?;
+                let failover = match (entry.primary_wan, entry.secondary_wan) {
+                    (Some(primary), Some(secondary)) => Some(FailoverWans { primary, secondary }),
+                    (None, None) => None,
+                    _ => {
+                        return Err(format!(
+                            "Record {} must have both a primary_wan and a secondary_wan",
+                            entry.name
+                        )
+                        .into());
+                    }
+                };
+                if failover.is_some() && !entry.wans.is_empty() {
+                    return Err(format!(
+                        "Record {} can't have both wans and a primary_wan",
+                        entry.name
+                    )
+                    .into());
+                }
+                Ok(RecordConfig {
+                    name: entry.name,
+                    zone: entry.zone.unwrap_or_else(|| default_zone.to_string()),
+                    families,
+                    failover,
+                    wans: entry.wans,
+                    addresses: entry.addresses,
+                    health_check: entry.health_check,
+                })
+            })
+            .collect::<Result<Vec<RecordConfig>, Box<dyn Error>>>()?,
+        Err(ConfigError::NotFound(_)) => {
+            let records_name = config
+                .get_string("records_name")
+                .map_err(|_| "records_name or records must be set")?;
+            parse_records_name(&records_name, default_zone, default_families)?
+        }
+        Err(e) => return Err(format!("Invalid records: {}", e).into()),
+    };
+    check_single_writer(&records)?;
+    Ok(records)
+}
+
+/// Checks no two records publish the same family of the same name: each one
+/// reconciles the whole set, and would remove the addresses of the other.
+fn check_single_writer(records: &[RecordConfig]) -> Result<(), Box<dyn Error>> {
+    for (index, record) in records.iter().enumerate() {
+        let shared_family = records[..index].iter().find_map(|other| {
+            (other.name == record.name && other.zone == record.zone)
+                .then(|| {
+                    record
+                        .families
+                        .iter()
+                        .find(|family| other.families.contains(family))
+                })
+                .flatten()
+        });
+        if let Some(family) = shared_family {
+            return Err(format!(
+                "Record {} of {} publishes its {} addresses more than once",
+                record.name, record.zone, family
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Tells whether at least one record publishes addresses of `family`.
+pub fn any_publishes(records: &[RecordConfig], family: AddressFamily) -> bool {
+    records.iter().any(|record| record.publishes(family))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{File, FileFormat};
+
+    #[test]
+    fn test_parse_records_name_with_families() {
+        let records = parse_records_name(
+            "*,www:ipv4,api:ipv6,both:ipv4+ipv6",
+            "example.com",
+            &[AddressFamily::Ipv4],
+        )
+        .unwrap();
+
+        let families: Vec<(&str, &[AddressFamily])> = records
+            .iter()
+            .map(|record| (record.name.as_str(), record.families.as_slice()))
+            .collect();
+        assert_eq!(
+            families,
+            vec![
+                ("*", &[AddressFamily::Ipv4][..]),
+                ("www", &[AddressFamily::Ipv4][..]),
+                ("api", &[AddressFamily::Ipv6][..]),
+                ("both", &[AddressFamily::Ipv4, AddressFamily::Ipv6][..]),
+            ]
+        );
+        assert!(records.iter().all(|record| record.zone == "example.com"));
+    }
+
+    #[test]
+    fn test_parse_records_name_invalid_family() {
+        assert!(parse_records_name("www:ipv5", "example.com", &[AddressFamily::Ipv4]).is_err());
+        assert!(parse_records_name("www", "example.com", &[]).is_err());
+    }
+
+    #[test]
+    fn test_load_records_from_file() {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                [[records]]
+                name = "*"
+                families = ["ipv6"]
+                addresses = ["2001:db8::10"]
+
+                [[records]]
+                name = "www"
+                zone = "example.org"
+                primary_wan = "fiber"
+                secondary_wan = "lte"
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        let records = load_records(&config, "example.com", &[AddressFamily::Ipv4]).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                RecordConfig {
+                    name: "*".to_string(),
+                    zone: "example.com".to_string(),
+                    families: vec![AddressFamily::Ipv6],
+                    failover: None,
+                    wans: Vec::new(),
+                    addresses: vec!["2001:db8::10".parse().unwrap()],
+                    health_check: None,
+                },
+                RecordConfig {
+                    name: "www".to_string(),
+                    zone: "example.org".to_string(),
+                    families: vec![AddressFamily::Ipv4],
+                    failover: Some(FailoverWans {
+                        primary: "fiber".to_string(),
+                        secondary: "lte".to_string(),
+                    }),
+                    wans: Vec::new(),
+                    addresses: Vec::new(),
+                    health_check: None,
+                },
+            ]
+        );
+        assert!(!any_publishes(&records[..1], AddressFamily::Ipv4));
+        assert!(any_publishes(&records, AddressFamily::Ipv4));
+    }
+
+    #[test]
+    fn test_record_targets() {
+        let record = RecordConfig {
+            name: "www".to_string(),
+            zone: "example.com".to_string(),
+            families: vec![AddressFamily::Ipv4],
+            failover: None,
+            wans: Vec::new(),
+            addresses: vec![
+                "192.0.2.10".parse().unwrap(),
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::10".parse().unwrap(),
+            ],
+            health_check: None,
+        };
+        let public_ips = PublicIps::new(Some("192.0.2.1".parse().unwrap()), None);
+
+        assert_eq!(
+            record.targets(AddressFamily::Ipv4, &public_ips),
+            vec!["192.0.2.1", "192.0.2.10"]
+        );
+        assert!(record.targets(AddressFamily::Ipv6, &public_ips).is_empty());
+        assert!(
+            record
+                .targets(AddressFamily::Ipv4, &PublicIps::default())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_load_records_requires_both_wans() {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                [[records]]
+                name = "www"
+                primary_wan = "fiber"
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        assert!(load_records(&config, "example.com", &[AddressFamily::Ipv4]).is_err());
+    }
+
+    #[test]
+    fn test_load_records_rejects_malformed_records() {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                records_name = "www"
+
+                [[records]]
+                families = ["ipv4"]
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        assert!(load_records(&config, "example.com", &[AddressFamily::Ipv4]).is_err());
+    }
+
+    #[test]
+    fn test_load_records_rejects_several_writers() {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                [[records]]
+                name = "www"
+                families = ["ipv4"]
+                addresses = ["192.0.2.10"]
+
+                [[records]]
+                name = "www"
+                families = ["ipv4", "ipv6"]
+                wans = ["fiber"]
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        assert!(load_records(&config, "example.com", &[AddressFamily::Ipv4]).is_err());
+        assert!(parse_records_name("www:ipv4,www:ipv6", "example.com", &[]).is_ok());
+        assert!(
+            load_records(
+                &Config::builder()
+                    .set_override("records_name", "*,www,*")
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+                "example.com",
+                &[AddressFamily::Ipv4]
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::records::RecordConfig;
+use crate::signals::Signal;
+use config::Config;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time;
+
+const CONFIG_FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Extensions `config::File::with_name` tries when the name has none.
+const CONFIG_FILE_EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+
+/// Returns the path of the configuration file `name`, with or without its
+/// extension.
+pub fn config_file_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return Some(path.to_path_buf());
+    }
+    CONFIG_FILE_EXTENSIONS
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls the configuration file at `path` and asks for a reload when it
+/// changes.
+pub fn watch(path: PathBuf, sender: mpsc::UnboundedSender<Signal>) {
+    tokio::spawn(async move {
+        let mut version = file_version(&path);
+        loop {
+            time::sleep(CONFIG_FILE_POLL_INTERVAL).await;
+            let new_version = file_version(&path);
+            if new_version == version {
+                continue;
+            }
+            version = new_version;
+            if version.is_none() {
+                warn!("Configuration file {} disappeared", path.display());
+                continue;
+            }
+            info!("Configuration file {} changed", path.display());
+            if sender.send(Signal::Reload).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Returns the top-level settings whose value differ between `old` and
+/// `new`. Values are left out since some are secrets.
+pub fn changed_settings(old: &Config, new: &Config) -> Vec<String> {
+    let to_json = |config: &Config| {
+        config
+            .clone()
+            .try_deserialize::<serde_json::Map<String, serde_json::Value>>()
+            .unwrap_or_default()
+    };
+    let (old, new) = (to_json(old), to_json(new));
+    let mut changed: Vec<String> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+fn same_record(record: &RecordConfig, other: &RecordConfig) -> bool {
+    record.name == other.name && record.zone == other.zone
+}
+
+/// Returns the records of `records` missing from `other`.
+pub fn missing_records(records: &[RecordConfig], other: &[RecordConfig]) -> Vec<RecordConfig> {
+    records
+        .iter()
+        .filter(|record| !other.iter().any(|other| same_record(record, other)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_family::AddressFamily;
+
+    fn record(name: &str) -> RecordConfig {
+        RecordConfig {
+            name: name.to_string(),
+            zone: "example.com".to_string(),
+            families: vec![AddressFamily::Ipv4],
+            failover: None,
+            wans: Vec::new(),
+            addresses: Vec::new(),
+            health_check: None,
+        }
+    }
+
+    fn config(records_name: &str, token: &str) -> Config {
+        Config::builder()
+            .set_override("records_name", records_name)
+            .unwrap()
+            .set_override("infomaniak_api_token", token)
+            .unwrap()
+            .set_override("time_between_updates_in_seconds", 60)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_changed_settings() {
+        assert_eq!(
+            changed_settings(&config("*,www", "a"), &config("*,www,api", "b")),
+            vec!["infomaniak_api_token", "records_name"]
+        );
+        assert!(changed_settings(&config("*", "a"), &config("*", "a")).is_empty());
+    }
+
+    #[test]
+    fn test_missing_records() {
+        let old = vec![record("*"), record("www")];
+        let new = vec![record("*"), record("api")];
+
+        let removed = missing_records(&old, &new);
+        let added = missing_records(&new, &old);
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].name, "www");
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].name, "api");
+    }
+
+    #[test]
+    fn test_config_file_path() {
+        let path = std::env::temp_dir().join(format!(
+            "infomaniak-dyndns-wildcard-{}",
+            crate::random::random_u64()
+        ));
+        let file = path.with_extension("toml");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(config_file_path(path.to_str().unwrap()), Some(file.clone()));
+        assert_eq!(config_file_path(file.to_str().unwrap()), Some(file.clone()));
+        fs::remove_file(&file).unwrap();
+        assert_eq!(config_file_path(path.to_str().unwrap()), None);
+    }
+}
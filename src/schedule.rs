@@ -0,0 +1,432 @@
+use crate::random;
+use config::Config;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 86400;
+/// Cron expressions matching no time in this many days are refused.
+const MAX_CRON_SEARCH_IN_DAYS: u64 = 366 * 5;
+
+/// How the time between two cycles is counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleMode {
+    /// The interval starts when the previous cycle ends.
+    FixedDelay,
+    /// Cycles start at a fixed interval, whatever their duration.
+    FixedRate,
+}
+
+impl std::str::FromStr for ScheduleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-delay" => Ok(ScheduleMode::FixedDelay),
+            "fixed-rate" => Ok(ScheduleMode::FixedRate),
+            _ => Err(format!(
+                "Invalid schedule mode {:?}, expected \"fixed-delay\" or \"fixed-rate\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Schedule of the cycles of the main loop.
+#[derive(Debug)]
+pub struct Schedule {
+    pub interval: Duration,
+    pub mode: ScheduleMode,
+    /// Maximum random delay added to each sleep, so instances started
+    /// together don't call the API in lockstep.
+    pub jitter: Duration,
+    next_cycle: Option<Instant>,
+}
+
+impl Schedule {
+    pub fn new(interval: Duration, mode: ScheduleMode, jitter: Duration) -> Schedule {
+        Schedule {
+            interval,
+            mode,
+            jitter,
+            next_cycle: None,
+        }
+    }
+
+    /// Returns how long to sleep at `now` after the cycle started at
+    /// `cycle_start`, without the jitter.
+    fn delay(&mut self, cycle_start: Instant, now: Instant) -> Duration {
+        match self.mode {
+            ScheduleMode::FixedDelay => self.interval,
+            ScheduleMode::FixedRate => {
+                let next_cycle = self.next_cycle.unwrap_or(cycle_start) + self.interval;
+                // Cycles running late are skipped rather than run back to back
+                let next_cycle = if next_cycle < now {
+                    now + self.interval
+                        - Duration::from_nanos(
+                            ((now - next_cycle).as_nanos() % self.interval.as_nanos().max(1))
+                                as u64,
+                        )
+                } else {
+                    next_cycle
+                };
+                self.next_cycle = Some(next_cycle);
+                next_cycle - now
+            }
+        }
+    }
+
+    /// Returns how long to sleep after the cycle started at `cycle_start`.
+    pub fn sleep_duration(&mut self, cycle_start: Instant) -> Duration {
+        let jitter_in_millis = self.jitter.as_millis() as u64;
+        let jitter = match jitter_in_millis {
+            0 => Duration::ZERO,
+            _ => Duration::from_millis(random::random_u64() % (jitter_in_millis + 1)),
+        };
+        self.delay(cycle_start, Instant::now()) + jitter
+    }
+}
+
+/// Set of allowed values of a cron field, as a bit mask.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CronField {
+    values: u64,
+    /// Whether the field is `*`, which matters for days.
+    any: bool,
+}
+
+impl CronField {
+    fn parse(field: &str, min: u32, max: u32) -> Result<CronField, String> {
+        let invalid = || format!("Invalid cron field {:?}", field);
+        let mut values = 0u64;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (
+                        start.parse::<u32>().map_err(|_| invalid())?,
+                        end.parse::<u32>().map_err(|_| invalid())?,
+                    ),
+                    None => {
+                        let start = range.parse::<u32>().map_err(|_| invalid())?;
+                        // `5/15` means from 5 to the maximum
+                        (start, if part.contains('/') { max } else { start })
+                    }
+                },
+            };
+            if step == 0 || start < min || end > max || start > end {
+                return Err(invalid());
+            }
+            for value in (start..=end).step_by(step as usize) {
+                values |= 1 << value;
+            }
+        }
+        Ok(CronField {
+            values,
+            any: field == "*",
+        })
+    }
+
+    fn matches(&self, value: u64) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+/// Cron expression of 5 fields (minute, hour, day of month, month and day of
+/// week), evaluated in UTC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: CronField,
+    hours: CronField,
+    days: CronField,
+    months: CronField,
+    weekdays: CronField,
+}
+
+/// Returns the year, month and day of `days` since the epoch.
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl Cron {
+    fn day_matches(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch as i64);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        if !self.months.matches(month) {
+            return false;
+        }
+        // Like cron, a day matches either field when both are restricted
+        match (self.days.any, self.weekdays.any) {
+            (false, false) => self.days.matches(day) || self.weekdays.matches(weekday),
+            _ => self.days.matches(day) && self.weekdays.matches(weekday),
+        }
+    }
+
+    /// Returns the first time matching the expression strictly after `time`.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        // Start at the next minute
+        let start_minute = seconds / 60 + 1;
+        let start_day = start_minute * 60 / SECONDS_PER_DAY;
+        for day in start_day..start_day + MAX_CRON_SEARCH_IN_DAYS {
+            if !self.day_matches(day) {
+                continue;
+            }
+            for minute_of_day in 0..24 * 60 {
+                let minute = day * 24 * 60 + minute_of_day;
+                if minute >= start_minute
+                    && self.hours.matches(minute_of_day / 60)
+                    && self.minutes.matches(minute_of_day % 60)
+                {
+                    return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl std::str::FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(format!(
+                "Invalid cron expression {:?}, expected 5 fields",
+                s
+            ));
+        };
+        let mut weekdays = CronField::parse(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays.matches(7) {
+            weekdays.values |= 1;
+        }
+        let cron = Cron {
+            minutes: CronField::parse(minutes, 0, 59)?,
+            hours: CronField::parse(hours, 0, 23)?,
+            days: CronField::parse(days, 1, 31)?,
+            months: CronField::parse(months, 1, 12)?,
+            weekdays,
+        };
+        if cron.next_after(UNIX_EPOCH).is_none() {
+            return Err(format!("Cron expression {:?} never matches", s));
+        }
+        Ok(cron)
+    }
+}
+
+/// When the records are fully verified through the API. In between, cycles
+/// only check the public IPs and reconcile when they change.
+#[derive(Debug)]
+pub struct FullVerification {
+    interval: Option<Duration>,
+    cron: Option<Cron>,
+    last: Option<SystemTime>,
+}
+
+impl FullVerification {
+    pub fn new(interval: Option<Duration>, cron: Option<Cron>) -> FullVerification {
+        FullVerification {
+            interval,
+            cron,
+            last: None,
+        }
+    }
+
+    /// Reads `full_verification_interval_in_seconds` and
+    /// `full_verification_cron` from `config`.
+    pub fn from_config(config: &Config) -> Result<FullVerification, Box<dyn Error>> {
+        let interval = config
+            .get::<u64>("full_verification_interval_in_seconds")
+            .ok()
+            .map(Duration::from_secs);
+        let cron = match config.get_string("full_verification_cron") {
+            Ok(cron) => Some(cron.parse::<Cron>()?),
+            Err(_) => None,
+        };
+        Ok(FullVerification::new(interval, cron))
+    }
+
+    /// Tells whether cycles only reconcile when the public IPs change.
+    pub fn is_scheduled(&self) -> bool {
+        self.interval.is_some() || self.cron.is_some()
+    }
+
+    /// Tells whether a full verification is due at `now`. It always is when
+    /// none is scheduled, and at the first cycle.
+    pub fn is_due(&self, now: SystemTime) -> bool {
+        let Some(last) = self.last else {
+            return true;
+        };
+        if !self.is_scheduled() {
+            return true;
+        }
+        let interval_elapsed = self.interval.is_some_and(|interval| {
+            now.duration_since(last)
+                .is_ok_and(|elapsed| elapsed >= interval)
+        });
+        let cron_matched = self
+            .cron
+            .as_ref()
+            .and_then(|cron| cron.next_after(last))
+            .is_some_and(|next| next <= now);
+        interval_elapsed || cron_matched
+    }
+
+    /// Keeps the time of the last full verification done with `previous`.
+    pub fn inherit(&mut self, previous: &FullVerification) {
+        self.last = previous.last;
+    }
+
+    pub fn record_done(&mut self, now: SystemTime) {
+        self.last = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2024-02-29
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        // 2024-01-01T00:00:00Z, a Monday
+        let monday = 1704067200;
+        let nightly = "0 3 * * *".parse::<Cron>().unwrap();
+        assert_eq!(
+            nightly.next_after(time(monday)),
+            Some(time(monday + 3 * 3600))
+        );
+        assert_eq!(
+            nightly.next_after(time(monday + 3 * 3600)),
+            Some(time(monday + SECONDS_PER_DAY + 3 * 3600))
+        );
+
+        let every_quarter = "*/15 * * * *".parse::<Cron>().unwrap();
+        assert_eq!(
+            every_quarter.next_after(time(monday + 60)),
+            Some(time(monday + 15 * 60))
+        );
+
+        let sunday = "30 1 * * 7".parse::<Cron>().unwrap();
+        assert_eq!(
+            sunday.next_after(time(monday)),
+            Some(time(monday + 6 * SECONDS_PER_DAY + 5400))
+        );
+
+        // Either the day of month or the day of week when both are restricted
+        let first_or_wednesday = "0 0 1 * 3".parse::<Cron>().unwrap();
+        assert_eq!(
+            first_or_wednesday.next_after(time(monday)),
+            Some(time(monday + 2 * SECONDS_PER_DAY))
+        );
+    }
+
+    #[test]
+    fn test_invalid_cron() {
+        assert!("0 3 * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("0 0 31 2 *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn test_fixed_rate_ignores_cycle_duration() {
+        let mut schedule = Schedule::new(
+            Duration::from_secs(60),
+            ScheduleMode::FixedRate,
+            Duration::ZERO,
+        );
+        let start = Instant::now();
+
+        assert_eq!(
+            schedule.delay(start, start + Duration::from_secs(10)),
+            Duration::from_secs(50)
+        );
+        // The next cycle starts late, the one after keeps the rate
+        assert_eq!(
+            schedule.delay(
+                start + Duration::from_secs(65),
+                start + Duration::from_secs(70)
+            ),
+            Duration::from_secs(50)
+        );
+        // Missed cycles are skipped
+        assert_eq!(
+            schedule.delay(
+                start + Duration::from_secs(200),
+                start + Duration::from_secs(250)
+            ),
+            Duration::from_secs(50)
+        );
+
+        let mut schedule = Schedule::new(
+            Duration::from_secs(60),
+            ScheduleMode::FixedDelay,
+            Duration::ZERO,
+        );
+        assert_eq!(
+            schedule.delay(start, start + Duration::from_secs(10)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn test_jitter_is_bounded() {
+        let mut schedule = Schedule::new(
+            Duration::from_secs(60),
+            ScheduleMode::FixedDelay,
+            Duration::from_secs(10),
+        );
+
+        for _ in 0..20 {
+            let sleep = schedule.sleep_duration(Instant::now());
+            assert!(sleep >= Duration::from_secs(60) && sleep <= Duration::from_secs(70));
+        }
+    }
+
+    #[test]
+    fn test_full_verification_is_due() {
+        let mut full_verification = FullVerification::new(Some(Duration::from_secs(3600)), None);
+
+        assert!(full_verification.is_due(time(0)));
+        full_verification.record_done(time(0));
+        assert!(!full_verification.is_due(time(1800)));
+        assert!(full_verification.is_due(time(3600)));
+
+        let mut full_verification = FullVerification::new(None, Some("0 3 * * *".parse().unwrap()));
+        full_verification.record_done(time(0));
+        assert!(!full_verification.is_due(time(3 * 3600 - 1)));
+        assert!(full_verification.is_due(time(3 * 3600)));
+
+        let mut every_cycle = FullVerification::new(None, None);
+        every_cycle.record_done(time(0));
+        assert!(every_cycle.is_due(time(1)));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::redact;
+use config::Config;
+use log::warn;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time after which the commands printing a secret are killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads the secret `key` from `config`, either set directly or loaded from
+/// `<key>_file`, the output of `<key>_command` or the system keyring entry of
+/// service `<key>_keyring`. Returns `None` when none is set.
+pub fn get_secret(config: &Config, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let sources: Vec<(&str, String)> = ["", "_file", "_command", "_keyring"]
+        .into_iter()
+        .filter_map(|suffix| {
+            let value = config.get_string(&format!("{}{}", key, suffix)).ok()?;
+            Some((suffix, value))
+        })
+        .collect();
+    let (suffix, value) = match sources.as_slice() {
+        [] => return Ok(None),
+        [source] => source,
+        _ => {
+            return Err(format!(
+                "Only one of {} can be set",
+                sources
+                    .iter()
+                    .map(|(suffix, _)| format!("{}{}", key, suffix))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into());
+        }
+    };
+    let secret = match *suffix {
+        "_file" => read_file(Path::new(value))
+            .map_err(|e| format!("Error reading {} from {}: {}", key, value, e))?,
+        "_command" => run_command(value, COMMAND_TIMEOUT)
+            .map_err(|e| format!("Error reading {} from command {:?}: {}", key, value, e))?,
+        "_keyring" => read_keyring(value, key).map_err(|e| {
+            format!(
+                "Error reading {} from the keyring entry of service {}: {}",
+                key, value, e
+            )
+        })?,
+        _ => value.clone(),
+    };
+    if secret.is_empty() {
+        return Err(format!("{} is empty", key).into());
+    }
+    redact::register(&secret);
+    Ok(Some(secret))
+}
+
+/// Only keeps the first line, so files and outputs ending with a new line, or
+/// `pass` entries with metadata after the password, can be used.
+fn first_line(content: &str) -> String {
+    content
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+/// Reads the secret in `path`, refusing files others can write to and
+/// warning about files others can read.
+fn read_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o022 != 0 {
+            return Err(format!(
+                "writable by other users (mode {:o}), restrict it with chmod go-w",
+                mode & 0o777
+            )
+            .into());
+        }
+        if mode & 0o004 != 0 {
+            warn!(
+                "{} is readable by every user (mode {:o}), restrict it with chmod o-r",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    Ok(first_line(&fs::read_to_string(path)?))
+}
+
+/// Runs `command` and returns its exit status and standard output. It is
+/// killed after `timeout`, so a stuck command can't hang a reload.
+fn output(
+    command: &mut Command,
+    timeout: Duration,
+) -> Result<(ExitStatus, String), Box<dyn Error>> {
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let mut stdout = child.stdout.take().expect("the output is piped");
+    // Read meanwhile, so a long output can't fill the pipe and block the command
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(format!("timed out after {:?}", timeout).into());
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let output = reader.join().map_err(|_| "the output couldn't be read")??;
+    Ok((status, output))
+}
+
+fn run_command(command: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let (status, output) = output(
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit()),
+        timeout,
+    )?;
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+    Ok(first_line(&output))
+}
+
+/// Reads the password of `account` in the keyring entry of `service`, with
+/// libsecret on Linux and the keychain on macOS.
+fn read_keyring(service: &str, account: &str) -> Result<String, Box<dyn Error>> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(["find-generic-password", "-s", service, "-a", account, "-w"]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", service, "account", account]);
+        command
+    };
+    let program = command.get_program().to_owned();
+    let (status, output) = output(
+        command.stdin(Stdio::null()).stderr(Stdio::null()),
+        COMMAND_TIMEOUT,
+    )
+    .map_err(|e| format!("{:?} can't be run: {}", program, e))?;
+    if !status.success() {
+        return Err("no password found".into());
+    }
+    Ok(first_line(&output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(overrides: &[(&str, &str)]) -> Config {
+        overrides
+            .iter()
+            .fold(Config::builder(), |builder, (key, value)| {
+                builder.set_override(*key, *value).unwrap()
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_secret_from_value_or_command() {
+        assert_eq!(get_secret(&config(&[]), "token").unwrap(), None);
+        assert_eq!(
+            get_secret(&config(&[("token", "s3cr3t-v4lue")]), "token").unwrap(),
+            Some("s3cr3t-v4lue".to_string())
+        );
+        assert_eq!(
+            get_secret(
+                &config(&[(
+                    "token_command",
+                    "printf 's3cr3t-v4lue\\nurl: example.com\\n'"
+                )]),
+                "token"
+            )
+            .unwrap(),
+            Some("s3cr3t-v4lue".to_string())
+        );
+        assert!(get_secret(&config(&[("token_command", "false")]), "token").is_err());
+        // Loaded secrets are redacted from then on
+        assert_eq!(
+            redact::redact("Invalid token s3cr3t-v4lue"),
+            "Invalid token [REDACTED]"
+        );
+        assert!(
+            get_secret(
+                &config(&[
+                    ("token", "s3cr3t-v4lue"),
+                    ("token_command", "echo s3cr3t-v4lue")
+                ]),
+                "token"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_secret_command_timeout() {
+        let start = Instant::now();
+
+        assert!(run_command("sleep 10", Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            run_command("echo s3cr3t-v4lue", Duration::from_secs(5)).unwrap(),
+            "s3cr3t-v4lue"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!(
+            "infomaniak-dyndns-wildcard-secret-{}",
+            crate::random::random_u64()
+        ));
+        fs::write(&path, "s3cr3t-v4lue\n").unwrap();
+        let config = config(&[("token_file", path.to_str().unwrap())]);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(
+            get_secret(&config, "token").unwrap(),
+            Some("s3cr3t-v4lue".to_string())
+        );
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(get_secret(&config, "token").is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(get_secret(&config, "token").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use log::{error, warn};
+use std::io;
+use std::process;
+use tokio::sync::mpsc;
+
+/// What a signal asks the main loop to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// SIGTERM or SIGINT: exit once the current cycle is done.
+    Shutdown,
+    /// SIGHUP: reload the configuration.
+    Reload,
+    /// SIGUSR1: reconcile now.
+    Reconcile,
+}
+
+/// Starts listening to the signals, which are forwarded to `sender`. A
+/// second shutdown signal exits right away, without waiting for the current
+/// cycle.
+pub fn listen(sender: mpsc::UnboundedSender<Signal>) -> io::Result<()> {
+    let mut signals = listeners()?;
+    tokio::spawn(async move {
+        let mut shutting_down = false;
+        loop {
+            let signal = match signals.recv().await {
+                Ok(signal) => signal,
+                Err(e) => {
+                    error!("Error listening to signals: {}", e);
+                    return;
+                }
+            };
+            if signal == Signal::Shutdown {
+                if shutting_down {
+                    warn!("Received a second shutdown signal, exiting now");
+                    process::exit(1);
+                }
+                shutting_down = true;
+            }
+            if sender.send(signal).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+struct Listeners {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+    user_defined1: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+fn listeners() -> io::Result<Listeners> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    Ok(Listeners {
+        terminate: signal(SignalKind::terminate())?,
+        interrupt: signal(SignalKind::interrupt())?,
+        hangup: signal(SignalKind::hangup())?,
+        user_defined1: signal(SignalKind::user_defined1())?,
+    })
+}
+
+#[cfg(unix)]
+impl Listeners {
+    async fn recv(&mut self) -> io::Result<Signal> {
+        tokio::select! {
+            _ = self.terminate.recv() => Ok(Signal::Shutdown),
+            _ = self.interrupt.recv() => Ok(Signal::Shutdown),
+            _ = self.hangup.recv() => Ok(Signal::Reload),
+            _ = self.user_defined1.recv() => Ok(Signal::Reconcile),
+        }
+    }
+}
+
+/// Only Ctrl-C is supported on other platforms.
+#[cfg(not(unix))]
+struct Listeners;
+
+#[cfg(not(unix))]
+fn listeners() -> io::Result<Listeners> {
+    Ok(Listeners)
+}
+
+#[cfg(not(unix))]
+impl Listeners {
+    async fn recv(&mut self) -> io::Result<Signal> {
+        tokio::signal::ctrl_c().await?;
+        Ok(Signal::Shutdown)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn send(signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(process::id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn test_signals_are_forwarded() {
+        let (sender, mut signals) = mpsc::unbounded_channel();
+        listen(sender).unwrap();
+
+        send("USR1");
+        assert_eq!(
+            time::timeout(Duration::from_secs(5), signals.recv())
+                .await
+                .unwrap(),
+            Some(Signal::Reconcile)
+        );
+        send("HUP");
+        assert_eq!(
+            time::timeout(Duration::from_secs(5), signals.recv())
+                .await
+                .unwrap(),
+            Some(Signal::Reload)
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use crate::redact;
+use log::{debug, warn};
+use std::env;
+use std::error::Error;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::time;
+
+const USAGE: &str = "Usage: infomaniak-dyndns-wildcard systemd-unit [service|socket [<address>]]";
+const DEFAULT_SOCKET_ADDRESS: &str = "127.0.0.1:9090";
+/// First file descriptor passed by systemd with socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sends state changes to systemd when started with `Type=notify`, does
+/// nothing otherwise.
+#[derive(Debug)]
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// Whether readiness was already sent.
+    started: AtomicBool,
+}
+
+impl Notifier {
+    /// Connects to the socket set by systemd in `NOTIFY_SOCKET`.
+    pub fn from_env() -> Notifier {
+        Notifier::new(env::var("NOTIFY_SOCKET").ok().as_deref())
+    }
+
+    #[cfg(unix)]
+    pub fn new(path: Option<&str>) -> Notifier {
+        let socket = path.and_then(|path| match Notifier::connect(path) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("Can't notify systemd through {}: {}", path, e);
+                None
+            }
+        });
+        Notifier {
+            socket,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_path: Option<&str>) -> Notifier {
+        Notifier {
+            started: AtomicBool::new(false),
+        }
+    }
+
+    #[cfg(unix)]
+    fn connect(path: &str) -> io::Result<(UnixDatagram, SocketAddr)> {
+        let address = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets are only supported on Linux",
+                ));
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok((UnixDatagram::unbound()?, address))
+    }
+
+    /// Sends `state`, made of `KEY=value` lines, if systemd listens to it.
+    pub fn notify(&self, state: &str) {
+        #[cfg(unix)]
+        if let Some(Err(e)) = self
+            .socket
+            .as_ref()
+            .map(|(socket, address)| socket.send_to_addr(state.as_bytes(), address))
+        {
+            warn!("Error notifying systemd: {}", e);
+        }
+        #[cfg(not(unix))]
+        let _ = state;
+    }
+
+    /// Tells systemd the service is started, only the first time.
+    pub fn ready(&self) {
+        if !self.started.swap(true, Ordering::Relaxed) {
+            self.notify("READY=1");
+        }
+    }
+
+    /// Tells systemd the reload is done, once the service is started.
+    pub fn reloaded(&self) {
+        if self.started.load(Ordering::Relaxed) {
+            self.notify("READY=1");
+        }
+    }
+
+    pub fn reloading(&self) {
+        self.notify("RELOADING=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Sets the status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&format!(
+            "STATUS={}",
+            redact::redact(status).replace('\n', " ")
+        ));
+    }
+}
+
+/// Progress of the main loop, used to stop the watchdog pings when a cycle
+/// hangs.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// When the loop last made progress, `None` while it waits for the next
+    /// cycle.
+    last_step: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    /// Records that the loop made progress in the current cycle.
+    pub fn step(&self) {
+        *self.last_step.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Records that the loop waits for the next cycle.
+    pub fn idle(&self) {
+        *self.last_step.lock().unwrap() = None;
+    }
+
+    /// Returns whether the loop made no progress for `timeout` while in a
+    /// cycle.
+    pub fn is_stuck(&self, now: Instant, timeout: Duration) -> bool {
+        self.last_step
+            .lock()
+            .unwrap()
+            .is_some_and(|last_step| now.saturating_duration_since(last_step) >= timeout)
+    }
+}
+
+/// Returns the watchdog timeout set by systemd with `WatchdogSec=`, if it
+/// applies to this process.
+fn watchdog_timeout() -> Option<Duration> {
+    if env::var("WATCHDOG_PID").is_ok_and(|pid| pid.parse::<u32>().ok() != Some(process::id())) {
+        return None;
+    }
+    let timeout = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (timeout > 0).then(|| Duration::from_micros(timeout))
+}
+
+/// Pings the systemd watchdog twice per timeout as long as the main loop
+/// makes progress, so a hung cycle gets the service restarted.
+pub fn start_watchdog(notifier: Arc<Notifier>, progress: Arc<Progress>) {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    debug!("Pinging the systemd watchdog every {:?}", timeout / 2);
+    tokio::spawn(async move {
+        let mut warned = false;
+        loop {
+            if progress.is_stuck(Instant::now(), timeout) {
+                if !warned {
+                    warn!("No progress for {:?}, stopping the watchdog pings", timeout);
+                    warned = true;
+                }
+            } else {
+                warned = false;
+                notifier.notify("WATCHDOG=1");
+            }
+            time::sleep(timeout / 2).await;
+        }
+    });
+}
+
+/// Returns the first socket passed by systemd with socket activation.
+#[cfg(unix)]
+pub fn activated_listener() -> io::Result<Option<TcpListener>> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let for_this_process = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !for_this_process || fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(
+            "{} sockets passed by systemd, only the first one is used",
+            fds
+        );
+    }
+    // Safety: systemd passes the sockets from file descriptor 3, owned by
+    // this process only as LISTEN_PID matches.
+    let passed = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    // The duplicate is closed on exec, unlike the passed descriptor, so the
+    // commands run for secrets don't inherit the socket
+    let listener = std::net::TcpListener::from(passed.try_clone()?);
+    drop(passed);
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // Safety: read once at startup, before anything else reads the
+        // environment from another thread.
+        unsafe { env::remove_var(name) };
+    }
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+fn service_unit(executable: &str) -> String {
+    format!(
+        "[Unit]
+Description=Infomaniak DynDNS wildcard updater
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={executable}
+ExecReload=/bin/kill -HUP $MAINPID
+EnvironmentFile=-/etc/default/infomaniak-dyndns-wildcard
+# Longer than the longest cycle, verification of updates runs in the
+# background. The start fails, to be retried, when the records can't be
+# updated in time
+TimeoutStartSec=10min
+WatchdogSec=10min
+Restart=on-failure
+DynamicUser=yes
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+fn socket_unit(address: &str) -> String {
+    format!(
+        "[Unit]
+Description=Metrics and health of the Infomaniak DynDNS wildcard updater
+
+[Socket]
+ListenStream={address}
+
+[Install]
+WantedBy=sockets.target
+"
+    )
+}
+
+/// Prints the unit file asked in `args`.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let unit = match args.first().map(String::as_str) {
+        None | Some("service") => {
+            let executable = env::current_exe()?;
+            service_unit(&executable.to_string_lossy())
+        }
+        Some("socket") => socket_unit(args.get(1).map_or(DEFAULT_SOCKET_ADDRESS, String::as_str)),
+        Some(_) => return Err(USAGE.into()),
+    };
+    print!("{}", unit);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifier_sends_states() {
+        redact::register("n0t1fi3d-s3cr3t");
+        let path = env::temp_dir().join(format!(
+            "infomaniak-dyndns-wildcard-notify-{}",
+            crate::random::random_u64()
+        ));
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(path.to_str());
+
+        notifier.reloaded();
+        notifier.ready();
+        notifier.ready();
+        notifier.status("Public IPv4: 198.41.0.4");
+        notifier.status("Degraded: token n0t1fi3d-s3cr3t rejected");
+
+        let mut buffer = [0; 128];
+        let size = systemd.recv(&mut buffer).unwrap();
+        // Readiness is only sent once, and reloads only end once started
+        assert_eq!(&buffer[..size], b"READY=1");
+        let size = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"STATUS=Public IPv4: 198.41.0.4");
+        let size = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..size],
+            b"STATUS=Degraded: token [REDACTED] rejected"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // Without systemd, notifications are dropped
+        Notifier::new(None).ready();
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress::default();
+        let timeout = Duration::from_secs(60);
+
+        assert!(!progress.is_stuck(Instant::now() + timeout, timeout));
+        progress.step();
+        let later = Instant::now() + timeout;
+        assert!(!progress.is_stuck(Instant::now(), timeout));
+        assert!(progress.is_stuck(later, timeout));
+        progress.idle();
+        assert!(!progress.is_stuck(later, timeout));
+    }
+
+    #[test]
+    fn test_units() {
+        assert!(
+            service_unit("/usr/bin/infomaniak-dyndns-wildcard")
+                .contains("ExecStart=/usr/bin/infomaniak-dyndns-wildcard\n")
+        );
+        assert!(socket_unit("[::1]:9090").contains("ListenStream=[::1]:9090\n"));
+        assert!(run(&["timer".to_string()]).is_err());
+    }
+}
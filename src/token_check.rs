@@ -0,0 +1,269 @@
+use crate::random;
+use log::warn;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Name of the TXT record created then deleted to check write access.
+const PROBE_RECORD_NAME: &str = "_infomaniak-dyndns-wildcard-token-check";
+
+/// Access to the zones checked at startup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenCheck {
+    /// Don't check the token.
+    Off,
+    /// List the records of each zone.
+    Read,
+    /// Also create and delete a TXT record in each zone.
+    ReadWrite,
+}
+
+impl std::str::FromStr for TokenCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(TokenCheck::Off),
+            "read" => Ok(TokenCheck::Read),
+            "read-write" => Ok(TokenCheck::ReadWrite),
+            _ => Err(format!(
+                "Invalid token check {:?}, expected \"off\", \"read\" or \"read-write\"",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when the API rejects the token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidTokenPolicy {
+    /// Exit right away, at startup or once the token is checked again.
+    Exit,
+    /// Keep running without updating records, checking the token again at
+    /// every cycle.
+    Degraded,
+}
+
+impl std::str::FromStr for InvalidTokenPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(InvalidTokenPolicy::Exit),
+            "degraded" => Ok(InvalidTokenPolicy::Degraded),
+            _ => Err(format!(
+                "Invalid token policy {:?}, expected \"exit\" or \"degraded\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Result of the check of the token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenStatus {
+    /// The token has the access checked on every zone.
+    Valid,
+    /// The API rejected the token, which won't change without a new token.
+    Rejected(String),
+    /// The API couldn't be asked, the token should be checked again later.
+    Unverified(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedRecord {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateRecordResponse {
+    data: CreatedRecord,
+}
+
+/// Sends `request`, turning statuses telling the token lacks `access` to
+/// `zone` into a rejection.
+async fn send(request: RequestBuilder, zone: &str, access: &str) -> Result<Response, TokenStatus> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| TokenStatus::Unverified(format!("Error calling the API: {}", e)))?;
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(TokenStatus::Rejected(
+            "The API token is invalid or expired".to_string(),
+        )),
+        StatusCode::FORBIDDEN => Err(TokenStatus::Rejected(format!(
+            "The API token isn't allowed to {} the records of zone {}, check its scopes",
+            access, zone
+        ))),
+        StatusCode::NOT_FOUND => Err(TokenStatus::Rejected(format!(
+            "Zone {} doesn't exist or can't be accessed with the API token",
+            zone
+        ))),
+        status => Err(TokenStatus::Unverified(format!(
+            "Unexpected status {} checking the access to zone {}",
+            status, zone
+        ))),
+    }
+}
+
+async fn check_zone(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    zone: &str,
+    check: TokenCheck,
+) -> Result<(), TokenStatus> {
+    let records_url = format!("{}/{}/records", infomaniak_zones_api_url, zone);
+    send(
+        client.get(format!("{}?filter[types][]=TXT", records_url)),
+        zone,
+        "read",
+    )
+    .await?;
+    if check != TokenCheck::ReadWrite {
+        return Ok(());
+    }
+
+    let probe = send(
+        client.post(&records_url).json(&json!({
+            "source": PROBE_RECORD_NAME,
+            "target": format!("{:016x}", random::random_u64()),
+            "type": "TXT",
+            "ttl": "300"
+        })),
+        zone,
+        "create",
+    )
+    .await?
+    .json::<CreateRecordResponse>()
+    .await
+    .map_err(|e| TokenStatus::Unverified(format!("Invalid API response: {}", e)))?;
+    send(
+        client.delete(format!("{}/{}", records_url, probe.data.id)),
+        zone,
+        "delete",
+    )
+    .await
+    .inspect_err(|_| {
+        warn!(
+            "Record {} was left in zone {}, it can be deleted",
+            PROBE_RECORD_NAME, zone
+        )
+    })?;
+    Ok(())
+}
+
+/// Checks the token used by `client` has the access of `check` to every zone
+/// of `zones`.
+pub async fn check_token(
+    client: &Client,
+    infomaniak_zones_api_url: &str,
+    zones: &[String],
+    check: TokenCheck,
+) -> TokenStatus {
+    if check == TokenCheck::Off {
+        return TokenStatus::Valid;
+    }
+    for zone in zones {
+        if let Err(status) = check_zone(client, infomaniak_zones_api_url, zone, check).await {
+            return status;
+        }
+    }
+    TokenStatus::Valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+
+    const LIST_PATH: &str = "/test-zone/records?filter[types][]=TXT";
+
+    #[tokio::test]
+    async fn test_token_with_read_write_access() {
+        let mut server = Server::new_async().await;
+        let list_mock = server
+            .mock("GET", LIST_PATH)
+            .with_status(200)
+            .with_body(json!({"data": []}).to_string())
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .match_body(Matcher::PartialJson(
+                json!({"source": PROBE_RECORD_NAME, "type": "TXT"}),
+            ))
+            .with_status(201)
+            .with_body(json!({"data": {"id": 42}}).to_string())
+            .create_async()
+            .await;
+        let delete_mock = server
+            .mock("DELETE", "/test-zone/records/42")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let status = check_token(
+            &Client::new(),
+            &server.url(),
+            &["test-zone".to_string()],
+            TokenCheck::ReadWrite,
+        )
+        .await;
+
+        assert_eq!(status, TokenStatus::Valid);
+        list_mock.assert_async().await;
+        create_mock.assert_async().await;
+        delete_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_token_rejections() {
+        let mut server = Server::new_async().await;
+        let zones = ["test-zone".to_string()];
+        let list_mock = server
+            .mock("GET", LIST_PATH)
+            .with_status(401)
+            .create_async()
+            .await;
+        assert_eq!(
+            check_token(&Client::new(), &server.url(), &zones, TokenCheck::Read).await,
+            TokenStatus::Rejected("The API token is invalid or expired".to_string())
+        );
+        list_mock.remove_async().await;
+
+        server
+            .mock("GET", LIST_PATH)
+            .with_status(200)
+            .with_body(json!({"data": []}).to_string())
+            .create_async()
+            .await;
+        let create_mock = server
+            .mock("POST", "/test-zone/records")
+            .with_status(403)
+            .create_async()
+            .await;
+        assert_eq!(
+            check_token(&Client::new(), &server.url(), &zones, TokenCheck::Read).await,
+            TokenStatus::Valid
+        );
+        assert_eq!(
+            check_token(&Client::new(), &server.url(), &zones, TokenCheck::ReadWrite).await,
+            TokenStatus::Rejected(
+                "The API token isn't allowed to create the records of zone test-zone, check its scopes"
+                    .to_string()
+            )
+        );
+        create_mock.remove_async().await;
+
+        server
+            .mock("POST", "/test-zone/records")
+            .with_status(503)
+            .create_async()
+            .await;
+        assert!(matches!(
+            check_token(&Client::new(), &server.url(), &zones, TokenCheck::ReadWrite).await,
+            TokenStatus::Unverified(_)
+        ));
+    }
+}
@@ -0,0 +1,767 @@
+use crate::address_family::{AddressFamily, FamilyState, PublicIps};
+use crate::bogon::AddressFilter;
+use crate::hysteresis::{Confirmation, ConfirmedIp};
+use crate::public_ip::{DetectionBinding, IPIFY_IPV4_URL, IPIFY_IPV6_URL, IpSource};
+use crate::reconcile::{Reconciler, Reconciliation};
+use crate::records::RecordConfig;
+use config::Config;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Uplink as written in the `wans` table of the configuration file.
+#[derive(Debug, Deserialize)]
+struct WanEntry {
+    interface: Option<String>,
+    ipv4_source_address: Option<IpAddr>,
+    ipv6_source_address: Option<IpAddr>,
+}
+
+/// Primary and secondary uplinks of a failover record.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FailoverWans {
+    pub primary: String,
+    pub secondary: String,
+}
+
+/// Health of an uplink, as seen by the last detection of its public IPs.
+#[derive(Debug, Default)]
+pub struct WanHealth {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    healthy_since: Option<Instant>,
+}
+
+impl WanHealth {
+    /// Returns the public IPs of the uplink, or none while it is down.
+    pub fn public_ips(&self) -> PublicIps {
+        if !self.is_healthy() {
+            return PublicIps::default();
+        }
+        PublicIps::new(self.ipv4, self.ipv6)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy_since.is_some()
+    }
+
+    /// Tells whether the uplink has been healthy for at least `hold_down` at `now`.
+    pub fn is_stable(&self, now: Instant, hold_down: Duration) -> bool {
+        self.healthy_since
+            .is_some_and(|healthy_since| now.saturating_duration_since(healthy_since) >= hold_down)
+    }
+
+    /// Records the result of a health check done at `now`.
+    pub fn record_check(
+        &mut self,
+        ipv4: Option<Ipv4Addr>,
+        ipv6: Option<Ipv6Addr>,
+        healthy: bool,
+        now: Instant,
+    ) {
+        self.ipv4 = ipv4;
+        self.ipv6 = ipv6;
+        if !healthy {
+            self.healthy_since = None;
+        } else if self.healthy_since.is_none() {
+            self.healthy_since = Some(now);
+        }
+    }
+}
+
+/// Uplink of a multi-homed host, whose public IPs are detected through
+/// sources bound to its interface or source addresses.
+pub struct Wan {
+    pub name: String,
+    ipv4_source: IpSource,
+    ipv6_source: IpSource,
+    address_filter: AddressFilter,
+    confirmation: Confirmation,
+    confirmed_ipv4: ConfirmedIp<Ipv4Addr>,
+    confirmed_ipv6: ConfirmedIp<Ipv6Addr>,
+    pub health: WanHealth,
+}
+
+impl Wan {
+    fn new(
+        name: &str,
+        entry: &WanEntry,
+        config: &Config,
+        address_filter: &AddressFilter,
+        confirmation: Confirmation,
+    ) -> Result<Wan, Box<dyn Error>> {
+        let ipv4_binding = DetectionBinding {
+            source_address: entry.ipv4_source_address,
+            interface: entry.interface.clone(),
+        };
+        let ipv6_binding = DetectionBinding {
+            source_address: entry.ipv6_source_address,
+            interface: entry.interface.clone(),
+        };
+        Ok(Wan {
+            name: name.to_string(),
+            ipv4_source: IpSource::for_uplink(
+                config,
+                AddressFamily::Ipv4,
+                IPIFY_IPV4_URL,
+                &ipv4_binding,
+            )
+            .map_err(|e| format!("Invalid WAN {}: {}", name, e))?,
+            ipv6_source: IpSource::for_uplink(
+                config,
+                AddressFamily::Ipv6,
+                IPIFY_IPV6_URL,
+                &ipv6_binding,
+            )
+            .map_err(|e| format!("Invalid WAN {}: {}", name, e))?,
+            address_filter: address_filter.clone(),
+            confirmation,
+            confirmed_ipv4: ConfirmedIp::new(confirmation),
+            confirmed_ipv6: ConfirmedIp::new(confirmation),
+            health: WanHealth::default(),
+        })
+    }
+
+    /// Detects the public IPs of `families` through the uplink. It is healthy
+    /// when every one of them is detected.
+    ///
+    /// Like the public IPs of the host, a new IP is only used once confirmed.
+    pub async fn check(&mut self, families: &[AddressFamily], now: Instant) {
+        let mut healthy = true;
+        let mut ipv4 = None;
+        let mut ipv6 = None;
+        for family in families {
+            let result = match family {
+                AddressFamily::Ipv4 => self
+                    .ipv4_source
+                    .detect_ipv4(&self.address_filter)
+                    .await
+                    .map(|ip| ipv4 = Some(self.confirmed_ipv4.observe(ip, now))),
+                AddressFamily::Ipv6 => self
+                    .ipv6_source
+                    .detect_ipv6(&self.address_filter)
+                    .await
+                    .map(|ip| ipv6 = Some(self.confirmed_ipv6.observe(ip, now))),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Health check of WAN {} failed, can't detect its public {}: {}",
+                    self.name, family, e
+                );
+                healthy = false;
+            }
+        }
+        if healthy && !self.health.is_healthy() {
+            info!(
+                "WAN {} is healthy, public IPv4: {:?}, public IPv6: {:?}",
+                self.name, ipv4, ipv6
+            );
+        }
+        self.health.record_check(ipv4, ipv6, healthy, now);
+    }
+}
+
+/// Loads the uplinks of the `wans` table of the configuration file, whose
+/// public IPs are detected with the sources of the host.
+pub fn load_wans(
+    config: &Config,
+    address_filter: &AddressFilter,
+    confirmation: Confirmation,
+) -> Result<HashMap<String, Wan>, Box<dyn Error>> {
+    let entries = config
+        .get::<HashMap<String, WanEntry>>("wans")
+        .unwrap_or_default();
+    entries
+        .iter()
+        .map(|(name, entry)| {
+            let wan = Wan::new(name, entry, config, address_filter, confirmation)?;
+            Ok((name.clone(), wan))
+        })
+        .collect()
+}
+
+/// Uplink a failover record currently points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uplink {
+    Primary,
+    Secondary,
+}
+
+/// Chooses the uplink published by failover records.
+///
+/// The secondary is used as soon as the primary is down, and the primary is
+/// used again once it has been healthy for the hold-down period, to avoid
+/// flapping when it comes back and goes down again.
+#[derive(Debug)]
+pub struct FailoverState {
+    active: Uplink,
+}
+
+impl Default for FailoverState {
+    fn default() -> FailoverState {
+        FailoverState {
+            active: Uplink::Primary,
+        }
+    }
+}
+
+impl FailoverState {
+    pub fn select(
+        &mut self,
+        primary: &WanHealth,
+        secondary: &WanHealth,
+        hold_down: Duration,
+        now: Instant,
+    ) -> Uplink {
+        self.active = match self.active {
+            Uplink::Primary if !primary.is_healthy() && secondary.is_healthy() => Uplink::Secondary,
+            Uplink::Secondary
+                if primary.is_stable(now, hold_down)
+                    || (primary.is_healthy() && !secondary.is_healthy()) =>
+            {
+                Uplink::Primary
+            }
+            active => active,
+        };
+        self.active
+    }
+}
+
+/// Outcome of the reconciliation of the records published through uplinks.
+#[derive(Debug, Default, PartialEq)]
+pub struct WanReconciliation {
+    pub reconciliation: Reconciliation,
+    /// Records left untouched since all their uplinks are down.
+    pub records_down: Vec<String>,
+    /// Families whose updates are held back after failures.
+    pub backing_off: Vec<AddressFamily>,
+}
+
+impl WanReconciliation {
+    /// Tells whether every record points to the public IPs of its uplinks.
+    /// Updates held back by the rate limit don't count as failures.
+    pub fn is_healthy(&self) -> bool {
+        self.reconciliation.failed.is_empty()
+            && self.records_down.is_empty()
+            && self.backing_off.is_empty()
+    }
+
+    /// Tells whether every record was checked and points to the public IPs
+    /// of its uplinks.
+    pub fn is_complete(&self) -> bool {
+        self.is_healthy() && self.reconciliation.is_complete()
+    }
+}
+
+/// Records published through uplinks, either failing over between a primary
+/// and a secondary one, or publishing the public IPs of several of them.
+pub struct MultiWan {
+    pub wans: HashMap<String, Wan>,
+    pub records: Vec<RecordConfig>,
+    pub hold_down: Duration,
+    failovers: HashMap<FailoverWans, FailoverState>,
+    ipv4_state: FamilyState,
+    ipv6_state: FamilyState,
+    /// Public IPs each record was last reconciled to, for the families whose
+    /// records were up to date.
+    reconciled: Vec<(RecordConfig, PublicIps)>,
+}
+
+impl MultiWan {
+    pub fn new(
+        wans: HashMap<String, Wan>,
+        records: Vec<RecordConfig>,
+        hold_down: Duration,
+    ) -> Result<MultiWan, Box<dyn Error>> {
+        for record in &records {
+            let failover_wans = record
+                .failover
+                .iter()
+                .flat_map(|failover| [&failover.primary, &failover.secondary]);
+            for name in failover_wans.chain(&record.wans) {
+                if !wans.contains_key(name) {
+                    return Err(format!("Record {} uses unknown WAN {}", record.name, name).into());
+                }
+            }
+        }
+        Ok(MultiWan {
+            wans,
+            records,
+            hold_down,
+            failovers: HashMap::new(),
+            ipv4_state: FamilyState::new(AddressFamily::Ipv4),
+            ipv6_state: FamilyState::new(AddressFamily::Ipv6),
+            reconciled: Vec::new(),
+        })
+    }
+
+    /// Takes over what was learned by `previous`: the health and confirmed
+    /// IPs of the uplinks kept, the active uplink of its failovers, the
+    /// backoff and the published IPs, so a reload doesn't restart the
+    /// hold-down periods nor update unchanged records.
+    pub fn inherit(&mut self, previous: &mut MultiWan) {
+        for (name, wan) in &mut self.wans {
+            if let Some(previous_wan) = previous.wans.get_mut(name) {
+                wan.health = std::mem::take(&mut previous_wan.health);
+                std::mem::swap(&mut wan.confirmed_ipv4, &mut previous_wan.confirmed_ipv4);
+                std::mem::swap(&mut wan.confirmed_ipv6, &mut previous_wan.confirmed_ipv6);
+                wan.confirmed_ipv4.set_confirmation(wan.confirmation);
+                wan.confirmed_ipv6.set_confirmation(wan.confirmation);
+            }
+        }
+        self.failovers = std::mem::take(&mut previous.failovers);
+        std::mem::swap(&mut self.ipv4_state, &mut previous.ipv4_state);
+        std::mem::swap(&mut self.ipv6_state, &mut previous.ipv6_state);
+        // Records whose configuration changed are reconciled again
+        self.reconciled = std::mem::take(&mut previous.reconciled);
+    }
+
+    /// Checks the health of every uplink used by a record.
+    async fn check_wans(&mut self, now: Instant) {
+        for wan in self.wans.values_mut() {
+            let mut families = Vec::new();
+            for record in &self.records {
+                if !record.uses_wan(&wan.name) {
+                    continue;
+                }
+                for family in &record.families {
+                    if !families.contains(family) {
+                        families.push(*family);
+                    }
+                }
+            }
+            if !families.is_empty() {
+                wan.check(&families, now).await;
+            }
+        }
+    }
+
+    /// Returns the public IPs each record must point to: the ones of the
+    /// active uplink for failover records, and the ones of every healthy
+    /// uplink for the others.
+    fn public_ips(&mut self, now: Instant) -> Vec<(RecordConfig, PublicIps)> {
+        let mut public_ips = Vec::new();
+        for record in &self.records {
+            let mut record_ips = PublicIps::default();
+            if let Some(failover) = &record.failover {
+                let primary = &self.wans[&failover.primary];
+                let secondary = &self.wans[&failover.secondary];
+                let state = self.failovers.entry(failover.clone()).or_default();
+                let previous = state.active;
+                let active =
+                    match state.select(&primary.health, &secondary.health, self.hold_down, now) {
+                        Uplink::Primary => primary,
+                        Uplink::Secondary => secondary,
+                    };
+                if state.active != previous {
+                    warn!(
+                        "Switching records on WANs {} and {} to WAN {}",
+                        failover.primary, failover.secondary, active.name
+                    );
+                }
+                record_ips.extend(&active.health.public_ips());
+            }
+            for name in &record.wans {
+                record_ips.extend(&self.wans[name].health.public_ips());
+            }
+            public_ips.push((record.clone(), record_ips));
+        }
+        public_ips
+    }
+
+    /// Returns the public IPs `record` was last reconciled to.
+    fn reconciled_ips(&mut self, record: &RecordConfig) -> &mut PublicIps {
+        let index = match self
+            .reconciled
+            .iter()
+            .position(|(reconciled, _)| reconciled == record)
+        {
+            Some(index) => index,
+            None => {
+                self.reconciled.push((record.clone(), PublicIps::default()));
+                self.reconciled.len() - 1
+            }
+        };
+        &mut self.reconciled[index].1
+    }
+
+    /// Checks the uplinks, then points the records to the public IPs of their
+    /// uplinks.
+    ///
+    /// Like the records of the host, a record is only verified through the
+    /// API when its public IPs changed, it is health checked or a full
+    /// verification is due, and a family backs off after failures.
+    pub async fn reconcile(
+        &mut self,
+        reconciler: &Arc<Reconciler>,
+        full_verification_due: bool,
+        interval: Duration,
+        max_backoff: Duration,
+    ) -> WanReconciliation {
+        let now = Instant::now();
+        self.check_wans(now).await;
+
+        let mut outcome = WanReconciliation::default();
+        for state in [&self.ipv4_state, &self.ipv6_state] {
+            if !state.is_due(now) {
+                outcome.backing_off.push(state.family);
+            }
+        }
+        let mut groups: Vec<(PublicIps, Vec<RecordConfig>)> = Vec::new();
+        for (record, mut public_ips) in self.public_ips(now) {
+            if public_ips.is_empty() {
+                warn!(
+                    "All WANs of record {} are down, leaving it untouched",
+                    record.name
+                );
+                outcome.records_down.push(record.name.clone());
+                continue;
+            }
+            // Families without public IPs are left untouched
+            if outcome.backing_off.contains(&AddressFamily::Ipv4) {
+                public_ips.ipv4.clear();
+            }
+            if outcome.backing_off.contains(&AddressFamily::Ipv6) {
+                public_ips.ipv6.clear();
+            }
+            let verified = !full_verification_due
+                && record.health_check.is_none()
+                && *self.reconciled_ips(&record) == public_ips;
+            if public_ips.is_empty() || verified {
+                continue;
+            }
+            match groups
+                .iter_mut()
+                .find(|(group_ips, _)| *group_ips == public_ips)
+            {
+                Some((_, group_records)) => group_records.push(record),
+                None => groups.push((public_ips, vec![record])),
+            }
+        }
+
+        let mut reconciled_families = Vec::new();
+        for (public_ips, group_records) in groups {
+            let reconciliation = reconciler.reconcile_all(&group_records, &public_ips).await;
+            for record in &group_records {
+                let reconciled_ips = self.reconciled_ips(record);
+                if reconciliation.is_done(AddressFamily::Ipv4) {
+                    reconciled_ips.ipv4 = public_ips.ipv4.clone();
+                }
+                if reconciliation.is_done(AddressFamily::Ipv6) {
+                    reconciled_ips.ipv6 = public_ips.ipv6.clone();
+                }
+            }
+            for family in [AddressFamily::Ipv4, AddressFamily::Ipv6] {
+                if !public_ips.targets(family).is_empty() && !reconciled_families.contains(&family)
+                {
+                    reconciled_families.push(family);
+                }
+            }
+            outcome.reconciliation.merge(reconciliation);
+        }
+
+        for state in [&mut self.ipv4_state, &mut self.ipv6_state] {
+            if !reconciled_families.contains(&state.family) {
+                continue;
+            }
+            if outcome.reconciliation.failed.contains(&state.family) {
+                let backoff = state.record_failure(now, interval, max_backoff);
+                warn!(
+                    "Some {} records published through WANs failed to update, retrying in {:?}",
+                    state.family, backoff
+                );
+            } else if let Some(retry_in) = outcome.reconciliation.deferred(state.family) {
+                state.defer(now, retry_in);
+            } else {
+                state.record_success();
+            }
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+
+    fn health(healthy_since: Option<Instant>) -> WanHealth {
+        WanHealth {
+            ipv4: None,
+            ipv6: None,
+            healthy_since,
+        }
+    }
+
+    #[test]
+    fn test_failover_switches_back_after_hold_down() {
+        let hold_down = Duration::from_secs(300);
+        let start = Instant::now();
+        let mut state = FailoverState::default();
+        let healthy = health(Some(start));
+        let down = health(None);
+
+        assert_eq!(
+            state.select(&healthy, &healthy, hold_down, start),
+            Uplink::Primary
+        );
+        assert_eq!(
+            state.select(&down, &healthy, hold_down, start),
+            Uplink::Secondary
+        );
+
+        let back = start + Duration::from_secs(60);
+        let primary = health(Some(back));
+        assert_eq!(
+            state.select(
+                &primary,
+                &healthy,
+                hold_down,
+                back + Duration::from_secs(299)
+            ),
+            Uplink::Secondary
+        );
+        assert_eq!(
+            state.select(&primary, &healthy, hold_down, back + hold_down),
+            Uplink::Primary
+        );
+    }
+
+    #[test]
+    fn test_failover_ignores_hold_down_when_secondary_is_down() {
+        let hold_down = Duration::from_secs(300);
+        let now = Instant::now();
+        let mut state = FailoverState {
+            active: Uplink::Secondary,
+        };
+
+        assert_eq!(
+            state.select(&health(Some(now)), &health(None), hold_down, now),
+            Uplink::Primary
+        );
+        assert_eq!(
+            state.select(&health(None), &health(None), hold_down, now),
+            Uplink::Primary
+        );
+    }
+
+    fn wan(url: &str, address_filter: &AddressFilter) -> Wan {
+        let entry = WanEntry {
+            interface: None,
+            ipv4_source_address: Some("127.0.0.1".parse().unwrap()),
+            ipv6_source_address: None,
+        };
+        let config = Config::builder()
+            .set_override("ipv4_url", url)
+            .unwrap()
+            .set_override("ipv6_url", url)
+            .unwrap()
+            .build()
+            .unwrap();
+        Wan::new(
+            "fiber",
+            &entry,
+            &config,
+            address_filter,
+            Confirmation::default(),
+        )
+        .unwrap()
+    }
+
+    fn multi_wan(wan: Wan) -> MultiWan {
+        let record = RecordConfig {
+            name: "www".to_string(),
+            zone: "test-zone".to_string(),
+            families: vec![AddressFamily::Ipv4],
+            failover: None,
+            wans: vec!["fiber".to_string()],
+            addresses: Vec::new(),
+            health_check: None,
+        };
+        MultiWan::new(
+            HashMap::from([("fiber".to_string(), wan)]),
+            vec![record],
+            Duration::ZERO,
+        )
+        .unwrap()
+    }
+
+    fn reconciler(url: &str) -> Arc<Reconciler> {
+        Arc::new(Reconciler {
+            client: Client::new(),
+            infomaniak_zones_api_url: url.to_string(),
+            owner_id: None,
+            unowned_records_policy: crate::ownership::UnownedRecordsPolicy::Report,
+            verification: None,
+            drift_check: None,
+            health_checks: Default::default(),
+            update_rate_limit: Default::default(),
+            api_permits: tokio::sync::Semaphore::new(1),
+            metrics: Default::default(),
+            token_rejected: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_wan_health_check() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/")
+            .with_status(200)
+            .with_body("192.0.2.1")
+            .create_async()
+            .await;
+        let address_filter = AddressFilter::new(vec!["192.0.2.0/24".parse().unwrap()]);
+        let mut wan = wan(&server.url(), &address_filter);
+        let now = Instant::now();
+
+        wan.check(&[AddressFamily::Ipv4], now).await;
+        assert!(wan.health.is_healthy());
+        assert_eq!(wan.health.ipv4, Some("192.0.2.1".parse().unwrap()));
+
+        wan.check(&[AddressFamily::Ipv4, AddressFamily::Ipv6], now)
+            .await;
+        assert!(!wan.health.is_healthy());
+    }
+
+    #[test]
+    fn test_wan_refuses_sources_shared_by_uplinks() {
+        let entry = WanEntry {
+            interface: Some("wan0".to_string()),
+            ipv4_source_address: None,
+            ipv6_source_address: None,
+        };
+        let config = Config::builder()
+            .set_override("ipv4_source", "file")
+            .unwrap()
+            .set_override("ipv4_file", "/run/public-ipv4")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(
+            Wan::new(
+                "fiber",
+                &entry,
+                &config,
+                &AddressFilter::default(),
+                Confirmation::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_reports_records_with_all_wans_down() {
+        let mut server = mockito::Server::new_async().await;
+        let _detection_mock = server
+            .mock("GET", "/")
+            .with_status(500)
+            .create_async()
+            .await;
+        let api_mock = server
+            .mock("GET", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let mut multi_wan = multi_wan(wan(&server.url(), &AddressFilter::default()));
+
+        let outcome = multi_wan
+            .reconcile(
+                &reconciler(&server.url()),
+                true,
+                Duration::from_secs(60),
+                Duration::from_secs(300),
+            )
+            .await;
+
+        assert_eq!(outcome.records_down, vec!["www"]);
+        assert!(!outcome.is_healthy());
+        api_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_only_verifies_changed_records() {
+        let mut server = mockito::Server::new_async().await;
+        let _detection_mock = server
+            .mock("GET", "/")
+            .with_status(200)
+            .with_body("192.0.2.1")
+            .create_async()
+            .await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                serde_json::json!({"data": [{
+                    "id": 1,
+                    "source": "www",
+                    "target": "192.0.2.1",
+                    "ttl": 300,
+                    "type": "A",
+                    "updated_at": 1234567890
+                }]})
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let address_filter = AddressFilter::new(vec!["192.0.2.0/24".parse().unwrap()]);
+        let mut multi_wan = multi_wan(wan(&server.url(), &address_filter));
+        let reconciler = reconciler(&server.url());
+        let (interval, max_backoff) = (Duration::from_secs(60), Duration::from_secs(300));
+
+        for full_verification_due in [false, false, true] {
+            let outcome = multi_wan
+                .reconcile(&reconciler, full_verification_due, interval, max_backoff)
+                .await;
+            assert!(outcome.is_complete());
+        }
+        // The second cycle found the same public IPs, and skipped the API
+        list_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_backs_off_after_failures() {
+        let mut server = mockito::Server::new_async().await;
+        let _detection_mock = server
+            .mock("GET", "/")
+            .with_status(200)
+            .with_body("192.0.2.1")
+            .create_async()
+            .await;
+        let list_mock = server
+            .mock(
+                "GET",
+                "/test-zone/records?filter[types][]=A&filter[types][]=AAAA",
+            )
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let address_filter = AddressFilter::new(vec!["192.0.2.0/24".parse().unwrap()]);
+        let mut multi_wan = multi_wan(wan(&server.url(), &address_filter));
+        let reconciler = reconciler(&server.url());
+        let (interval, max_backoff) = (Duration::from_secs(60), Duration::from_secs(300));
+
+        let outcome = multi_wan
+            .reconcile(&reconciler, true, interval, max_backoff)
+            .await;
+        assert_eq!(outcome.reconciliation.failed, vec![AddressFamily::Ipv4]);
+
+        let outcome = multi_wan
+            .reconcile(&reconciler, true, interval, max_backoff)
+            .await;
+        assert_eq!(outcome.backing_off, vec![AddressFamily::Ipv4]);
+        assert!(!outcome.is_healthy());
+        list_mock.assert_async().await;
+    }
+}